@@ -3,8 +3,10 @@ mod services;
 mod uuids;
 
 pub use device::{
-    media_player::MediaPlayerEvent, notification::Notification,
-    InfiniTime, ProgressEvent, ProgressRx, ProgressTx,
+    fwupd::{is_mcuboot_image, mcuboot, read_dfu_image_info, FirmwareVerification},
+    media_player::MediaPlayerEvent, notification::Notification, resources::{pack_resources_dir, ResourcePackage, ResourcePlan, ResourcesMarker},
+    DeviceFilter, InfiniTime, NotInfiniTime, ProgressEvent, ProgressRx, ProgressTx,
     progress_channel,
 };
 pub use services::start_gatt_services;
+pub(crate) use device::ProgressTxWrapper;
@@ -3,7 +3,7 @@ use uuid::Uuid;
 use anyhow::{anyhow, Result};
 use bluer::{gatt::remote::Characteristic, Adapter, Device};
 use futures::{Stream, StreamExt};
-use std::{sync::{Arc, atomic::{AtomicBool, Ordering}}, collections::HashMap};
+use std::{sync::{Arc, atomic::{AtomicBool, Ordering}}, collections::HashMap, fmt};
 use tokio::sync::mpsc;
 
 pub mod fs;
@@ -13,6 +13,66 @@ pub mod media_player;
 pub mod resources;
 
 
+/// Services specific to InfiniTime, any of them identifies the watch before connecting
+const ADVERTISED_SERVICES: [Uuid; 1] = [uuids::SRV_MP];
+
+/// Services InfiniTime shares with other devices (Nordic DFU, Adafruit file
+/// transfer), only all of them together identify the watch
+const ADVERTISED_COMMON_SERVICES: [Uuid; 2] = [uuids::SRV_FWUPD, uuids::SRV_FS];
+
+/// Characteristics provided by every InfiniTime version, checked after connecting
+const SIGNATURE: [Uuid; 2] = [uuids::CHR_FWUPD_CONTROL_POINT, uuids::CHR_MP_EVENTS];
+
+
+/// Rules to recognize InfiniTime watches among bluetooth devices. Watches are
+/// recognized by the advertised services, or by the name for the firmware
+/// builds which don't advertise them.
+#[derive(Debug, Clone)]
+pub struct DeviceFilter {
+    /// Names of the devices accepted regardless of their advertised services
+    pub names: Vec<String>,
+}
+
+impl Default for DeviceFilter {
+    fn default() -> Self {
+        Self { names: vec![String::from("InfiniTime")] }
+    }
+}
+
+impl DeviceFilter {
+    pub fn new(names: Vec<String>) -> Self {
+        Self { names }
+    }
+
+    pub async fn matches(&self, device: &Device) -> bool {
+        if let Ok(Some(services)) = device.uuids().await {
+            if ADVERTISED_SERVICES.iter().any(|uuid| services.contains(uuid))
+                || ADVERTISED_COMMON_SERVICES.iter().all(|uuid| services.contains(uuid))
+            {
+                return true;
+            }
+        }
+        match device.name().await {
+            Ok(Some(name)) => self.names.iter().any(|n| *n == name),
+            _ => false,
+        }
+    }
+}
+
+
+/// Connected device doesn't provide the services of InfiniTime
+#[derive(Debug)]
+pub struct NotInfiniTime;
+
+impl fmt::Display for NotInfiniTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Device doesn't provide InfiniTime services")
+    }
+}
+
+impl std::error::Error for NotInfiniTime {}
+
+
 #[derive(Debug)]
 pub struct InfiniTime {
     device: Arc<Device>,
@@ -24,6 +84,10 @@ impl InfiniTime {
     pub async fn new(device: Arc<Device>) -> Result<Self> {
         let characteristics = Self::read_characteristics_map(&device).await?;
         log::debug!("Characteristics: {:#?}", characteristics.keys());
+        // The name alone doesn't guarantee it's InfiniTime
+        if !SIGNATURE.iter().all(|uuid| characteristics.contains_key(uuid)) {
+            return Err(NotInfiniTime.into());
+        }
         Ok(Self {
             device,
             characteristics,
@@ -94,18 +158,15 @@ impl InfiniTime {
         self.is_upgrading_firmware.load(Ordering::SeqCst)
     }
 
-    pub async fn check_device(device: &Device) -> bool {
-        match device.name().await {
-            Ok(Some(name)) => name.as_str() == "InfiniTime",
-            _ => false,
-        }
+    pub async fn check_device(device: &Device, filter: &DeviceFilter) -> bool {
+        filter.matches(device).await
     }
 
-    pub async fn list_known_devices(adapter: &Adapter) -> Result<Vec<Device>> {
+    pub async fn list_known_devices(adapter: &Adapter, filter: &DeviceFilter) -> Result<Vec<Device>> {
         let mut result = Vec::new();
         for address in adapter.device_addresses().await? {
             let device = adapter.device(address)?;
-            if Self::check_device(&device).await {
+            if Self::check_device(&device, filter).await {
                 result.push(device);
             }
         }
@@ -143,12 +204,12 @@ pub fn progress_channel(capacity: usize) -> (ProgressTx, ProgressRx) {
     mpsc::channel(capacity)
 }
 
-// Private helper
+// Crate-private helper
 
-struct ProgressTxWrapper(Option<ProgressTx>);
+pub(crate) struct ProgressTxWrapper(pub(crate) Option<ProgressTx>);
 
 impl ProgressTxWrapper {
-    async fn report(&self, event: ProgressEvent) {
+    pub(crate) async fn report(&self, event: ProgressEvent) {
         if let Some(tx) = &self.0 {
             if let Err(err) = tx.send(event).await {
                 log::error!("Failed to send progress event: {}", err);
@@ -156,11 +217,11 @@ impl ProgressTxWrapper {
         }
     }
 
-    async fn report_msg<T: Into<String>>(&self, msg: T) {
+    pub(crate) async fn report_msg<T: Into<String>>(&self, msg: T) {
         self.report(ProgressEvent::Message(msg.into())).await;
     }
 
-    async fn report_num(&self, current: u32, total: u32) {
+    pub(crate) async fn report_num(&self, current: u32, total: u32) {
         self.report(ProgressEvent::Numbers { current, total }).await;
     }
 }
@@ -170,6 +170,12 @@ impl InfiniTime {
     }
 
     pub async fn list_dir(&self, path: &str) -> Result<Vec<DirEntry>> {
+        self.list_dir_if_exists(path).await?
+            .ok_or_else(|| anyhow!("LittleFS error: {:?}", Status::NoDirectoryEntry))
+    }
+
+    /// Same as `list_dir`, but returns `None` if the directory doesn't exist
+    pub async fn list_dir_if_exists(&self, path: &str) -> Result<Option<Vec<DirEntry>>> {
         log::info!("Listing dir: {}", path);
         let chr = self.chr(&uuids::CHR_FS_TRANSFER)?;
         let resp_stream = chr.notify().await?;
@@ -180,13 +186,17 @@ impl InfiniTime {
 
         let mut output = Vec::new();
         while let Some(resp) = resp_stream.next().await {
-            let parsed = msg::ListDirResponse::deserialize_check(resp.as_slice())?;
+            let parsed = msg::ListDirResponse::deserialize(resp.as_slice())?;
+            if parsed.status == Status::NoDirectoryEntry {
+                return Ok(None);
+            }
+            parsed.check()?;
             output.push(DirEntry::from(&parsed));
             if parsed.entry_idx >= parsed.entries_total - 1 {
                 break;
             }
         }
-        Ok(output)
+        Ok(Some(output))
     }
 
     pub async fn move_file(&self, old_path: &str, new_path: &str) -> Result<()> {
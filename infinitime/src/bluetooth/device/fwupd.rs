@@ -1,4 +1,4 @@
-use crate::utils;
+use crate::{policy, utils};
 use super::{uuids, InfiniTime, ProgressTx, ProgressTxWrapper};
 use anyhow::{anyhow, ensure, Result};
 use futures::{pin_mut, StreamExt};
@@ -8,9 +8,14 @@ use std::{
     sync::atomic::Ordering,
 };
 
+pub mod mcuboot;
 
 pub const MAX_FIRMWARE_SIZE: usize = 512 * 1024;
 
+/// Device type used by InfiniTime DFU packages (`--dev-type 0x0052` in nrfutil)
+pub const DFU_DEVICE_TYPE: u16 = 0x0052;
+
+
 
 #[derive(Deserialize, Debug)]
 struct Manifest {
@@ -20,24 +25,118 @@ struct Manifest {
 #[derive(Deserialize, Debug)]
 struct ManifestInner {
     application: Application,
-    // dfu_version: String,
 }
 
 #[derive(Deserialize, Debug)]
 struct Application {
     bin_file: String,
     dat_file: String,
-    // init_packet_data: InitPacketData,
 }
 
-// #[derive(Deserialize, Debug)]
-// struct InitPacketData {
-//     application_version: u32,
-//     device_revision: u16,
-//     device_type: u16,
-//     firmware_crc16: u16,
-//     softdevice_req: Vec<u16>,
-// }
+#[derive(Debug)]
+struct InitPacketData {
+    application_version: u32,
+    device_revision: u16,
+    device_type: u16,
+    firmware_crc16: u16,
+    softdevice_req: Vec<u16>,
+}
+
+impl InitPacketData {
+    /// Init packet parameters matching those used by InfiniTime build
+    fn for_firmware(firmware: &[u8]) -> Self {
+        Self {
+            application_version: 0xffff_ffff,
+            device_revision: 0xffff,
+            device_type: DFU_DEVICE_TYPE,
+            firmware_crc16: utils::crc16(firmware),
+            softdevice_req: vec![0xfffe],
+        }
+    }
+
+    /// Serialize into legacy Nordic DFU init packet (.dat file)
+    fn serialize(&self) -> Vec<u8> {
+        let softdevice_req = self.softdevice_req.iter()
+            .flat_map(|r| r.to_le_bytes())
+            .collect::<Vec<u8>>();
+        [
+            self.device_type.to_le_bytes().as_slice(),
+            &self.device_revision.to_le_bytes(),
+            &self.application_version.to_le_bytes(),
+            &(self.softdevice_req.len() as u16).to_le_bytes(),
+            &softdevice_req,
+            &self.firmware_crc16.to_le_bytes(),
+        ].concat()
+    }
+}
+
+
+/// Check whether the data is a raw mcuboot image rather than a DFU package
+pub fn is_mcuboot_image(data: &[u8]) -> bool {
+    data.get(0..4)
+        .map(|m| u32::from_le_bytes(m.try_into().unwrap()) == mcuboot::IMAGE_MAGIC)
+        .unwrap_or(false)
+}
+
+/// Parse mcuboot header and TLVs of the firmware image inside DFU package
+/// (or of the raw mcuboot image). Fails if the image has no mcuboot header,
+/// which means it is not bootable by the PineTime bootloader
+pub fn read_dfu_image_info(dfu_content: &[u8]) -> Result<mcuboot::ImageInfo> {
+    let (_, firmware) = extract_dfu(dfu_content)?;
+    mcuboot::ImageInfo::parse(&firmware)
+}
+
+/// Extract init packet and firmware image from DFU package. Raw mcuboot
+/// image is accepted too, in which case the init packet is generated
+fn extract_dfu(dfu_content: &[u8]) -> Result<(Vec<u8>, Vec<u8>)> {
+    if is_mcuboot_image(dfu_content) {
+        ensure!(dfu_content.len() < MAX_FIRMWARE_SIZE, "Firmware cannot be that large");
+        let init_packet = InitPacketData::for_firmware(dfu_content).serialize();
+        return Ok((init_packet, dfu_content.to_vec()));
+    }
+
+    // Parse manifest from the archive
+    let mut zip = zip::ZipArchive::new(Cursor::new(dfu_content))?;
+    let mut json = String::new();
+    zip.by_name("manifest.json")?.read_to_string(&mut json)?;
+    let manifest = serde_json::from_str::<Manifest>(&json)
+        .map_err(|_| anyhow!("Invalid manifest.json"))?.manifest;
+
+    // Read DFU data
+    let mut init_packet = Vec::new();
+    zip.by_name(&manifest.application.dat_file)?.read_to_end(&mut init_packet)?;
+
+    let mut firmware_buffer = Vec::new();
+    let mut file = zip.by_name(&manifest.application.bin_file)?;
+    ensure!(file.size() < MAX_FIRMWARE_SIZE as u64, "Firmware cannot be that large");
+    file.read_to_end(&mut firmware_buffer)?;
+
+    Ok((init_packet, firmware_buffer))
+}
+
+
+/// Result of checking the firmware version reported by the watch
+/// after it has rebooted into the newly flashed image
+#[derive(Debug, Clone, PartialEq)]
+pub enum FirmwareVerification {
+    /// The watch runs the expected version
+    Confirmed(String),
+    /// The watch runs some other version, most likely the previous one
+    Mismatch { expected: String, actual: String },
+    /// Expected version is unknown (e.g. flashed from file), so only the
+    /// reported one is available
+    Unverified(String),
+}
+
+impl FirmwareVerification {
+    pub fn new(expected: Option<&str>, actual: String) -> Self {
+        match expected {
+            Some(expected) if policy::versions_match(expected, &actual) => Self::Confirmed(actual),
+            Some(expected) => Self::Mismatch { expected: expected.to_string(), actual },
+            None => Self::Unverified(actual),
+        }
+    }
+}
 
 
 impl InfiniTime {
@@ -54,25 +153,8 @@ impl InfiniTime {
 
         progress.report_msg("Extracting firmware files...").await;
 
-        // Parse manifest from the archive
-        let mut zip = zip::ZipArchive::new(Cursor::new(dfu_content))?;
-        let mut json = String::new();
-        zip.by_name("manifest.json")?.read_to_string(&mut json)?;
-        let manifest = serde_json::from_str::<Manifest>(&json)
-            .map_err(|_| anyhow!("Invalid manifest.json"))?.manifest;
-
-
-        // Read DFU data
-        let mut init_packet = Vec::new();
-        zip.by_name(&manifest.application.dat_file)?.read_to_end(&mut init_packet)?;
-
-        let mut firmware_buffer = Vec::new();
-        {
-            // file is not Send, so it needs to go out of scope before the next await
-            let mut file = zip.by_name(&manifest.application.bin_file)?;
-            ensure!(file.size() < MAX_FIRMWARE_SIZE as u64, "Firmware cannot be that large");
-            file.read_to_end(&mut firmware_buffer)?;
-        }
+        // Zip file is not Send, so extraction is done in a separate non-async function
+        let (init_packet, firmware_buffer) = extract_dfu(dfu_content)?;
 
         // Obtain characteristics
         let control_point_stream = chr_ctrl.notify().await?;
@@ -145,4 +227,80 @@ impl InfiniTime {
 
         Ok(())
     }
-}
\ No newline at end of file
+
+    /// Read firmware version after the watch has rebooted and reconnected
+    /// following `firmware_upgrade`, and compare it with the expected one
+    pub async fn verify_firmware_version(&self, expected: Option<&str>) -> Result<FirmwareVerification> {
+        let actual = self.read_firmware_version().await?;
+        Ok(FirmwareVerification::new(expected, actual))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mcuboot_image() -> Vec<u8> {
+        let mut image = vec![0; 64];
+        image[0..4].copy_from_slice(&mcuboot::IMAGE_MAGIC.to_le_bytes());
+        image
+    }
+
+    #[test]
+    fn init_packet_layout() {
+        // Same layout as produced by `adafruit-nrfutil dfu genpkg --dev-type 0x0052`
+        let packet = InitPacketData::for_firmware(b"123456789").serialize();
+        assert_eq!(packet, [
+            0x52, 0x00,             // device type
+            0xff, 0xff,             // device revision
+            0xff, 0xff, 0xff, 0xff, // application version
+            0x01, 0x00,             // softdevice list length
+            0xfe, 0xff,             // any softdevice
+            0xb1, 0x29,             // firmware CRC16
+        ]);
+    }
+
+    #[test]
+    fn raw_image_detection() {
+        assert!(is_mcuboot_image(&mcuboot_image()));
+        assert!(!is_mcuboot_image(b"PK\x03\x04"));
+        assert!(!is_mcuboot_image(&[0x3d, 0xb8]));
+    }
+
+    #[test]
+    fn raw_image_extraction() {
+        let image = mcuboot_image();
+        let (init_packet, firmware) = extract_dfu(&image).unwrap();
+        assert_eq!(firmware, image);
+        assert_eq!(init_packet, InitPacketData::for_firmware(&image).serialize());
+
+        let mut too_large = mcuboot_image();
+        too_large.resize(MAX_FIRMWARE_SIZE, 0);
+        assert!(extract_dfu(&too_large).is_err());
+    }
+
+    #[test]
+    fn dfu_package_extraction() {
+        use std::io::Write;
+        use zip::{write::SimpleFileOptions, ZipWriter};
+
+        let image = mcuboot_image();
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        let options = SimpleFileOptions::default();
+        zip.start_file("manifest.json", options).unwrap();
+        zip.write_all(br#"{"manifest": {"application": {
+            "bin_file": "pinetime-mcuboot-app-image.bin",
+            "dat_file": "pinetime-mcuboot-app-image.dat"
+        }}}"#).unwrap();
+        zip.start_file("pinetime-mcuboot-app-image.bin", options).unwrap();
+        zip.write_all(&image).unwrap();
+        zip.start_file("pinetime-mcuboot-app-image.dat", options).unwrap();
+        zip.write_all(b"init").unwrap();
+        let package = zip.finish().unwrap().into_inner();
+
+        let (init_packet, firmware) = extract_dfu(&package).unwrap();
+        assert_eq!(init_packet, b"init");
+        assert_eq!(firmware, image);
+    }
+}
@@ -0,0 +1,203 @@
+use crate::utils;
+use anyhow::{anyhow, ensure, Result};
+use std::fmt;
+
+// Reference: https://docs.mcuboot.com/design.html#image-format
+
+pub const IMAGE_MAGIC: u32 = 0x96f3b83d;
+pub const IMAGE_HEADER_SIZE: usize = 32;
+
+const TLV_INFO_MAGIC: u16 = 0x6907;
+const TLV_PROT_INFO_MAGIC: u16 = 0x6908;
+const TLV_INFO_SIZE: usize = 4;
+const TLV_ENTRY_HEADER_SIZE: usize = 4;
+const TLV_SHA256: u8 = 0x10;
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ImageVersion {
+    pub major: u8,
+    pub minor: u8,
+    pub revision: u16,
+    pub build_num: u32,
+}
+
+impl ImageVersion {
+    /// Version without the build number, as reported by the running firmware
+    pub fn release(&self) -> String {
+        format!("{}.{}.{}", self.major, self.minor, self.revision)
+    }
+}
+
+impl fmt::Display for ImageVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.revision)?;
+        if self.build_num != 0 {
+            write!(f, "+{}", self.build_num)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ImageHeader {
+    pub load_addr: u32,
+    pub header_size: u16,
+    pub protected_tlv_size: u16,
+    pub image_size: u32,
+    pub flags: u32,
+    pub version: ImageVersion,
+}
+
+impl ImageHeader {
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        ensure!(data.len() >= IMAGE_HEADER_SIZE, "Image is too small for mcuboot header");
+        let magic = u32::from_le_bytes(data[0..4].try_into()?);
+        ensure!(magic == IMAGE_MAGIC, "mcuboot header not found (magic: {:#010x})", magic);
+        Ok(Self {
+            load_addr: u32::from_le_bytes(data[4..8].try_into()?),
+            header_size: u16::from_le_bytes(data[8..10].try_into()?),
+            protected_tlv_size: u16::from_le_bytes(data[10..12].try_into()?),
+            image_size: u32::from_le_bytes(data[12..16].try_into()?),
+            flags: u32::from_le_bytes(data[16..20].try_into()?),
+            version: ImageVersion {
+                major: data[20],
+                minor: data[21],
+                revision: u16::from_le_bytes(data[22..24].try_into()?),
+                build_num: u32::from_le_bytes(data[24..28].try_into()?),
+            },
+        })
+    }
+}
+
+/// Parsed mcuboot image metadata
+#[derive(Debug, Clone)]
+pub struct ImageInfo {
+    pub header: ImageHeader,
+    /// Total size of TLV areas (protected and unprotected), including their info headers
+    pub tlv_size: usize,
+    /// Image hash stored in SHA-256 TLV
+    pub sha256: Option<[u8; 32]>,
+}
+
+impl ImageInfo {
+    pub fn parse(image: &[u8]) -> Result<Self> {
+        let header = ImageHeader::parse(image)?;
+        let mut offset = header.header_size as usize + header.image_size as usize;
+        ensure!(offset <= image.len(), "Image is truncated");
+
+        let mut tlv_size = 0;
+        let mut sha256 = None;
+        // Protected TLV area (if any) is followed by the unprotected one
+        while offset + TLV_INFO_SIZE <= image.len() {
+            let magic = u16::from_le_bytes(image[offset..offset + 2].try_into()?);
+            let total = u16::from_le_bytes(image[offset + 2..offset + 4].try_into()?) as usize;
+            if magic != TLV_INFO_MAGIC && magic != TLV_PROT_INFO_MAGIC {
+                break;
+            }
+            let area = image.get(offset + TLV_INFO_SIZE..offset + total)
+                .ok_or(anyhow!("TLV area is truncated"))?;
+            for (kind, value) in TlvIter(area) {
+                if kind == TLV_SHA256 {
+                    sha256 = value.try_into().ok();
+                }
+            }
+            tlv_size += total;
+            offset += total;
+        }
+
+        Ok(Self { header, tlv_size, sha256 })
+    }
+
+    pub fn sha256_hex(&self) -> Option<String> {
+        self.sha256.map(|h| utils::to_hex(&h))
+    }
+}
+
+
+struct TlvIter<'s>(&'s [u8]);
+
+impl<'s> Iterator for TlvIter<'s> {
+    type Item = (u8, &'s [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let data = self.0;
+        let header = data.get(..TLV_ENTRY_HEADER_SIZE)?;
+        let kind = header[0];
+        let length = u16::from_le_bytes([header[2], header[3]]) as usize;
+        let value = data.get(TLV_ENTRY_HEADER_SIZE..TLV_ENTRY_HEADER_SIZE + length)?;
+        self.0 = &data[TLV_ENTRY_HEADER_SIZE + length..];
+        Some((kind, value))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Header of an image produced by `imgtool sign --version 1.14.0
+    // --header-size 32 --align 4 --slot-size 475136 --pad-header`
+    const HEADER: [u8; IMAGE_HEADER_SIZE] = [
+        0x3d, 0xb8, 0xf3, 0x96, // magic
+        0x00, 0x00, 0x00, 0x00, // load address
+        0x20, 0x00,             // header size
+        0x00, 0x00,             // protected TLV size
+        0x08, 0x00, 0x00, 0x00, // image size
+        0x00, 0x00, 0x00, 0x00, // flags
+        0x01, 0x0e, 0x00, 0x00, // version 1.14.0
+        0x00, 0x00, 0x00, 0x00, // build number
+        0x00, 0x00, 0x00, 0x00, // padding
+    ];
+
+    fn image(sha256: &[u8; 32]) -> Vec<u8> {
+        let mut image = HEADER.to_vec();
+        image.extend_from_slice(&[0xaa; 8]);
+        // TLV info: magic and total size, followed by SHA-256 and a key hash TLVs
+        image.extend_from_slice(&[0x07, 0x69, 0x4c, 0x00]);
+        image.extend_from_slice(&[TLV_SHA256, 0x00, 0x20, 0x00]);
+        image.extend_from_slice(sha256);
+        image.extend_from_slice(&[0x01, 0x00, 0x20, 0x00]);
+        image.extend_from_slice(&[0x55; 32]);
+        image
+    }
+
+    #[test]
+    fn header() {
+        let header = ImageHeader::parse(&HEADER).unwrap();
+        assert_eq!(header.header_size, 32);
+        assert_eq!(header.image_size, 8);
+        assert_eq!(header.version, ImageVersion { major: 1, minor: 14, revision: 0, build_num: 0 });
+        assert_eq!(header.version.to_string(), "1.14.0");
+    }
+
+    #[test]
+    fn version_with_build_number() {
+        let version = ImageVersion { major: 1, minor: 15, revision: 2, build_num: 7 };
+        assert_eq!(version.to_string(), "1.15.2+7");
+        assert_eq!(version.release(), "1.15.2");
+    }
+
+    #[test]
+    fn image_tlvs() {
+        let hash = [0x42; 32];
+        let info = ImageInfo::parse(&image(&hash)).unwrap();
+        assert_eq!(info.tlv_size, 0x4c);
+        assert_eq!(info.sha256, Some(hash));
+        assert_eq!(info.sha256_hex().unwrap(), "42".repeat(32));
+    }
+
+    #[test]
+    fn invalid_images() {
+        // No magic, e.g. recovery loader or a random file
+        assert!(ImageInfo::parse(&[0; 64]).is_err());
+        // Too short for the header
+        assert!(ImageInfo::parse(&HEADER[..16]).is_err());
+        // Image body is cut off
+        assert!(ImageInfo::parse(&HEADER).is_err());
+        // TLV area is cut off
+        let mut truncated = image(&[0; 32]);
+        truncated.truncate(truncated.len() - 8);
+        assert!(ImageInfo::parse(&truncated).is_err());
+    }
+}
@@ -1,79 +1,435 @@
+use crate::{policy, utils};
 use super::{fs, InfiniTime, ProgressTx, ProgressTxWrapper};
-// use std::sync::mpsc;
-use std::io::{Cursor, Read};
-// use futures::{pin_mut, StreamExt};
+use std::{
+    cmp::Ordering,
+    collections::{BTreeMap, HashMap},
+    io::{Cursor, Read, Write},
+    path::{Component, Path},
+};
 use anyhow::{anyhow, ensure, Result};
-use serde::Deserialize;
-use version_compare::Version;
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use zip::{write::SimpleFileOptions, ZipWriter};
 
 pub const MAX_RESOURCE_SIZE: usize = 4 * 1024 * 1024;
 
-#[derive(Deserialize, Debug)]
+/// File on the watch recording which resources are installed
+pub const RESOURCES_MARKER_PATH: &str = "/resources-marker.json";
+
+#[derive(Serialize, Deserialize, Debug, Default)]
 struct Resources {
     resources: Vec<Resource>,
     obsolete_files: Vec<ObsoleteFile>,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug)]
 struct Resource {
     filename: String,
     path: String,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug)]
 struct ObsoleteFile {
     path: String,
     since: String,
 }
 
 
+/// Changes that uploading resources makes on the watch filesystem
+#[derive(Debug, Clone, Default)]
+pub struct ResourcePlan {
+    /// Directories that don't exist yet
+    pub dirs: Vec<String>,
+    /// Files that are missing or differ from the archive
+    pub writes: Vec<String>,
+    /// Files that are already up to date
+    pub skips: Vec<String>,
+    /// Obsolete files that are present on the watch
+    pub deletions: Vec<String>,
+}
+
+/// Content of the marker file, written after resources are successfully uploaded
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ResourcesMarker {
+    /// Release tag the resources come from, if known
+    pub version: Option<String>,
+    /// Installation time, seconds since Unix epoch
+    pub installed_at: i64,
+    /// SHA-256 of every installed file by its path on the watch
+    pub files: BTreeMap<String, String>,
+}
+
+impl ResourcesMarker {
+    /// Whether the installed resources match the firmware version,
+    /// `None` if resources version is unknown
+    pub fn matches_firmware(&self, firmware_version: &str) -> Option<bool> {
+        self.version.as_ref().map(|v| policy::versions_match(v, firmware_version))
+    }
+}
+
+impl ResourcePlan {
+    pub fn summary(&self) -> String {
+        format!(
+            "{} files to write, {} unchanged, {} to delete",
+            self.writes.len(), self.skips.len(), self.deletions.len()
+        )
+    }
+}
+
+
 impl InfiniTime {
-    pub async fn upload_resources(&self, resources_archive: &[u8], progress_sender: Option<ProgressTx>) -> Result<()>
-    {
+    /// Upload resources from the archive, skipping the files that are already
+    /// on the watch. Files are considered unchanged if their size matches, or
+    /// if their content matches when `verify` is set (slower, as every such
+    /// file has to be read back).
+    /// On success, the marker file recording `version` and file hashes is
+    /// written (see `read_resources_marker`), and the executed plan is returned.
+    pub async fn upload_resources(
+        &self, resources_archive: &[u8], verify: bool, version: Option<&str>,
+        progress_sender: Option<ProgressTx>,
+    ) -> Result<ResourcePlan> {
+        let progress = ProgressTxWrapper(progress_sender.clone());
+        progress.report_msg("Checking files on the watch").await;
+        let plan = self.plan_resources_upload(resources_archive, verify).await?;
+        self.upload_resources_with_plan(resources_archive, &plan, version, progress_sender).await?;
+        Ok(plan)
+    }
+
+    /// Execute the plan previously obtained from `plan_resources_upload`
+    /// for the same archive (e.g. after the user has reviewed it)
+    pub async fn upload_resources_with_plan(
+        &self, resources_archive: &[u8], plan: &ResourcePlan, version: Option<&str>,
+        progress_sender: Option<ProgressTx>,
+    ) -> Result<()> {
         let progress = ProgressTxWrapper(progress_sender);
 
-        // Parse manifest from the archive
         let mut zip = zip::ZipArchive::new(Cursor::new(resources_archive))?;
-        let mut json = String::new();
-        zip.by_name("resources.json")?.read_to_string(&mut json)?;
-        let manifest: Resources = serde_json::from_str(&json)
-            .map_err(|_| anyhow!("Invalid resources.json"))?;
+        let manifest = read_manifest(&mut zip)?;
+
+        log::info!("Resources upload plan: {}", plan.summary());
+        progress.report_msg(plan.summary()).await;
+
+        // The marker is rewritten at the end, it must not describe
+        // the old resources if uploading fails in the middle
+        if !plan.writes.is_empty() {
+            if let Err(err) = self.delete_file(RESOURCES_MARKER_PATH).await {
+                log::debug!("No resources marker to remove: {}", err);
+            }
+        }
 
         // Make dirs
-        let files = manifest.resources.iter().map(|r| r.path.as_str());
-        for dir in fs::ancestors_union(files) {
+        for dir in &plan.dirs {
             progress.report_msg(format!("Creating directory: {}", dir)).await;
             self.make_dir(dir).await?;
         }
 
-        // Write new files
-        for res in manifest.resources {
-            let mut content = Vec::new();
-            {
-                // file is not Send, so it has to go out of scope befor the next await
-                let mut file = zip.by_name(&res.filename)?;
-                ensure!(file.size() < MAX_RESOURCE_SIZE as u64, "File too large: {}", res.filename);
-                file.read_to_end(&mut content)?;
-            }
-            progress.report_msg(format!("Writing resource file: {}", &res.path)).await;
-            self.write_file(&res.path, &content, 0, progress.0.clone()).await?;
+        // Write new and changed files
+        for (idx, path) in plan.writes.iter().enumerate() {
+            let res = manifest.resources.iter()
+                .find(|r| &r.path == path)
+                .ok_or(anyhow!("Resource not found: {}", path))?;
+            let content = read_resource(&mut zip, &res.filename)?;
+            progress.report_msg(format!(
+                "Writing resource file ({}/{}): {}", idx + 1, plan.writes.len(), path
+            )).await;
+            self.write_file(path, &content, 0, progress.0.clone()).await?;
         }
 
         // Remove obsolete files
+        for path in &plan.deletions {
+            progress.report_msg(format!("Removing obsolete file: {}", path)).await;
+            if let Err(err) = self.delete_file(path).await {
+                log::warn!("Failed to delete file '{}': {}", path, err);
+            }
+        }
+
+        // Record what is installed
+        let mut files = BTreeMap::new();
+        for res in &manifest.resources {
+            let content = read_resource(&mut zip, &res.filename)?;
+            files.insert(res.path.clone(), utils::sha256_hex(&content));
+        }
+        let marker = ResourcesMarker {
+            version: version.map(String::from),
+            installed_at: Utc::now().timestamp(),
+            files,
+        };
+        let json = serde_json::to_vec(&marker)?;
+        progress.report_msg("Writing resources marker").await;
+        self.write_file(RESOURCES_MARKER_PATH, &json, 0, None).await?;
+
+        Ok(())
+    }
+
+    /// Read the marker of installed resources. Returns `None` if resources
+    /// were never installed by this library (or were removed).
+    pub async fn read_resources_marker(&self) -> Result<Option<ResourcesMarker>> {
+        // Check for existence first, to tell a missing file from a read failure
+        let (dir, name) = RESOURCES_MARKER_PATH.rsplit_once('/').unwrap_or(("", RESOURCES_MARKER_PATH));
+        let root = if dir.is_empty() { "/" } else { dir };
+        if !self.list_dir(root).await?.iter().any(|e| e.path.trim_start_matches('/') == name) {
+            return Ok(None);
+        }
+        let content = self.read_file(RESOURCES_MARKER_PATH, 0, None).await?;
+        Ok(Some(serde_json::from_slice(&content)?))
+    }
+
+    /// Dry run of `upload_resources`: find out what would be changed
+    /// on the watch, without writing anything
+    pub async fn plan_resources_upload(&self, resources_archive: &[u8], verify: bool) -> Result<ResourcePlan> {
+        let mut zip = zip::ZipArchive::new(Cursor::new(resources_archive))?;
+        let manifest = read_manifest(&mut zip)?;
+        self.plan_resources(&mut zip, &manifest, verify).await
+    }
+
+    /// Remove all the files listed in the resources.json of the archive, and
+    /// the directories that became empty as a result. Obsolete files are left
+    /// alone, as they are managed by the firmware itself.
+    pub async fn uninstall_resources(
+        &self, resources_archive: &[u8], progress_sender: Option<ProgressTx>
+    ) -> Result<()> {
+        let progress = ProgressTxWrapper(progress_sender);
+
+        let mut zip = zip::ZipArchive::new(Cursor::new(resources_archive))?;
+        let manifest = read_manifest(&mut zip)?;
+
+        // Remove the marker first, so that it never describes partially removed resources
+        if let Err(err) = self.delete_file(RESOURCES_MARKER_PATH).await {
+            log::debug!("No resources marker to remove: {}", err);
+        }
+
+        let total = manifest.resources.len() as u32;
+        for (idx, res) in manifest.resources.iter().enumerate() {
+            progress.report_msg(format!("Removing resource file: {}", &res.path)).await;
+            // Missing files are expected if resources were never fully installed
+            if let Err(err) = self.delete_file(&res.path).await {
+                log::warn!("Failed to delete file '{}': {}", &res.path, err);
+            }
+            progress.report_num(idx as u32 + 1, total).await;
+        }
+
+        // Deepest directories first, non-empty ones fail to be deleted and are kept
+        let files = manifest.resources.iter().map(|r| r.path.as_str());
+        for dir in fs::ancestors_union(files).into_iter().rev() {
+            if let Err(err) = self.delete_file(dir).await {
+                log::info!("Keeping directory '{}': {}", dir, err);
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn plan_resources(
+        &self, zip: &mut zip::ZipArchive<Cursor<&[u8]>>, manifest: &Resources, verify: bool,
+    ) -> Result<ResourcePlan> {
         let fw_version = self.read_firmware_version().await?;
-        let current_version = Version::from(&fw_version)
-            .ok_or(anyhow!("Failed to parse current firmware version"))?;
-        for obsolete in manifest.obsolete_files {
-            if let Some(obsolete_version) = Version::from(&obsolete.since) {
-                if current_version >= obsolete_version {
-                    progress.report_msg(format!("Removing obsolete file: {}", &obsolete.path)).await;
-                    if let Err(err) = self.delete_file(&obsolete.path).await {
-                        log::warn!("Failed to delete file '{}': {}", &obsolete.path, err);
+        let obsolete = manifest.obsolete_files.iter()
+            .filter(|f| matches!(policy::compare(&fw_version, &f.since), Some(Ordering::Greater | Ordering::Equal)))
+            .map(|f| f.path.as_str())
+            .collect::<Vec<_>>();
+
+        // List all relevant directories to find out what's already there.
+        // Only the missing directories of the resources have to be created
+        let resource_dirs = fs::ancestors_union(manifest.resources.iter().map(|r| r.path.as_str()));
+        let paths = manifest.resources.iter().map(|r| r.path.as_str()).chain(obsolete.iter().copied());
+        let mut plan = ResourcePlan::default();
+        let mut existing = HashMap::new();
+        for dir in listed_dirs(paths) {
+            match self.list_dir_if_exists(dir).await? {
+                Some(entries) => {
+                    for entry in entries.into_iter().filter(|e| !e.is_dir) {
+                        existing.insert(child_path(dir, &entry.path), entry.size);
                     }
                 }
+                None if resource_dirs.contains(&dir) => plan.dirs.push(dir.to_string()),
+                None => {}
             }
         }
 
-        Ok(())
+        for res in &manifest.resources {
+            let size = zip.by_name(&res.filename)?.size();
+            let unchanged = match existing.get(&res.path) {
+                Some(&existing_size) if existing_size as u64 == size => {
+                    if verify {
+                        let expected = read_resource(zip, &res.filename)?;
+                        // Treat read errors as mismatch, the file will be rewritten
+                        self.read_file(&res.path, 0, None).await.map_or(false, |c| c == expected)
+                    } else {
+                        true
+                    }
+                }
+                _ => false,
+            };
+            if unchanged {
+                plan.skips.push(res.path.clone());
+            } else {
+                plan.writes.push(res.path.clone());
+            }
+        }
+
+        plan.deletions = obsolete.into_iter()
+            .filter(|path| existing.contains_key(*path))
+            .map(String::from)
+            .collect();
+
+        Ok(plan)
+    }
+}
+
+
+/// Builder of custom resources archives accepted by `upload_resources`
+#[derive(Default)]
+pub struct ResourcePackage {
+    manifest: Resources,
+    files: Vec<Vec<u8>>,
+}
+
+impl ResourcePackage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add file stored as `filename` in the archive and installed to `path` on the watch
+    pub fn add_file(&mut self, filename: &str, path: &str, content: Vec<u8>) -> Result<&mut Self> {
+        ensure!(content.len() < MAX_RESOURCE_SIZE, "File too large: {}", filename);
+        ensure!(path.starts_with('/'), "Path on the watch must be absolute: {}", path);
+        ensure!(
+            !self.manifest.resources.iter().any(|r| r.filename == filename || r.path == path),
+            "Duplicate resource: {}", filename
+        );
+        self.manifest.resources.push(Resource { filename: filename.to_string(), path: path.to_string() });
+        self.files.push(content);
+        Ok(self)
+    }
+
+    /// Mark file on the watch for removal when the firmware is at least `since` version
+    pub fn add_obsolete_file(&mut self, path: &str, since: &str) -> &mut Self {
+        self.manifest.obsolete_files.push(ObsoleteFile { path: path.to_string(), since: since.to_string() });
+        self
+    }
+
+    pub fn build(&self) -> Result<Vec<u8>> {
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        let options = SimpleFileOptions::default();
+        zip.start_file("resources.json", options)?;
+        zip.write_all(serde_json::to_string_pretty(&self.manifest)?.as_bytes())?;
+        for (res, content) in self.manifest.resources.iter().zip(&self.files) {
+            zip.start_file(res.filename.as_str(), options)?;
+            zip.write_all(content)?;
+        }
+        Ok(zip.finish()?.into_inner())
+    }
+}
+
+/// Pack resources from a directory (e.g. InfiniTime build output) into an
+/// archive accepted by `upload_resources`. The directory must contain
+/// resources.json and all the files it references.
+pub fn pack_resources_dir(dir: &Path) -> Result<Vec<u8>> {
+    let json = std::fs::read_to_string(dir.join("resources.json"))
+        .map_err(|e| anyhow!("Failed to read resources.json: {}", e))?;
+    let manifest: Resources = serde_json::from_str(&json)
+        .map_err(|_| anyhow!("Invalid resources.json"))?;
+
+    let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+    let options = SimpleFileOptions::default();
+    zip.start_file("resources.json", options)?;
+    zip.write_all(json.as_bytes())?;
+    for res in &manifest.resources {
+        // The manifest may come from anywhere, it must not refer to files outside the directory
+        let inside = Path::new(&res.filename).components()
+            .all(|c| matches!(c, Component::Normal(_) | Component::CurDir));
+        ensure!(!res.filename.is_empty() && inside, "Invalid file name in resources.json: {}", res.filename);
+        let content = std::fs::read(dir.join(&res.filename))
+            .map_err(|e| anyhow!("Failed to read {}: {}", res.filename, e))?;
+        ensure!(content.len() < MAX_RESOURCE_SIZE, "File too large: {}", res.filename);
+        zip.start_file(res.filename.as_str(), options)?;
+        zip.write_all(&content)?;
+    }
+    Ok(zip.finish()?.into_inner())
+}
+
+/// Directories to list to find the given files. The root directory comes
+/// first, as top-level files (e.g. obsolete ones) have no ancestors.
+fn listed_dirs<'s>(paths: impl Iterator<Item=&'s str>) -> Vec<&'s str> {
+    std::iter::once("/").chain(fs::ancestors_union(paths)).collect()
+}
+
+/// Path of the directory entry `name` listed in `dir`
+fn child_path(dir: &str, name: &str) -> String {
+    format!("{}/{}", dir.trim_end_matches('/'), name.trim_start_matches('/'))
+}
+
+fn read_manifest(zip: &mut zip::ZipArchive<Cursor<&[u8]>>) -> Result<Resources> {
+    let mut json = String::new();
+    zip.by_name("resources.json")?.read_to_string(&mut json)?;
+    serde_json::from_str(&json).map_err(|_| anyhow!("Invalid resources.json"))
+}
+
+fn read_resource(zip: &mut zip::ZipArchive<Cursor<&[u8]>>, filename: &str) -> Result<Vec<u8>> {
+    // ZipFile is not Send, so it must never be held across an await
+    let mut file = zip.by_name(filename)?;
+    ensure!(file.size() < MAX_RESOURCE_SIZE as u64, "File too large: {}", filename);
+    let mut content = Vec::with_capacity(file.size() as usize);
+    file.read_to_end(&mut content)?;
+    Ok(content)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn root_dir_is_listed() {
+        let paths = ["/foo.bin", "/fonts/lv_font.bin", "/fonts/sub/a.bin"];
+        assert_eq!(listed_dirs(paths.into_iter()), ["/", "/fonts", "/fonts/sub"]);
+        assert_eq!(listed_dirs(std::iter::empty()), ["/"]);
+    }
+
+    #[test]
+    fn root_level_obsolete_file_is_found() {
+        // Listing entries may or may not have the leading slash
+        assert_eq!(child_path("/", "foo.bin"), "/foo.bin");
+        assert_eq!(child_path("/", "/foo.bin"), "/foo.bin");
+        assert_eq!(child_path("/fonts", "a.bin"), "/fonts/a.bin");
+        assert_eq!(child_path("/fonts", "/a.bin"), "/fonts/a.bin");
+    }
+
+    /// Directory with a single font file, and resources.json referring to it by `filenames`
+    fn resources_dir(name: &str, filenames: &[&str]) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("infinitime-resources-{}-{}", std::process::id(), name));
+        _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("fonts")).unwrap();
+        std::fs::write(dir.join("fonts").join("font.bin"), b"font").unwrap();
+        let resources = filenames.iter()
+            .map(|filename| Resource { filename: filename.to_string(), path: "/fonts/font.bin".to_string() })
+            .collect();
+        let manifest = Resources { resources, obsolete_files: Vec::new() };
+        std::fs::write(dir.join("resources.json"), serde_json::to_string(&manifest).unwrap()).unwrap();
+        dir
+    }
+
+    #[test]
+    fn pack_dir() {
+        let dir = resources_dir("valid", &["fonts/font.bin"]);
+        let archive = pack_resources_dir(&dir).unwrap();
+        let mut zip = zip::ZipArchive::new(Cursor::new(archive.as_slice())).unwrap();
+        assert_eq!(read_manifest(&mut zip).unwrap().resources.len(), 1);
+        assert_eq!(read_resource(&mut zip, "fonts/font.bin").unwrap(), b"font");
+        _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn pack_dir_rejects_files_outside() {
+        // All of them refer to the existing font file, but not from within the directory
+        let name = format!("infinitime-resources-{}-outside", std::process::id());
+        let absolute = std::env::temp_dir().join(&name).join("fonts").join("font.bin");
+        let parent = format!("../{}/fonts/font.bin", name);
+        let nested = format!("fonts/../../{}/fonts/font.bin", name);
+        for filename in ["", &absolute.to_string_lossy(), &parent, &nested] {
+            let dir = resources_dir("outside", &[filename]);
+            assert!(pack_resources_dir(&dir).is_err(), "{}", filename);
+            _ = std::fs::remove_dir_all(&dir);
+        }
     }
 }
@@ -1,6 +1,9 @@
 use uuid::{uuid, Uuid};
 
 pub const SRV_CURRENT_TIME: Uuid = uuid!("00001805-0000-1000-8000-00805f9b34fb");
+pub const SRV_FS: Uuid = uuid!("0000febb-0000-1000-8000-00805f9b34fb");
+pub const SRV_FWUPD: Uuid = uuid!("00001530-1212-efde-1523-785feabcd123");
+pub const SRV_MP: Uuid = uuid!("00000000-78fc-48fe-8e23-433b3a1942d0");
 
 pub const CHR_CURRENT_TIME: Uuid = uuid!("00002a2b-0000-1000-8000-00805f9b34fb");
 
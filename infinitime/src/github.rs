@@ -1,8 +1,18 @@
-use std::{env, path::{Path, PathBuf}};
+use crate::{bt::{ProgressTx, ProgressTxWrapper}, policy, utils};
+use std::{cmp::Ordering, fmt, io::Cursor, path::{Path, PathBuf}};
 use tokio::{fs::File, io::AsyncWriteExt};
-use anyhow::{anyhow, Result};
-use serde::Deserialize;
-use reqwest::IntoUrl;
+use anyhow::{anyhow, ensure, Context, Result};
+use chrono::{DateTime, Local, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+
+mod library;
+mod sources;
+
+pub use library::{Library, LibraryEntry};
+pub use sources::{
+    all_releases, GitHubArtifacts, GitHubReleases, GiteaReleases, LocalReleases, ReleaseSource,
+    SourceConfig, SourceKind, INFINITIME_REPO,
+};
 
 #[derive(Deserialize, Debug, PartialEq)]
 pub struct ReleaseInfo {
@@ -12,6 +22,16 @@ pub struct ReleaseInfo {
     #[serde(rename = "html_url")]
     pub url: String,
     pub assets: Vec<Asset>,
+    #[serde(default)]
+    pub prerelease: bool,
+    /// Drafts are only visible to the repository maintainers
+    #[serde(default)]
+    pub draft: bool,
+    #[serde(default)]
+    pub published_at: Option<DateTime<Utc>>,
+    /// Release notes in Markdown
+    #[serde(default)]
+    pub body: Option<String>,
 }
 
 #[derive(Clone, Deserialize, Debug, PartialEq)]
@@ -22,83 +42,471 @@ pub struct Asset {
     pub direct_url: String,
     pub content_type: String,
     pub size: u32,
+    /// Checksum in "sha256:<hex>" form, if published
+    #[serde(default)]
+    pub digest: Option<String>,
+}
+
+/// Kind of the release artifact, derived from its file name
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AssetKind {
+    /// InfiniTime application DFU package, flashable over BLE
+    ApplicationDfu,
+    /// Raw mcuboot application image
+    ApplicationImage,
+    /// External resources package (fonts, images)
+    Resources,
+    /// Recovery loader DFU package, flashable over BLE. It installs the
+    /// recovery firmware into the external flash
+    RecoveryLoaderDfu,
+    /// Raw mcuboot recovery loader image
+    RecoveryLoaderImage,
+    /// Recovery firmware image
+    RecoveryFirmware,
+    /// Bootloader updater (reloader) DFU package, flashable over BLE
+    BootloaderDfu,
+    /// Images for flashing via SWD (.hex, .out, non-mcuboot .bin)
+    Standalone,
+    /// Anything else (e.g. InfiniSim builds, checksums)
+    Other,
+}
+
+impl AssetKind {
+    pub fn from_name(name: &str) -> Self {
+        let is_zip = name.ends_with(".zip");
+        let is_bin = name.ends_with(".bin");
+        if name.starts_with("infinitime-resources") && is_zip {
+            Self::Resources
+        } else if name.starts_with("pinetime-mcuboot-app-dfu") && is_zip {
+            Self::ApplicationDfu
+        } else if name.starts_with("pinetime-mcuboot-app-image") && is_bin {
+            Self::ApplicationImage
+        } else if name.starts_with("pinetime-mcuboot-recovery-loader-dfu") && is_zip {
+            Self::RecoveryLoaderDfu
+        } else if name.starts_with("pinetime-mcuboot-recovery-loader-image") && is_bin {
+            Self::RecoveryLoaderImage
+        } else if name.starts_with("pinetime-recovery") && !name.contains("loader") {
+            Self::RecoveryFirmware
+        } else if (name.contains("reloader") || name.contains("bootloader")) && is_zip {
+            Self::BootloaderDfu
+        } else if name.starts_with("pinetime-")
+            && (name.ends_with(".hex") || name.ends_with(".out") || is_bin)
+        {
+            Self::Standalone
+        } else {
+            Self::Other
+        }
+    }
+
+    /// Whether flashing the asset replaces system components
+    /// and thus requires explicit confirmation
+    pub fn is_expert(&self) -> bool {
+        matches!(self, Self::RecoveryLoaderDfu | Self::BootloaderDfu)
+    }
+}
+
+impl Asset {
+    pub fn kind(&self) -> AssetKind {
+        AssetKind::from_name(&self.name)
+    }
+
+    /// Expected SHA-256 of the content in hex, if known
+    pub fn sha256(&self) -> Option<&str> {
+        self.digest.as_deref()?.strip_prefix("sha256:")
+    }
+
+    fn is_zip(&self) -> bool {
+        self.name.ends_with(".zip") || self.content_type.contains("zip")
+    }
+
+    /// Check downloaded content against the size, format and checksum of the asset
+    pub fn verify(&self, content: &[u8]) -> Result<()> {
+        ensure!(
+            self.size == 0 || content.len() == self.size as usize,
+            "Downloaded {} bytes instead of {}", content.len(), self.size
+        );
+        if self.is_zip() {
+            zip::ZipArchive::new(Cursor::new(content)).context("Downloaded file is not a valid zip archive")?;
+        }
+        if let Some(expected) = self.sha256() {
+            let actual = utils::sha256_hex(content);
+            ensure!(actual.eq_ignore_ascii_case(expected), "Checksum mismatch: expected {}, got {}", expected, actual);
+        }
+        Ok(())
+    }
 }
 
 impl ReleaseInfo {
+    pub fn get_asset(&self, kind: AssetKind) -> Option<&Asset> {
+        self.assets.iter().find(|a| a.kind() == kind)
+    }
+
     pub fn get_dfu_asset(&self) -> Option<&Asset> {
-        self.assets.iter().find(|a| {
-            a.name.starts_with("pinetime-mcuboot-app-dfu") &&
-            a.name.ends_with(".zip")
-        })
+        self.get_asset(AssetKind::ApplicationDfu)
     }
 
     pub fn get_resources_asset(&self) -> Option<&Asset> {
-        self.assets.iter().find(|a| {
-            a.name.starts_with("infinitime-resources") &&
-            a.name.ends_with(".zip")
+        self.get_asset(AssetKind::Resources)
+    }
+
+    pub fn is_stable(&self) -> bool {
+        !self.prerelease && !self.draft
+    }
+
+    pub fn notes(&self) -> &str {
+        self.body.as_deref().map(str::trim).unwrap_or_default()
+    }
+}
+
+/// Releases that are newer than `installed`, up to and including `target`,
+/// newest first. Intermediate prereleases are skipped.
+pub fn releases_between<'a>(releases: &'a [ReleaseInfo], installed: &str, target: &str) -> Vec<&'a ReleaseInfo> {
+    let mut between = releases.iter()
+        .filter(|r| !r.draft)
+        .filter(|r| policy::compare(&r.tag, installed) == Some(Ordering::Greater))
+        .filter(|r| match policy::compare(&r.tag, target) {
+            Some(Ordering::Less) => !r.prerelease,
+            Some(Ordering::Equal) => r.tag == target,
+            _ => false,
         })
+        .collect::<Vec<_>>();
+    between.sort_by(|a, b| policy::compare(&b.tag, &a.tag).unwrap_or(Ordering::Equal));
+    between
+}
+
+/// Notes of all the given releases in Markdown, one section per release
+pub fn combined_notes(releases: &[&ReleaseInfo]) -> String {
+    releases.iter()
+        .map(|r| match r.notes() {
+            "" => format!("# {}\n\nNo release notes", r.tag),
+            notes => format!("# {}\n\n{}", r.tag, notes),
+        })
+        .collect::<Vec<_>>()
+        .join("\n\n")
+}
+
+/// One page of the releases list
+#[derive(Debug, Default)]
+pub struct ReleasePage {
+    pub releases: Vec<ReleaseInfo>,
+    /// Whether the next page might have more releases
+    pub has_more: bool,
+}
+
+impl ReleasePage {
+    /// Full page suggests that there might be more. Worst case,
+    /// the next page is empty.
+    fn new(releases: Vec<ReleaseInfo>, per_page: usize) -> Self {
+        Self { has_more: releases.len() >= per_page, releases }
     }
 }
 
-pub async fn list_releases() -> Result<Vec<ReleaseInfo>> {
-    let client = reqwest::Client::new();
-    let response = client
-        .get("https://api.github.com/repos/InfiniTimeOrg/InfiniTime/releases")
-        .header("Accept", "application/vnd.github+json")
-        .header("User-Agent", "Watchmate")
-        .send().await?;
+/// Which releases to show to the user. Drafts are never shown.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ReleaseFilter {
+    pub include_prereleases: bool,
+    /// Only releases providing resources package
+    pub require_resources: bool,
+}
 
-    let status = response.status();
-    if status.is_success() {
-        let releases = response.json().await?;
-        // dbg!(&releases);
-        Ok(releases)
-    } else {
-        let text = response.text().await?;
-        log::error!("Request failed: {}\n{}", status, text);
-        Err(anyhow!("Request failed: {}", status))
+impl ReleaseFilter {
+    pub fn matches(&self, release: &ReleaseInfo) -> bool {
+        !release.draft
+            && (self.include_prereleases || !release.prerelease)
+            && (!self.require_resources || release.get_resources_asset().is_some())
     }
 }
 
-pub async fn download_content(url: impl IntoUrl) -> Result<Vec<u8>>
-{
-    let client = reqwest::Client::new();
-    let response = client
-        .get(url)
-        .header("Accept", "application/octet-stream")
-        .header("User-Agent", "Watchmate")
-        .send().await?;
+const GITHUB_API: &str = "https://api.github.com";
+
+
+/// GitHub API rate limit is exhausted until the reset time
+#[derive(Debug)]
+pub struct RateLimitError {
+    pub reset: Option<DateTime<Local>>,
+}
 
-    let status = response.status();
-    if status.is_success() {
-        let content = response.bytes().await?;
-        Ok(content.to_vec())
-    } else {
-        let text = response.text().await?;
-        log::error!("Request failed: {}\n{}", status, text);
-        Err(anyhow!("Request failed: {}", status))
+impl fmt::Display for RateLimitError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.reset {
+            Some(reset) => write!(f, "GitHub API rate limit exceeded, try again after {}", reset.format("%H:%M")),
+            None => write!(f, "GitHub API rate limit exceeded, try again later"),
+        }
     }
 }
 
+impl std::error::Error for RateLimitError {}
+
+
+/// Value that might come from the on-disk cache
+#[derive(Debug)]
+pub struct Cached<T> {
+    pub value: T,
+    /// Set if the request failed (e.g. offline or rate-limited)
+    /// and the cached value is used instead of a fresh one
+    pub error: Option<anyhow::Error>,
+}
+
+impl<T> Cached<T> {
+    fn try_map<U>(self, f: impl FnOnce(T) -> Result<U>) -> Result<Cached<U>> {
+        Ok(Cached { value: f(self.value)?, error: self.error })
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct CacheEntry {
+    etag: Option<String>,
+    body: String,
+}
+
+
+/// GitHub API client. Responses are cached on disk and revalidated with
+/// ETag, which doesn't count against the rate limit if nothing changed.
+#[derive(Clone, Default)]
+pub struct Client {
+    http: reqwest::Client,
+    cache_dir: Option<PathBuf>,
+    token: Option<String>,
+}
+
+impl Client {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_cache_dir(mut self, cache_dir: impl Into<PathBuf>) -> Self {
+        self.cache_dir = Some(cache_dir.into());
+        self
+    }
+
+    /// Personal access token raises the rate limit from 60 to 5000 requests per hour
+    pub fn with_token(mut self, token: Option<String>) -> Self {
+        self.token = token.filter(|t| !t.trim().is_empty());
+        self
+    }
+
+    pub fn has_token(&self) -> bool {
+        self.token.is_some()
+    }
+
+    /// Releases of the official InfiniTime repository, `page` starts from 1
+    pub async fn list_releases(&self, page: u32) -> Result<Cached<ReleasePage>> {
+        GitHubReleases::new(self.clone(), INFINITIME_REPO).list_releases(page).await
+    }
+
+    /// Download release asset, reporting progress in bytes. `size` is used
+    /// as the total if the server doesn't report it, 0 if unknown.
+    /// The token is only sent to the GitHub API.
+    pub async fn download(&self, url: &str, size: u32, progress_sender: Option<ProgressTx>) -> Result<Vec<u8>> {
+        let progress = ProgressTxWrapper(progress_sender);
+        let mut request = self.http
+            .get(url)
+            .header("Accept", "application/octet-stream")
+            .header("User-Agent", "Watchmate");
+        if let Some(token) = self.token.as_ref().filter(|_| url.starts_with(GITHUB_API)) {
+            request = request.bearer_auth(token);
+        }
+        let mut response = request.send().await?;
+
+        let status = response.status();
+        if status.is_success() {
+            let is_html = response.headers().get("content-type")
+                .and_then(|v| v.to_str().ok())
+                .map_or(false, |v| v.starts_with("text/html"));
+            ensure!(!is_html, "Server returned a web page instead of the file");
+
+            let total = response.content_length().map_or(size, |l| l as u32);
+            let mut content = Vec::with_capacity(total as usize);
+            while let Some(chunk) = response.chunk().await? {
+                content.extend_from_slice(&chunk);
+                progress.report_num(content.len() as u32, total.max(content.len() as u32)).await;
+            }
+            Ok(content)
+        } else {
+            let text = response.text().await?;
+            log::error!("Request failed: {}\n{}", status, text);
+            Err(anyhow!("Request failed: {}", status))
+        }
+    }
+
+    /// GET request with the response body cached on disk
+    async fn get_cached_json(&self, url: &str) -> Result<Cached<String>> {
+        let cache_name = format!("{}.json", &crate::utils::sha256_hex(url.as_bytes())[..16]);
+        let cache_path = self.cache_dir.as_ref().map(|dir| dir.join(cache_name));
+        let cache = match &cache_path {
+            Some(path) => read_cache(path).await,
+            None => None,
+        };
+
+        let mut request = self.http
+            .get(url)
+            .header("Accept", "application/vnd.github+json")
+            .header("User-Agent", "Watchmate");
+        if let Some(token) = self.token.as_ref().filter(|_| url.starts_with(GITHUB_API)) {
+            request = request.bearer_auth(token);
+        }
+        if let Some(etag) = cache.as_ref().and_then(|c| c.etag.as_ref()) {
+            request = request.header("If-None-Match", etag);
+        }
+
+        let result = match request.send().await {
+            Ok(response) => self.handle_response(response).await,
+            Err(error) => Err(error.into()),
+        };
+
+        match (result, cache) {
+            (Ok(None), Some(cache)) => {
+                log::debug!("Not modified: {}", url);
+                Ok(Cached { value: cache.body, error: None })
+            }
+            (Ok(None), None) => Err(anyhow!("Not modified, but nothing is cached")),
+            (Ok(Some(entry)), _) => {
+                if let Some(path) = &cache_path {
+                    if let Err(error) = write_cache(path, &entry).await {
+                        log::warn!("Failed to write cache '{}': {}", path.display(), error);
+                    }
+                }
+                Ok(Cached { value: entry.body, error: None })
+            }
+            (Err(error), Some(cache)) => {
+                log::warn!("Request failed, using cached response: {}", error);
+                Ok(Cached { value: cache.body, error: Some(error) })
+            }
+            (Err(error), None) => Err(error),
+        }
+    }
+
+    /// Returns `None` if the resource is not modified
+    async fn handle_response(&self, response: reqwest::Response) -> Result<Option<CacheEntry>> {
+        let status = response.status();
+        let header = |name: &str| {
+            response.headers().get(name).and_then(|v| v.to_str().ok()).map(String::from)
+        };
+        let etag = header("etag");
+        let remaining = header("x-ratelimit-remaining");
+        let reset = header("x-ratelimit-reset").and_then(|v| v.parse::<i64>().ok());
+        let retry_after = header("retry-after").and_then(|v| v.parse::<i64>().ok());
+
+        if let Some(remaining) = &remaining {
+            log::debug!("GitHub API rate limit remaining: {}", remaining);
+        }
+
+        let is_rate_limited = (status == reqwest::StatusCode::FORBIDDEN || status == reqwest::StatusCode::TOO_MANY_REQUESTS)
+            && (remaining.as_deref() == Some("0") || retry_after.is_some());
+
+        if status == reqwest::StatusCode::NOT_MODIFIED {
+            Ok(None)
+        } else if status.is_success() {
+            Ok(Some(CacheEntry { etag, body: response.text().await? }))
+        } else if is_rate_limited {
+            // Secondary rate limits only come with the Retry-After header
+            let reset = retry_after.map(|secs| Utc::now().timestamp() + secs).or(reset)
+                .and_then(|ts| Local.timestamp_opt(ts, 0).single());
+            Err(RateLimitError { reset }.into())
+        } else {
+            let text = response.text().await?;
+            log::error!("Request failed: {}\n{}", status, text);
+            Err(anyhow!("Request failed: {}", status))
+        }
+    }
+}
+
+async fn read_cache(path: &Path) -> Option<CacheEntry> {
+    let json = tokio::fs::read_to_string(path).await.ok()?;
+    serde_json::from_str(&json).ok()
+}
+
+async fn write_cache(path: &Path, entry: &CacheEntry) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        tokio::fs::create_dir_all(dir).await?;
+    }
+    tokio::fs::write(path, serde_json::to_string(entry)?).await?;
+    Ok(())
+}
+
+
+pub async fn download_content(url: &str) -> Result<Vec<u8>>
+{
+    Client::new().download(url, 0, None).await
+}
+
+
 pub async fn save_file(content: &[u8], filepath: impl AsRef<Path>) -> Result<()> {
     let mut file = File::create(&filepath).await?;
     file.write_all(&content).await?;
     Ok(())
 }
 
-pub async fn _download_file(url: impl IntoUrl, filepath: impl AsRef<Path>) -> Result<()> {
-    let content = download_content(url).await?;
-    save_file(&content, filepath).await?;
-    Ok(())
-}
 
-pub fn _get_download_dir() -> Result<PathBuf> {
-    match env::var("XDG_DOWNLOAD_DIR") {
-        Ok(value) => Ok(PathBuf::from(value)),
-        Err(_) => Ok(Path::new(&env::var("HOME")?).join("Downloads")),
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn release(tag: &str, prerelease: bool, draft: bool) -> ReleaseInfo {
+        ReleaseInfo {
+            name: tag.to_string(),
+            tag: tag.to_string(),
+            url: String::new(),
+            assets: Vec::new(),
+            prerelease,
+            draft,
+            published_at: None,
+            body: None,
+        }
+    }
+
+    fn tags(releases: Vec<&ReleaseInfo>) -> Vec<&str> {
+        releases.iter().map(|r| r.tag.as_str()).collect()
+    }
+
+    #[test]
+    fn releases_between_installed_and_target() {
+        let releases = [
+            release("1.15.0", false, false),
+            release("1.14.1", false, false),
+            release("1.14.0", false, false),
+            release("1.13.0", false, false),
+        ];
+        assert_eq!(tags(releases_between(&releases, "1.13.0", "1.14.1")), ["1.14.1", "1.14.0"]);
+        assert_eq!(tags(releases_between(&releases, "1.14.1", "1.14.1")), Vec::<&str>::new());
+        assert_eq!(tags(releases_between(&releases, "1.15.0", "1.13.0")), Vec::<&str>::new());
+    }
+
+    #[test]
+    fn releases_between_sorted_newest_first() {
+        let releases = [
+            release("1.14.0", false, false),
+            release("1.15.0", false, false),
+            release("1.14.1", false, false),
+        ];
+        assert_eq!(tags(releases_between(&releases, "1.13.0", "1.15.0")), ["1.15.0", "1.14.1", "1.14.0"]);
+    }
+
+    #[test]
+    fn releases_between_skips_drafts_and_intermediate_prereleases() {
+        let releases = [
+            release("1.16.0-rc1", true, false),
+            release("1.15.0", false, true),
+            release("1.15.0-rc1", true, false),
+            release("1.14.0", false, false),
+        ];
+        assert_eq!(tags(releases_between(&releases, "1.13.0", "1.16.0-rc1")), ["1.16.0-rc1", "1.14.0"]);
     }
-}
 
-pub fn _get_download_filepath(filename: impl AsRef<Path>) -> Result<PathBuf> {
-    Ok(_get_download_dir()?.join(&filename))
+    #[test]
+    fn releases_between_unknown_installed_version() {
+        let releases = [release("1.14.0", false, false)];
+        assert_eq!(tags(releases_between(&releases, "unknown", "1.14.0")), Vec::<&str>::new());
+    }
+
+    #[test]
+    fn combined_notes_per_release() {
+        let mut newer = release("1.15.0", false, false);
+        newer.body = Some("  Fixes\n".to_string());
+        let older = release("1.14.0", false, false);
+        assert_eq!(
+            combined_notes(&[&newer, &older]),
+            "# 1.15.0\n\nFixes\n\n# 1.14.0\n\nNo release notes",
+        );
+    }
 }
@@ -0,0 +1,389 @@
+use super::{Asset, AssetKind, ReleaseSource};
+use crate::{bt::{ProgressTx, ProgressTxWrapper}, policy, utils};
+use anyhow::{ensure, Context, Result};
+use chrono::{DateTime, Local, Utc};
+use serde::{Deserialize, Serialize};
+use std::{cmp::Reverse, collections::{BTreeMap, BTreeSet}, fs, path::{Path, PathBuf}};
+
+const INDEX_FILE: &str = "library.json";
+const CHECKSUMS_FILE: &str = "SHA256SUMS";
+
+
+/// Release asset stored in the `Library`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct LibraryEntry {
+    /// Tag of the release the asset belongs to
+    pub tag: String,
+    /// Asset file name
+    pub name: String,
+    pub size: u64,
+    /// SHA-256 of the content in hex
+    pub sha256: String,
+    /// URL the asset was downloaded from
+    pub source: String,
+    pub added: DateTime<Utc>,
+    /// Last time the stored copy was used instead of downloading
+    #[serde(default)]
+    pub used: Option<DateTime<Utc>>,
+}
+
+impl LibraryEntry {
+    pub fn kind(&self) -> AssetKind {
+        AssetKind::from_name(&self.name)
+    }
+
+    pub fn added_time(&self) -> DateTime<Local> {
+        self.added.with_timezone(&Local)
+    }
+
+    /// When the asset was last added or used
+    pub fn last_used(&self) -> DateTime<Utc> {
+        self.used.map_or(self.added, |used| used.max(self.added))
+    }
+}
+
+
+/// Managed collection of downloaded release assets, so that known versions
+/// can be flashed again without network access. Assets are stored as
+/// `<dir>/<tag>/<name>` with `SHA256SUMS` in every release folder, so the
+/// library directory can also be used with `LocalReleases`. Metadata is kept
+/// in a JSON index, which is re-read on every access, as with `Journal`.
+/// File access and hashing run on the blocking thread pool.
+#[derive(Debug, Clone)]
+pub struct Library {
+    dir: PathBuf,
+}
+
+impl Library {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// All stored assets, newest release first
+    pub async fn entries(&self) -> Result<Vec<LibraryEntry>> {
+        self.blocking(Self::read_entries).await?
+    }
+
+    /// Total size of the stored assets in bytes
+    pub async fn total_size(&self) -> Result<u64> {
+        Ok(self.entries().await?.iter().map(|e| e.size).sum())
+    }
+
+    pub fn path(&self, entry: &LibraryEntry) -> PathBuf {
+        self.dir.join(&entry.tag).join(&entry.name)
+    }
+
+    pub async fn remove(&self, entry: &LibraryEntry) -> Result<()> {
+        let entry = entry.clone();
+        self.blocking(move |library| library.remove_entry(&entry)).await?
+    }
+
+    /// Remove assets of all but the `keep` most recently added or used
+    /// releases, and return the removed assets. The `current` release,
+    /// e.g. the one just downloaded, is always kept.
+    pub async fn prune(&self, keep: usize, current: Option<String>) -> Result<Vec<LibraryEntry>> {
+        self.blocking(move |library| library.prune_entries(keep, current.as_deref())).await?
+    }
+
+    /// Content of the release asset from the library if it's there,
+    /// otherwise downloaded from the source and added to the library.
+    /// Library failures are only logged, as it's just a cache.
+    pub async fn download(
+        &self, source: &dyn ReleaseSource, tag: &str, asset: &Asset, progress_sender: Option<ProgressTx>,
+    ) -> Result<Vec<u8>> {
+        let (tag_, asset_) = (tag.to_string(), asset.clone());
+        match self.blocking(move |library| library.get(&tag_, &asset_)).await? {
+            Ok(Some(content)) => {
+                log::info!("Using {} {} from the library", tag, asset.name);
+                let size = content.len() as u32;
+                ProgressTxWrapper(progress_sender).report_num(size, size).await;
+                return Ok(content);
+            }
+            Ok(None) => {}
+            Err(error) => log::warn!("Failed to read firmware library: {}", error),
+        }
+        let content = source.download(asset, progress_sender).await?;
+        let (tag_, asset_) = (tag.to_string(), asset.clone());
+        let (content, added) = self.blocking(move |library| {
+            let added = library.add(&tag_, &asset_, &content);
+            (content, added)
+        }).await?;
+        if let Err(error) = added {
+            log::warn!("Failed to add {} {} to the library: {}", tag, asset.name, error);
+        }
+        Ok(content)
+    }
+
+    async fn blocking<T: Send + 'static>(&self, f: impl FnOnce(&Self) -> T + Send + 'static) -> Result<T> {
+        let library = self.clone();
+        Ok(tokio::task::spawn_blocking(move || f(&library)).await?)
+    }
+
+    fn read_entries(&self) -> Result<Vec<LibraryEntry>> {
+        let mut entries: Vec<LibraryEntry> = match fs::read_to_string(self.dir.join(INDEX_FILE)) {
+            Ok(json) => serde_json::from_str(&json)?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(err) => return Err(err.into()),
+        };
+        entries.sort_by(|a, b| {
+            policy::compare(&b.tag, &a.tag)
+                .unwrap_or_else(|| b.tag.cmp(&a.tag))
+                .then_with(|| a.name.cmp(&b.name))
+        });
+        Ok(entries)
+    }
+
+    fn find(&self, tag: &str, name: &str) -> Result<Option<LibraryEntry>> {
+        Ok(self.read_entries()?.into_iter().find(|e| e.tag == tag && e.name == name))
+    }
+
+    /// Content of the stored asset, verified against the recorded size and checksum
+    fn read(&self, entry: &LibraryEntry) -> Result<Vec<u8>> {
+        let path = self.path(entry);
+        let content = fs::read(&path).with_context(|| format!("Failed to read {}", path.display()))?;
+        ensure!(content.len() as u64 == entry.size, "Stored {} is {} bytes instead of {}", entry.name, content.len(), entry.size);
+        let actual = utils::sha256_hex(&content);
+        ensure!(actual == entry.sha256, "Stored {} is corrupted: expected SHA-256 {}, got {}", entry.name, entry.sha256, actual);
+        Ok(content)
+    }
+
+    /// Content of the release asset if it's stored and still matches the
+    /// published checksum. Corrupted or outdated copies are removed.
+    /// Copies downloaded from another source (e.g. a fork with the same
+    /// tags) don't match, they are replaced once the asset is downloaded.
+    fn get(&self, tag: &str, asset: &Asset) -> Result<Option<Vec<u8>>> {
+        let Some(entry) = self.find(tag, &asset.name)? else {
+            return Ok(None);
+        };
+        if entry.source != asset.direct_url {
+            log::info!("Stored {} {} comes from another source: {}", entry.tag, entry.name, entry.source);
+            return Ok(None);
+        }
+        let content = match self.read(&entry) {
+            Ok(content) => content,
+            Err(error) => {
+                log::warn!("Removing {} {} from the library: {}", entry.tag, entry.name, error);
+                self.remove_entry(&entry)?;
+                return Ok(None);
+            }
+        };
+        if let Err(error) = asset.verify(&content) {
+            log::warn!("Removing {} {} from the library, release asset changed: {}", entry.tag, entry.name, error);
+            self.remove_entry(&entry)?;
+            return Ok(None);
+        }
+        let used = self.modify(|entries| {
+            for e in entries.iter_mut().filter(|e| e.tag == entry.tag && e.name == entry.name) {
+                e.used = Some(Utc::now());
+            }
+        });
+        if let Err(error) = used {
+            log::warn!("Failed to record use of {} {} in the library: {}", entry.tag, entry.name, error);
+        }
+        Ok(Some(content))
+    }
+
+    /// Store the asset content, replacing the previously stored copy
+    fn add(&self, tag: &str, asset: &Asset, content: &[u8]) -> Result<LibraryEntry> {
+        let entry = LibraryEntry {
+            tag: path_component(tag)?.to_string(),
+            name: path_component(&asset.name)?.to_string(),
+            size: content.len() as u64,
+            sha256: utils::sha256_hex(content),
+            source: asset.direct_url.clone(),
+            added: Utc::now(),
+            used: None,
+        };
+        let path = self.path(&entry);
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        write_atomically(&path, content)?;
+        self.modify(|entries| {
+            entries.retain(|e| e.tag != entry.tag || e.name != entry.name);
+            entries.push(entry.clone());
+        })?;
+        Ok(entry)
+    }
+
+    fn remove_entry(&self, entry: &LibraryEntry) -> Result<()> {
+        match fs::remove_file(self.path(entry)) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err.into()),
+            _ => {}
+        }
+        self.modify(|entries| entries.retain(|e| e.tag != entry.tag || e.name != entry.name))
+    }
+
+    fn prune_entries(&self, keep: usize, current: Option<&str>) -> Result<Vec<LibraryEntry>> {
+        let entries = self.read_entries()?;
+        let mut last_used = BTreeMap::<&str, DateTime<Utc>>::new();
+        for entry in &entries {
+            let time = last_used.entry(&entry.tag).or_insert(entry.last_used());
+            *time = (*time).max(entry.last_used());
+        }
+        let mut tags = last_used.into_iter().collect::<Vec<_>>();
+        tags.sort_by_key(|(_, time)| Reverse(*time));
+
+        let mut kept_tags = current.into_iter().collect::<BTreeSet<_>>();
+        for (tag, _) in tags {
+            if kept_tags.len() >= keep {
+                break;
+            }
+            kept_tags.insert(tag);
+        }
+        let removed = entries.iter()
+            .filter(|e| !kept_tags.contains(e.tag.as_str()))
+            .cloned()
+            .collect::<Vec<_>>();
+        for entry in &removed {
+            self.remove_entry(entry)?;
+        }
+        Ok(removed)
+    }
+
+    fn modify(&self, f: impl FnOnce(&mut Vec<LibraryEntry>)) -> Result<()> {
+        let mut entries = self.read_entries()?;
+        let old_tags = entries.iter().map(|e| e.tag.clone()).collect::<BTreeSet<_>>();
+        f(&mut entries);
+        fs::create_dir_all(&self.dir)?;
+        write_atomically(&self.dir.join(INDEX_FILE), serde_json::to_string_pretty(&entries)?.as_bytes())?;
+
+        // Keep checksums of every release in sync, and drop empty folders
+        let new_tags = entries.iter().map(|e| e.tag.clone()).collect::<BTreeSet<_>>();
+        for tag in old_tags.union(&new_tags) {
+            let dir = self.dir.join(tag);
+            let checksums = entries.iter()
+                .filter(|e| &e.tag == tag)
+                .map(|e| format!("{}  {}\n", e.sha256, e.name))
+                .collect::<String>();
+            if checksums.is_empty() {
+                _ = fs::remove_file(dir.join(CHECKSUMS_FILE));
+                _ = fs::remove_dir(&dir);
+            } else {
+                write_atomically(&dir.join(CHECKSUMS_FILE), checksums.as_bytes())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Make sure the name can't escape the library directory
+fn path_component(name: &str) -> Result<&str> {
+    let valid = !name.is_empty() && name != "." && name != ".." && !name.contains(['/', '\\']);
+    ensure!(valid, "Invalid file name for the library: '{}'", name);
+    Ok(name)
+}
+
+/// Write into a temporary file first, so that interrupted writes don't leave partial files
+fn write_atomically(path: &Path, content: &[u8]) -> Result<()> {
+    let mut tmp_name = path.file_name().unwrap_or_default().to_os_string();
+    tmp_name.push(".part");
+    let tmp_path = path.with_file_name(tmp_name);
+    fs::write(&tmp_path, content)?;
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn library(name: &str) -> Library {
+        let dir = std::env::temp_dir().join(format!("infinitime-library-{}-{}", std::process::id(), name));
+        _ = fs::remove_dir_all(&dir);
+        Library::new(dir)
+    }
+
+    fn asset(name: &str) -> Asset {
+        asset_from(name, "")
+    }
+
+    fn asset_from(name: &str, direct_url: &str) -> Asset {
+        Asset {
+            name: name.to_string(),
+            url: String::new(),
+            direct_url: direct_url.to_string(),
+            content_type: String::new(),
+            size: 0,
+            digest: None,
+        }
+    }
+
+    /// Add assets of the given releases, added a day apart in the given order
+    fn add_releases(library: &Library, tags: &[&str]) {
+        for (day, tag) in tags.iter().enumerate() {
+            library.add(tag, &asset("firmware.zip"), tag.as_bytes()).unwrap();
+            library.modify(|entries| {
+                for entry in entries.iter_mut().filter(|e| e.tag == *tag) {
+                    entry.added = Utc.with_ymd_and_hms(2024, 1, day as u32 + 1, 0, 0, 0).unwrap();
+                }
+            }).unwrap();
+        }
+    }
+
+    fn tags(library: &Library) -> Vec<String> {
+        library.read_entries().unwrap().into_iter().map(|e| e.tag).collect()
+    }
+
+    #[test]
+    fn prune_least_recently_added() {
+        let library = library("added");
+        // Older release downloaded last, e.g. to downgrade
+        add_releases(&library, &["1.14.0", "1.15.0", "1.13.0"]);
+        let removed = library.prune_entries(2, None).unwrap();
+        assert_eq!(removed.iter().map(|e| e.tag.as_str()).collect::<Vec<_>>(), ["1.14.0"]);
+        assert_eq!(tags(&library), ["1.15.0", "1.13.0"]);
+        assert!(!library.dir().join("1.14.0").exists());
+        _ = fs::remove_dir_all(library.dir());
+    }
+
+    #[test]
+    fn prune_keeps_recently_used() {
+        let library = library("used");
+        add_releases(&library, &["1.13.0", "1.14.0", "1.15.0"]);
+        assert!(library.get("1.13.0", &asset("firmware.zip")).unwrap().is_some());
+        library.prune_entries(2, None).unwrap();
+        assert_eq!(tags(&library), ["1.15.0", "1.13.0"]);
+        _ = fs::remove_dir_all(library.dir());
+    }
+
+    #[test]
+    fn prune_keeps_current() {
+        let library = library("current");
+        add_releases(&library, &["1.13.0", "1.14.0", "1.15.0"]);
+        library.prune_entries(1, Some("1.13.0")).unwrap();
+        assert_eq!(tags(&library), ["1.13.0"]);
+        _ = fs::remove_dir_all(library.dir());
+    }
+
+    #[test]
+    fn stored_content_is_verified() {
+        let library = library("verify");
+        add_releases(&library, &["1.15.0"]);
+        fs::write(library.dir().join("1.15.0").join("firmware.zip"), b"corrupted").unwrap();
+        assert!(library.get("1.15.0", &asset("firmware.zip")).unwrap().is_none());
+        assert!(tags(&library).is_empty());
+        _ = fs::remove_dir_all(library.dir());
+    }
+
+    #[test]
+    fn other_source_is_a_miss() {
+        let library = library("source");
+        let upstream = asset_from("firmware.zip", "https://example.com/upstream/firmware.zip");
+        let fork = asset_from("firmware.zip", "https://example.com/fork/firmware.zip");
+        library.add("1.15.0", &upstream, b"upstream").unwrap();
+        assert!(library.get("1.15.0", &fork).unwrap().is_none());
+        assert_eq!(library.get("1.15.0", &upstream).unwrap().as_deref(), Some(&b"upstream"[..]));
+
+        library.add("1.15.0", &fork, b"fork").unwrap();
+        assert_eq!(library.get("1.15.0", &fork).unwrap().as_deref(), Some(&b"fork"[..]));
+        assert!(library.get("1.15.0", &upstream).unwrap().is_none());
+        _ = fs::remove_dir_all(library.dir());
+    }
+}
@@ -0,0 +1,646 @@
+use super::{Asset, Cached, Client, ReleaseInfo, ReleasePage, GITHUB_API};
+use crate::{bt::{ProgressTx, ProgressTxWrapper}, policy};
+use anyhow::{anyhow, ensure, Result};
+use chrono::{DateTime, Utc};
+use futures::{future::BoxFuture, stream::{self, BoxStream}, StreamExt};
+use serde::Deserialize;
+use std::{collections::{HashMap, HashSet}, fmt, path::PathBuf, str::FromStr};
+
+/// Official InfiniTime repository on GitHub
+pub const INFINITIME_REPO: &str = "InfiniTimeOrg/InfiniTime";
+
+// Page sizes, the maximum allowed by the respective APIs
+const GITHUB_PER_PAGE: usize = 100;
+const GITEA_PER_PAGE: usize = 50;
+
+/// Provider of firmware releases and their assets
+pub trait ReleaseSource: Send + Sync {
+    /// Page of the available releases, newest first. Pages start from 1.
+    fn list_releases(&self, page: u32) -> BoxFuture<'_, Result<Cached<ReleasePage>>>;
+
+    /// Raw content of the asset, as listed by this source
+    fn fetch<'a>(&'a self, asset: &'a Asset, progress_sender: Option<ProgressTx>) -> BoxFuture<'a, Result<Vec<u8>>>;
+
+    /// Content of the asset, verified against its size, format and checksum
+    fn download<'a>(&'a self, asset: &'a Asset, progress_sender: Option<ProgressTx>) -> BoxFuture<'a, Result<Vec<u8>>> {
+        Box::pin(async move {
+            let content = self.fetch(asset, progress_sender).await?;
+            asset.verify(&content)?;
+            Ok(content)
+        })
+    }
+}
+
+/// All releases of the source, the pages are fetched as the stream is consumed
+pub fn all_releases(source: &dyn ReleaseSource) -> BoxStream<'_, Result<ReleaseInfo>> {
+    stream::unfold(Some(1), move |page| async move {
+        let page = page?;
+        match source.list_releases(page).await {
+            Ok(cached) => {
+                let next = cached.value.has_more.then_some(page + 1);
+                let releases = cached.value.releases.into_iter().map(Ok).collect::<Vec<_>>();
+                Some((stream::iter(releases), next))
+            }
+            // Stop after reporting the error
+            Err(error) => Some((stream::iter(vec![Err(error)]), None)),
+        }
+    })
+    .flatten()
+    .boxed()
+}
+
+
+/// Releases of any GitHub repository, e.g. a fork of InfiniTime
+pub struct GitHubReleases {
+    client: Client,
+    repo: String,
+}
+
+impl GitHubReleases {
+    /// `repo` is in "owner/name" form
+    pub fn new(client: Client, repo: impl Into<String>) -> Self {
+        Self { client, repo: repo.into() }
+    }
+}
+
+impl ReleaseSource for GitHubReleases {
+    fn list_releases(&self, page: u32) -> BoxFuture<'_, Result<Cached<ReleasePage>>> {
+        Box::pin(async move {
+            let url = format!("{}/repos/{}/releases?per_page={}&page={}", GITHUB_API, self.repo, GITHUB_PER_PAGE, page);
+            self.client.get_cached_json(&url).await?
+                .try_map(|json| Ok(ReleasePage::new(serde_json::from_str(&json)?, GITHUB_PER_PAGE)))
+        })
+    }
+
+    fn fetch<'a>(&'a self, asset: &'a Asset, progress_sender: Option<ProgressTx>) -> BoxFuture<'a, Result<Vec<u8>>> {
+        Box::pin(self.client.download(&asset.url, asset.size, progress_sender))
+    }
+}
+
+
+/// Releases of a repository on Gitea or Forgejo instance (e.g. Codeberg)
+pub struct GiteaReleases {
+    client: Client,
+    api_url: String,
+}
+
+#[derive(Deserialize)]
+struct GiteaRelease {
+    name: String,
+    tag_name: String,
+    html_url: String,
+    #[serde(default)]
+    assets: Vec<GiteaAsset>,
+    #[serde(default)]
+    prerelease: bool,
+    #[serde(default)]
+    draft: bool,
+    #[serde(default)]
+    published_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    body: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct GiteaAsset {
+    name: String,
+    size: u32,
+    browser_download_url: String,
+}
+
+impl GiteaReleases {
+    /// `repo_url` is the web page of the repository, e.g. "https://codeberg.org/owner/name"
+    pub fn new(client: Client, repo_url: &str) -> Result<Self> {
+        let repo_url = repo_url.trim().trim_end_matches('/').trim_end_matches(".git");
+        let mut parts = repo_url.rsplitn(3, '/');
+        let (name, owner, base) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(name), Some(owner), Some(base)) if base.contains("://") => (name, owner, base),
+            _ => return Err(anyhow!("Expected repository URL, e.g. https://codeberg.org/owner/name")),
+        };
+        ensure!(!name.is_empty() && !owner.is_empty(), "Invalid repository URL: {}", repo_url);
+        let api_url = format!("{}/api/v1/repos/{}/{}/releases", base, owner, name);
+        Ok(Self { client, api_url })
+    }
+}
+
+impl ReleaseSource for GiteaReleases {
+    fn list_releases(&self, page: u32) -> BoxFuture<'_, Result<Cached<ReleasePage>>> {
+        Box::pin(async move {
+            let url = format!("{}?limit={}&page={}", self.api_url, GITEA_PER_PAGE, page);
+            self.client.get_cached_json(&url).await?.try_map(|json| {
+                let releases: Vec<GiteaRelease> = serde_json::from_str(&json)?;
+                let releases = releases.into_iter().map(|r| ReleaseInfo {
+                    name: r.name,
+                    tag: r.tag_name,
+                    url: r.html_url,
+                    assets: r.assets.into_iter().map(|a| Asset {
+                        name: a.name,
+                        url: a.browser_download_url.clone(),
+                        direct_url: a.browser_download_url,
+                        content_type: String::from("application/octet-stream"),
+                        size: a.size,
+                        digest: None,
+                    }).collect(),
+                    prerelease: r.prerelease,
+                    draft: r.draft,
+                    published_at: r.published_at,
+                    body: r.body,
+                }).collect();
+                Ok(ReleasePage::new(releases, GITEA_PER_PAGE))
+            })
+        })
+    }
+
+    fn fetch<'a>(&'a self, asset: &'a Asset, progress_sender: Option<ProgressTx>) -> BoxFuture<'a, Result<Vec<u8>>> {
+        Box::pin(self.client.download(&asset.url, asset.size, progress_sender))
+    }
+}
+
+
+/// Build artifacts of GitHub Actions workflow runs (e.g. pull requests),
+/// one release per run. Downloading artifacts requires a token.
+pub struct GitHubArtifacts {
+    client: Client,
+    repo: String,
+}
+
+#[derive(Deserialize)]
+struct ArtifactList {
+    artifacts: Vec<Artifact>,
+}
+
+#[derive(Deserialize)]
+struct Artifact {
+    name: String,
+    size_in_bytes: u32,
+    archive_download_url: String,
+    expired: bool,
+    #[serde(default)]
+    digest: Option<String>,
+    created_at: Option<DateTime<Utc>>,
+    workflow_run: Option<WorkflowRun>,
+}
+
+#[derive(Deserialize)]
+struct WorkflowRun {
+    id: u64,
+    head_branch: Option<String>,
+    head_sha: Option<String>,
+}
+
+impl GitHubArtifacts {
+    /// `repo` is in "owner/name" form
+    pub fn new(client: Client, repo: impl Into<String>) -> Self {
+        Self { client, repo: repo.into() }
+    }
+
+    /// Artifacts are always zipped by GitHub. InfiniTime CI uploads the unpacked
+    /// content of the DFU and resources packages (e.g. "InfiniTime DFU pr-123"),
+    /// so the artifact archives are themselves valid packages. Name them like the
+    /// release assets, so that they are recognized by `AssetKind`.
+    fn asset_name(artifact: &str) -> String {
+        let lowercase = artifact.to_lowercase();
+        let suffix = |prefix: &str| artifact.get(prefix.len()..).unwrap_or_default().trim().replace(' ', "-");
+        if lowercase.starts_with("infinitime dfu") {
+            format!("pinetime-mcuboot-app-dfu-{}.zip", suffix("infinitime dfu"))
+        } else if lowercase.starts_with("infinitime resources") {
+            format!("infinitime-resources-{}.zip", suffix("infinitime resources"))
+        } else {
+            format!("{}.zip", artifact)
+        }
+    }
+
+    async fn artifacts(&self, page: u32) -> Result<Cached<Vec<Artifact>>> {
+        let url = format!(
+            "{}/repos/{}/actions/artifacts?per_page={}&page={}",
+            GITHUB_API, self.repo, GITHUB_PER_PAGE, page
+        );
+        self.client.get_cached_json(&url).await?
+            .try_map(|json| Ok(serde_json::from_str::<ArtifactList>(&json)?.artifacts))
+    }
+
+    /// One release per workflow run, in the order the runs are first listed
+    /// (artifacts are listed newest first)
+    fn releases(repo: &str, artifacts: Vec<Artifact>) -> Vec<ReleaseInfo> {
+        let mut runs: Vec<(u64, ReleaseInfo)> = Vec::new();
+        for artifact in artifacts.into_iter().filter(|a| !a.expired) {
+            let Some(run) = artifact.workflow_run else { continue };
+            let asset = Asset {
+                name: Self::asset_name(&artifact.name),
+                url: artifact.archive_download_url.clone(),
+                direct_url: artifact.archive_download_url,
+                content_type: String::from("application/zip"),
+                size: artifact.size_in_bytes,
+                digest: artifact.digest,
+            };
+            match runs.iter_mut().find(|(id, _)| *id == run.id) {
+                Some((_, release)) => release.assets.push(asset),
+                None => {
+                    let branch = run.head_branch.unwrap_or_else(|| run.id.to_string());
+                    let tag = match run.head_sha {
+                        Some(sha) => format!("{}@{}", branch, &sha[..sha.len().min(7)]),
+                        None => branch,
+                    };
+                    runs.push((run.id, ReleaseInfo {
+                        name: tag.clone(),
+                        tag,
+                        url: format!("https://github.com/{}/actions/runs/{}", repo, run.id),
+                        assets: vec![asset],
+                        // Development builds
+                        prerelease: true,
+                        draft: false,
+                        published_at: artifact.created_at,
+                        body: None,
+                    }));
+                }
+            }
+        }
+        runs.into_iter().map(|(_, release)| release).collect()
+    }
+}
+
+/// Artifacts of the page, with the workflow runs split at page boundaries
+/// kept whole. A run belongs to the page where it's listed first, so its
+/// artifacts on the next page are moved to it.
+fn page_artifacts(previous: &[Artifact], current: Vec<Artifact>, next: Vec<Artifact>) -> Vec<Artifact> {
+    let run_id = |a: &Artifact| a.workflow_run.as_ref().map(|run| run.id);
+    let previous_runs = previous.iter().filter_map(run_id).collect::<HashSet<_>>();
+    let mut artifacts = current.into_iter()
+        .filter(|a| run_id(a).is_some_and(|id| !previous_runs.contains(&id)))
+        .collect::<Vec<_>>();
+    let runs = artifacts.iter().filter_map(run_id).collect::<HashSet<_>>();
+    artifacts.extend(next.into_iter().filter(|a| run_id(a).is_some_and(|id| runs.contains(&id))));
+    artifacts
+}
+
+impl ReleaseSource for GitHubArtifacts {
+    fn list_releases(&self, page: u32) -> BoxFuture<'_, Result<Cached<ReleasePage>>> {
+        Box::pin(async move {
+            let current = self.artifacts(page).await?;
+            let has_more = current.value.len() >= GITHUB_PER_PAGE;
+            // Neighbouring pages keep the runs at the page boundaries whole. They are
+            // fetched in turn anyway, so mostly just revalidated with ETag
+            let previous = if page > 1 { self.artifacts(page - 1).await?.value } else { Vec::new() };
+            let next = if has_more { self.artifacts(page + 1).await?.value } else { Vec::new() };
+            let artifacts = page_artifacts(&previous, current.value, next);
+            let releases = Self::releases(&self.repo, artifacts);
+            Ok(Cached { value: ReleasePage { releases, has_more }, error: current.error })
+        })
+    }
+
+    fn fetch<'a>(&'a self, asset: &'a Asset, progress_sender: Option<ProgressTx>) -> BoxFuture<'a, Result<Vec<u8>>> {
+        Box::pin(async move {
+            ensure!(self.client.has_token(), "GitHub token is required to download workflow artifacts");
+            self.client.download(&asset.url, asset.size, progress_sender).await
+        })
+    }
+}
+
+
+/// Local directory, where every subdirectory is a release named after
+/// its version, containing the asset files (e.g. as downloaded by hand).
+/// Checksums are taken from `SHA256SUMS` or `<asset>.sha256` files, if present.
+pub struct LocalReleases {
+    dir: PathBuf,
+}
+
+impl LocalReleases {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    async fn read_release(dir: PathBuf) -> Result<ReleaseInfo> {
+        let tag = dir.file_name().unwrap_or_default().to_string_lossy().to_string();
+        let mut assets = Vec::new();
+        let mut checksums = HashMap::new();
+        let mut entries = tokio::fs::read_dir(&dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let metadata = entry.metadata().await?;
+            if !metadata.is_file() {
+                continue;
+            }
+            let name = entry.file_name().to_string_lossy().to_string();
+            let path = entry.path();
+            if name.eq_ignore_ascii_case("SHA256SUMS") || name.ends_with(".sha256") {
+                let text = tokio::fs::read_to_string(&path).await?;
+                let asset_name = name.strip_suffix(".sha256").unwrap_or_default();
+                for (file, hash) in parse_sha256sums(&text) {
+                    let file = if file.is_empty() { asset_name.to_string() } else { file };
+                    checksums.insert(file, hash);
+                }
+            } else {
+                assets.push(Asset {
+                    name,
+                    url: path.to_string_lossy().to_string(),
+                    direct_url: format!("file://{}", path.display()),
+                    content_type: String::from("application/octet-stream"),
+                    size: metadata.len() as u32,
+                    digest: None,
+                });
+            }
+        }
+        for asset in &mut assets {
+            asset.digest = checksums.get(&asset.name).map(|hash| format!("sha256:{}", hash));
+        }
+        assets.sort_by(|a, b| a.name.cmp(&b.name));
+        let published_at = tokio::fs::metadata(&dir).await?.modified().ok().map(DateTime::<Utc>::from);
+        Ok(ReleaseInfo {
+            name: tag.clone(),
+            tag,
+            url: format!("file://{}", dir.display()),
+            assets,
+            prerelease: false,
+            draft: false,
+            published_at,
+            body: None,
+        })
+    }
+}
+
+impl ReleaseSource for LocalReleases {
+    fn list_releases(&self, page: u32) -> BoxFuture<'_, Result<Cached<ReleasePage>>> {
+        Box::pin(async move {
+            // Everything is listed on the first page
+            if page > 1 {
+                return Ok(Cached { value: ReleasePage::default(), error: None });
+            }
+            let mut releases = Vec::new();
+            let mut entries = tokio::fs::read_dir(&self.dir).await?;
+            while let Some(entry) = entries.next_entry().await? {
+                if entry.metadata().await?.is_dir() {
+                    let release = Self::read_release(entry.path()).await?;
+                    if !release.assets.is_empty() {
+                        releases.push(release);
+                    }
+                }
+            }
+            releases.sort_by(|a, b| policy::compare(&b.tag, &a.tag).unwrap_or_else(|| b.tag.cmp(&a.tag)));
+            Ok(Cached { value: ReleasePage { releases, has_more: false }, error: None })
+        })
+    }
+
+    fn fetch<'a>(&'a self, asset: &'a Asset, progress_sender: Option<ProgressTx>) -> BoxFuture<'a, Result<Vec<u8>>> {
+        Box::pin(async move {
+            let content = tokio::fs::read(&asset.url).await?;
+            let size = content.len() as u32;
+            ProgressTxWrapper(progress_sender).report_num(size, size).await;
+            Ok(content)
+        })
+    }
+}
+
+/// Parse checksums file in `sha256sum` format ("<hex>  <filename>" lines)
+/// into filename to checksum map. Lines without filename are stored under
+/// the empty name, as in per-file checksum files.
+fn parse_sha256sums(text: &str) -> HashMap<String, String> {
+    text.lines()
+        .filter_map(|line| {
+            let mut parts = line.trim().splitn(2, char::is_whitespace);
+            let hash = parts.next().filter(|h| h.len() == 64 && h.chars().all(|c| c.is_ascii_hexdigit()))?;
+            let name = parts.next().unwrap_or_default().trim().trim_start_matches('*');
+            Some((name.to_string(), hash.to_lowercase()))
+        })
+        .collect()
+}
+
+
+/// Kind of the release source, see `SourceConfig`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SourceKind {
+    GitHub,
+    Gitea,
+    GitHubArtifacts,
+    LocalDir,
+}
+
+impl SourceKind {
+    pub const ALL: [Self; 4] = [Self::GitHub, Self::Gitea, Self::GitHubArtifacts, Self::LocalDir];
+
+    fn prefix(&self) -> &'static str {
+        match self {
+            Self::GitHub => "github",
+            Self::Gitea => "gitea",
+            Self::GitHubArtifacts => "github-artifacts",
+            Self::LocalDir => "local",
+        }
+    }
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            Self::GitHub => "GitHub releases",
+            Self::Gitea => "Gitea/Forgejo releases",
+            Self::GitHubArtifacts => "GitHub Actions artifacts",
+            Self::LocalDir => "Local folder",
+        }
+    }
+
+    /// Example of the location format
+    pub fn placeholder(&self) -> &'static str {
+        match self {
+            Self::GitHub | Self::GitHubArtifacts => INFINITIME_REPO,
+            Self::Gitea => "https://codeberg.org/owner/name",
+            Self::LocalDir => "/path/to/releases",
+        }
+    }
+}
+
+/// Serializable description of the release source, e.g. "github:InfiniTimeOrg/InfiniTime",
+/// "gitea:https://codeberg.org/owner/name" or "local:/path/to/releases"
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceConfig {
+    pub kind: SourceKind,
+    /// Repository ("owner/name"), repository URL or directory path, depending on the kind
+    pub location: String,
+}
+
+impl Default for SourceConfig {
+    fn default() -> Self {
+        Self { kind: SourceKind::GitHub, location: INFINITIME_REPO.to_string() }
+    }
+}
+
+impl fmt::Display for SourceConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.kind.prefix(), self.location)
+    }
+}
+
+impl FromStr for SourceConfig {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (prefix, location) = s.split_once(':').ok_or(anyhow!("Invalid release source: {}", s))?;
+        let kind = SourceKind::ALL.into_iter()
+            .find(|k| k.prefix() == prefix)
+            .ok_or(anyhow!("Unknown release source kind: {}", prefix))?;
+        Ok(Self { kind, location: location.trim().to_string() })
+    }
+}
+
+impl SourceConfig {
+    pub fn build(&self, client: Client) -> Result<Box<dyn ReleaseSource>> {
+        let check_repo = |repo: &str| -> Result<()> {
+            let valid = repo.split_once('/')
+                .map_or(false, |(owner, name)| !owner.is_empty() && !name.is_empty() && !name.contains('/'));
+            ensure!(valid, "Expected repository in \"owner/name\" form, got \"{}\"", repo);
+            Ok(())
+        };
+        Ok(match self.kind {
+            SourceKind::GitHub => {
+                check_repo(&self.location)?;
+                Box::new(GitHubReleases::new(client, &self.location))
+            }
+            SourceKind::Gitea => Box::new(GiteaReleases::new(client, &self.location)?),
+            SourceKind::GitHubArtifacts => {
+                check_repo(&self.location)?;
+                Box::new(GitHubArtifacts::new(client, &self.location))
+            }
+            SourceKind::LocalDir => {
+                ensure!(!self.location.is_empty(), "Releases folder is not specified");
+                Box::new(LocalReleases::new(&self.location))
+            }
+        })
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HASH_A: &str = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+    const HASH_B: &str = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+
+    #[test]
+    fn sha256sums_multiple_files() {
+        let text = format!("{HASH_A}  pinetime-mcuboot-app-dfu-1.14.0.zip\n{HASH_B}  resources-1.14.0.zip\n");
+        let sums = parse_sha256sums(&text);
+        assert_eq!(sums.len(), 2);
+        assert_eq!(sums["pinetime-mcuboot-app-dfu-1.14.0.zip"], HASH_A);
+        assert_eq!(sums["resources-1.14.0.zip"], HASH_B);
+    }
+
+    #[test]
+    fn sha256sums_binary_mode_marker() {
+        let sums = parse_sha256sums(&format!("{HASH_A} *firmware.bin"));
+        assert_eq!(sums["firmware.bin"], HASH_A);
+    }
+
+    #[test]
+    fn sha256sums_hash_only() {
+        let sums = parse_sha256sums(&format!("{HASH_A}\n"));
+        assert_eq!(sums[""], HASH_A);
+    }
+
+    #[test]
+    fn sha256sums_lowercases_hash() {
+        let sums = parse_sha256sums(&format!("{}  firmware.zip", HASH_A.to_uppercase()));
+        assert_eq!(sums["firmware.zip"], HASH_A);
+    }
+
+    #[test]
+    fn sha256sums_skips_invalid_lines() {
+        let text = format!("\n# checksums\nnot-a-hash  firmware.zip\n{}  short.zip\n{HASH_B}  good.zip\r\n", &HASH_A[..63]);
+        let sums = parse_sha256sums(&text);
+        assert_eq!(sums.len(), 1);
+        assert_eq!(sums["good.zip"], HASH_B);
+    }
+
+    #[test]
+    fn source_config_round_trip() {
+        for kind in SourceKind::ALL {
+            let config = SourceConfig { kind, location: kind.placeholder().to_string() };
+            assert_eq!(config.to_string().parse::<SourceConfig>().unwrap(), config);
+        }
+        assert_eq!(SourceConfig::default().to_string(), "github:InfiniTimeOrg/InfiniTime");
+    }
+
+    #[test]
+    fn source_config_parsing() {
+        let config: SourceConfig = "local: /home/user/releases:old ".parse().unwrap();
+        assert_eq!(config.kind, SourceKind::LocalDir);
+        assert_eq!(config.location, "/home/user/releases:old");
+        let config: SourceConfig = "gitea:https://codeberg.org/owner/name".parse().unwrap();
+        assert_eq!(config.kind, SourceKind::Gitea);
+        assert_eq!(config.location, "https://codeberg.org/owner/name");
+    }
+
+    #[test]
+    fn source_config_malformed() {
+        for text in ["", "github", "InfiniTimeOrg/InfiniTime", "svn:InfiniTimeOrg/InfiniTime", "GitHub:owner/name"] {
+            assert!(text.parse::<SourceConfig>().is_err(), "{}", text);
+        }
+    }
+
+    #[test]
+    fn source_config_invalid_repo() {
+        for location in ["", "owner", "owner/", "/name", "owner/name/extra"] {
+            let config = SourceConfig { kind: SourceKind::GitHub, location: location.to_string() };
+            assert!(config.build(Client::new()).is_err(), "{}", location);
+        }
+    }
+
+    #[test]
+    fn gitea_repo_url() {
+        let api_url = |url: &str| GiteaReleases::new(Client::new(), url).map(|source| source.api_url);
+        let expected = "https://codeberg.org/api/v1/repos/owner/name/releases";
+        assert_eq!(api_url("https://codeberg.org/owner/name").unwrap(), expected);
+        assert_eq!(api_url("https://codeberg.org/owner/name/").unwrap(), expected);
+        assert_eq!(api_url(" https://codeberg.org/owner/name.git ").unwrap(), expected);
+        // Instance served from a subpath
+        assert_eq!(
+            api_url("https://example.com/git/owner/name").unwrap(),
+            "https://example.com/git/api/v1/repos/owner/name/releases"
+        );
+    }
+
+    #[test]
+    fn gitea_repo_url_malformed() {
+        for url in ["", "owner/name", "https://codeberg.org/owner", "https://codeberg.org/owner/", "https://codeberg.org//name", "codeberg.org/owner/name"] {
+            assert!(GiteaReleases::new(Client::new(), url).is_err(), "{}", url);
+        }
+    }
+
+    #[test]
+    fn artifact_asset_names() {
+        assert_eq!(GitHubArtifacts::asset_name("InfiniTime DFU pr-123"), "pinetime-mcuboot-app-dfu-pr-123.zip");
+        assert_eq!(GitHubArtifacts::asset_name("InfiniTime resources pr-123"), "infinitime-resources-pr-123.zip");
+        assert_eq!(GitHubArtifacts::asset_name("infinitime dfu main build"), "pinetime-mcuboot-app-dfu-main-build.zip");
+        assert_eq!(GitHubArtifacts::asset_name("InfiniTime MCUBoot"), "InfiniTime MCUBoot.zip");
+    }
+
+    fn artifact(name: &str, run: u64) -> Artifact {
+        Artifact {
+            name: name.to_string(),
+            size_in_bytes: 0,
+            archive_download_url: format!("https://api.github.com/artifacts/{}/{}", run, name),
+            expired: false,
+            digest: None,
+            created_at: None,
+            workflow_run: Some(WorkflowRun { id: run, head_branch: Some(format!("pr-{}", run)), head_sha: None }),
+        }
+    }
+
+    fn names(artifacts: &[Artifact]) -> Vec<&str> {
+        artifacts.iter().map(|a| a.name.as_str()).collect()
+    }
+
+    #[test]
+    fn artifacts_grouped_by_run() {
+        let artifacts = vec![artifact("InfiniTime DFU 2", 2), artifact("InfiniTime DFU 1", 1), artifact("InfiniTime resources 2", 2)];
+        let releases = GitHubArtifacts::releases(INFINITIME_REPO, artifacts);
+        assert_eq!(releases.iter().map(|r| r.tag.as_str()).collect::<Vec<_>>(), ["pr-2", "pr-1"]);
+        assert_eq!(releases[0].assets.len(), 2);
+        assert!(releases.iter().all(|r| r.prerelease));
+    }
+
+    #[test]
+    fn artifacts_run_split_between_pages() {
+        let page_1 = || vec![artifact("a3", 3), artifact("a2", 2)];
+        let page_2 = || vec![artifact("b2", 2), artifact("a1", 1)];
+        let page_3 = || vec![artifact("b1", 1)];
+        assert_eq!(names(&page_artifacts(&[], page_1(), page_2())), ["a3", "a2", "b2"]);
+        assert_eq!(names(&page_artifacts(&page_1(), page_2(), page_3())), ["a1", "b1"]);
+        assert!(page_artifacts(&page_2(), page_3(), Vec::new()).is_empty());
+    }
+}
@@ -0,0 +1,269 @@
+use anyhow::{ensure, Result};
+use chrono::{DateTime, Local, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+use std::{path::PathBuf, sync::atomic::{AtomicI64, AtomicU64, Ordering}};
+use tokio::{fs, sync::Mutex};
+
+/// Maximum number of entries kept in the journal, older ones are dropped
+pub const MAX_JOURNAL_ENTRIES: usize = 500;
+
+// Writes are read-modify-write of the whole file, so they must not interleave
+static WRITE_LOCK: Mutex<()> = Mutex::const_new(());
+static LAST_ID: AtomicI64 = AtomicI64::new(0);
+static TMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum JournalStatus {
+    InProgress,
+    Succeeded,
+    Failed(String),
+    Aborted,
+    /// Was in progress when the app was terminated (crash, suspend, etc.)
+    Interrupted,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct JournalEntry {
+    /// Unique ID, which is also the start time in milliseconds since Unix epoch.
+    /// Entries started within the same millisecond get the following ones.
+    pub id: i64,
+    pub device_address: String,
+    /// Flashed asset type, e.g. "Firmware" or "Resources"
+    pub asset: String,
+    /// File path or URL the asset was taken from
+    pub source: String,
+    pub firmware_before: Option<String>,
+    pub firmware_after: Option<String>,
+    pub bytes: u64,
+    pub duration_secs: Option<f32>,
+    pub status: JournalStatus,
+}
+
+impl JournalEntry {
+    pub fn new(device_address: String, asset: String, source: String, bytes: u64) -> Self {
+        Self {
+            id: Self::next_id(),
+            device_address,
+            asset,
+            source,
+            firmware_before: None,
+            firmware_after: None,
+            bytes,
+            duration_secs: None,
+            status: JournalStatus::InProgress,
+        }
+    }
+
+    pub fn start_time(&self) -> DateTime<Local> {
+        Local.timestamp_millis_opt(self.id).single().unwrap_or_default()
+    }
+
+    fn next_id() -> i64 {
+        let now = Utc::now().timestamp_millis();
+        let previous = LAST_ID.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |last| Some(now.max(last + 1)));
+        now.max(previous.unwrap_or_default() + 1)
+    }
+}
+
+
+/// Persistent record of firmware and resources operations. The journal is
+/// stored as a JSON file, which is re-read on every access, so that multiple
+/// instances of this type can refer to the same file. Writes are serialized
+/// within the process, but not across processes.
+#[derive(Debug, Clone)]
+pub struct Journal {
+    path: PathBuf,
+}
+
+impl Journal {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    /// All entries, oldest first
+    pub async fn entries(&self) -> Result<Vec<JournalEntry>> {
+        match fs::read_to_string(&self.path).await {
+            Ok(json) => Ok(serde_json::from_str(&json)?),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Record the start of an operation
+    pub async fn begin(&self, entry: &JournalEntry) -> Result<()> {
+        self.modify(|entries| {
+            ensure!(entries.iter().all(|e| e.id != entry.id), "Journal entry {} already exists", entry.id);
+            entries.push(entry.clone());
+            Ok(())
+        }).await?
+    }
+
+    /// Record the operation result
+    pub async fn finish(&self, id: i64, status: JournalStatus, duration_secs: Option<f32>) -> Result<()> {
+        self.modify(|entries| {
+            if let Some(entry) = entries.iter_mut().find(|e| e.id == id) {
+                entry.status = status;
+                entry.duration_secs = duration_secs.or(entry.duration_secs);
+            }
+        }).await
+    }
+
+    /// Record firmware version reported by the watch after the operation
+    pub async fn set_firmware_after(&self, id: i64, version: String) -> Result<()> {
+        self.modify(|entries| {
+            if let Some(entry) = entries.iter_mut().find(|e| e.id == id) {
+                entry.firmware_after = Some(version);
+            }
+        }).await
+    }
+
+    /// Mark operations that are still in progress as interrupted, and return them.
+    /// Supposed to be called once on startup, before any new operation begins.
+    pub async fn recover_interrupted(&self) -> Result<Vec<JournalEntry>> {
+        self.modify(|entries| {
+            let mut interrupted = Vec::new();
+            for entry in entries.iter_mut().filter(|e| e.status == JournalStatus::InProgress) {
+                entry.status = JournalStatus::Interrupted;
+                interrupted.push(entry.clone());
+            }
+            interrupted
+        }).await
+    }
+
+    async fn modify<T>(&self, f: impl FnOnce(&mut Vec<JournalEntry>) -> T) -> Result<T> {
+        let _guard = WRITE_LOCK.lock().await;
+        let mut entries = self.entries().await?;
+        let result = f(&mut entries);
+        if entries.len() > MAX_JOURNAL_ENTRIES {
+            entries.drain(..entries.len() - MAX_JOURNAL_ENTRIES);
+        }
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir).await?;
+        }
+        // Write to a temporary file first, so that the journal isn't
+        // corrupted if the app is terminated in the middle of writing
+        let mut tmp_name = self.path.file_name().unwrap_or_default().to_os_string();
+        tmp_name.push(format!(".{}-{}.tmp", std::process::id(), TMP_COUNTER.fetch_add(1, Ordering::Relaxed)));
+        let tmp_path = self.path.with_file_name(tmp_name);
+        fs::write(&tmp_path, serde_json::to_string_pretty(&entries)?).await?;
+        if let Err(error) = fs::rename(&tmp_path, &self.path).await {
+            _ = fs::remove_file(&tmp_path).await;
+            return Err(error.into());
+        }
+        Ok(result)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn journal(name: &str) -> Journal {
+        let path = std::env::temp_dir()
+            .join(format!("infinitime-journal-{}-{}", std::process::id(), name))
+            .join("journal.json");
+        _ = std::fs::remove_dir_all(path.parent().unwrap());
+        Journal::new(path)
+    }
+
+    fn entry(asset: &str) -> JournalEntry {
+        JournalEntry::new("AA:BB:CC:DD:EE:FF".to_string(), asset.to_string(), String::new(), 100)
+    }
+
+    fn block_on<F: std::future::Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new_multi_thread().enable_all().build().unwrap().block_on(future)
+    }
+
+    #[test]
+    fn unique_ids() {
+        let ids = (0..100).map(|_| entry("Firmware").id).collect::<std::collections::BTreeSet<_>>();
+        assert_eq!(ids.len(), 100);
+    }
+
+    #[test]
+    fn begin_and_finish() {
+        let journal = journal("finish");
+        block_on(async {
+            let first = entry("Firmware");
+            let second = entry("Resources");
+            journal.begin(&first).await.unwrap();
+            journal.begin(&second).await.unwrap();
+            assert!(journal.begin(&first).await.is_err());
+            journal.finish(first.id, JournalStatus::Succeeded, Some(1.5)).await.unwrap();
+            journal.set_firmware_after(first.id, "1.15.0".to_string()).await.unwrap();
+
+            let entries = journal.entries().await.unwrap();
+            assert_eq!(entries.len(), 2);
+            assert_eq!(entries[0].status, JournalStatus::Succeeded);
+            assert_eq!(entries[0].duration_secs, Some(1.5));
+            assert_eq!(entries[0].firmware_after.as_deref(), Some("1.15.0"));
+            assert_eq!(entries[1].status, JournalStatus::InProgress);
+        });
+        _ = std::fs::remove_dir_all(journal.path.parent().unwrap());
+    }
+
+    #[test]
+    fn recover_interrupted() {
+        let journal = journal("recover");
+        block_on(async {
+            let finished = entry("Firmware");
+            let interrupted = entry("Resources");
+            journal.begin(&finished).await.unwrap();
+            journal.finish(finished.id, JournalStatus::Aborted, None).await.unwrap();
+            journal.begin(&interrupted).await.unwrap();
+
+            let recovered = journal.recover_interrupted().await.unwrap();
+            assert_eq!(recovered.len(), 1);
+            assert_eq!(recovered[0].id, interrupted.id);
+            assert_eq!(recovered[0].status, JournalStatus::Interrupted);
+            assert!(journal.recover_interrupted().await.unwrap().is_empty());
+            let statuses = journal.entries().await.unwrap().into_iter().map(|e| e.status).collect::<Vec<_>>();
+            assert_eq!(statuses, [JournalStatus::Aborted, JournalStatus::Interrupted]);
+        });
+        _ = std::fs::remove_dir_all(journal.path.parent().unwrap());
+    }
+
+    #[test]
+    fn concurrent_writes_are_kept() {
+        let journal = journal("concurrent");
+        block_on(async {
+            let tasks = (0..20)
+                .map(|_| {
+                    let journal = journal.clone();
+                    tokio::spawn(async move {
+                        let entry = entry("Firmware");
+                        journal.begin(&entry).await.unwrap();
+                        journal.finish(entry.id, JournalStatus::Succeeded, None).await.unwrap();
+                    })
+                })
+                .collect::<Vec<_>>();
+            for task in tasks {
+                task.await.unwrap();
+            }
+            let entries = journal.entries().await.unwrap();
+            assert_eq!(entries.len(), 20);
+            assert!(entries.iter().all(|e| e.status == JournalStatus::Succeeded));
+        });
+        let leftovers = std::fs::read_dir(journal.path.parent().unwrap()).unwrap().count();
+        assert_eq!(leftovers, 1);
+        _ = std::fs::remove_dir_all(journal.path.parent().unwrap());
+    }
+
+    #[test]
+    fn trimmed_to_max_entries() {
+        let journal = journal("trim");
+        block_on(async {
+            let entries = (0..MAX_JOURNAL_ENTRIES + 5).map(|_| entry("Firmware")).collect::<Vec<_>>();
+            for entry in &entries {
+                journal.begin(entry).await.unwrap();
+            }
+            let stored = journal.entries().await.unwrap();
+            assert_eq!(stored.len(), MAX_JOURNAL_ENTRIES);
+            assert_eq!(stored[0].id, entries[5].id);
+            assert_eq!(stored.last().unwrap().id, entries.last().unwrap().id);
+        });
+        _ = std::fs::remove_dir_all(journal.path.parent().unwrap());
+    }
+}
@@ -11,6 +11,12 @@ pub mod github;
 #[cfg(feature = "github")]
 pub use github as gh;
 
+#[cfg(feature = "lvgl")]
+pub mod lvgl;
+
+pub mod journal;
+pub mod policy;
+
 mod utils;
 
 
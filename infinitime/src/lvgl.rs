@@ -0,0 +1,21 @@
+//! Converters into the binary formats that LVGL (the graphics library used
+//! by InfiniTime) loads from the filesystem. Converted files can be packaged
+//! into resources archive with `bt::ResourcePackage`, e.g. fonts are expected
+//! in `/fonts` and images in `/images`.
+
+pub mod font;
+pub mod image;
+
+/// InfiniTime is built with `LV_COLOR_16_SWAP`, so RGB565 colors are stored
+/// with swapped bytes (big-endian)
+pub const COLOR_16_SWAP: bool = true;
+
+/// Convert color to RGB565 in the byte order expected by InfiniTime
+pub fn rgb565_bytes(r: u8, g: u8, b: u8) -> [u8; 2] {
+    let color = ((r as u16 >> 3) << 11) | ((g as u16 >> 2) << 5) | (b as u16 >> 3);
+    if COLOR_16_SWAP {
+        color.to_be_bytes()
+    } else {
+        color.to_le_bytes()
+    }
+}
@@ -0,0 +1,295 @@
+use ab_glyph::{Font, FontRef, PxScale, ScaleFont};
+use anyhow::{anyhow, ensure, Result};
+use std::ops::RangeInclusive;
+
+// Reference: lv_font_loader.c (LVGL v8) and the binary format of lv_font_conv
+
+// Bit widths of the glyph descriptor fields. All of them together
+// take whole bytes, so that glyph bitmaps start at a byte boundary.
+const ADVANCE_WIDTH_BITS: u8 = 16;
+const XY_BITS: u8 = 8;
+const WH_BITS: u8 = 8;
+
+/// `LV_FONT_FMT_TXT_CMAP_FORMAT0_TINY`: contiguous range of code points
+/// mapped to contiguous glyph IDs, without any additional data
+const CMAP_FORMAT0_TINY: u8 = 2;
+
+/// Font conversion parameters
+#[derive(Debug, Clone)]
+pub struct FontOptions {
+    /// Font size (em height) in pixels
+    pub size: u16,
+    /// Bits per pixel of glyph bitmaps: 1, 2, 4 or 8
+    pub bpp: u8,
+    /// Characters to include, characters missing in the font are skipped
+    pub ranges: Vec<RangeInclusive<char>>,
+}
+
+/// Parse character ranges in lv_font_conv notation,
+/// e.g. "0x20-0x7F,0x410-0x44F,0xB0"
+pub fn parse_ranges(ranges: &str) -> Result<Vec<RangeInclusive<char>>> {
+    let parse_char = |s: &str| -> Result<char> {
+        let s = s.trim();
+        let code = match s.strip_prefix("0x").or(s.strip_prefix("0X")) {
+            Some(hex) => u32::from_str_radix(hex, 16)?,
+            None => s.parse()?,
+        };
+        char::from_u32(code).ok_or(anyhow!("Invalid code point: {}", s))
+    };
+    ranges.split(',')
+        .filter(|r| !r.trim().is_empty())
+        .map(|r| match r.split_once('-') {
+            Some((start, end)) => Ok(parse_char(start)?..=parse_char(end)?),
+            None => parse_char(r).map(|c| c..=c),
+        })
+        .collect()
+}
+
+struct Glyph {
+    advance: u16,
+    ofs_x: i8,
+    ofs_y: i8,
+    width: u8,
+    height: u8,
+    bitmap: Vec<u8>,
+}
+
+/// Rasterize TrueType/OpenType font into LVGL binary font file,
+/// which can be loaded by InfiniTime from `/fonts`
+pub fn convert_ttf(ttf: &[u8], options: &FontOptions) -> Result<Vec<u8>> {
+    ensure!(matches!(options.bpp, 1 | 2 | 4 | 8), "Unsupported bits per pixel: {}", options.bpp);
+    ensure!(options.size > 0, "Font size must be positive");
+
+    let font = FontRef::try_from_slice(ttf).map_err(|_| anyhow!("Invalid font file"))?;
+    let units_per_em = font.units_per_em().ok_or(anyhow!("Font has no units per em"))?;
+    let scale = PxScale::from(options.size as f32 * font.height_unscaled() / units_per_em);
+    let scaled = font.as_scaled(scale);
+
+    // Code points sorted and deduplicated, glyph IDs are assigned in this order
+    let mut chars = options.ranges.iter()
+        .flat_map(|r| r.clone())
+        .filter(|&c| font.glyph_id(c).0 != 0)
+        .collect::<Vec<_>>();
+    chars.sort();
+    chars.dedup();
+    ensure!(!chars.is_empty(), "None of the requested characters are present in the font");
+
+    let mut glyphs = Vec::with_capacity(chars.len());
+    for &c in &chars {
+        glyphs.push(rasterize(&scaled, c, options.bpp)?);
+    }
+
+    let ascent = scaled.ascent().ceil() as i16;
+    let descent = scaled.descent().floor() as i16;
+    let min_y = glyphs.iter().map(|g| g.ofs_y as i16).min().unwrap_or(0);
+    let max_y = glyphs.iter().map(|g| g.ofs_y as i16 + g.height as i16).max().unwrap_or(0);
+
+    let mut output = head_table(options, ascent, descent, min_y, max_y);
+    output.extend(cmap_table(&chars));
+    let (loca, glyf) = glyph_tables(&glyphs);
+    output.extend(loca);
+    output.extend(glyf);
+    Ok(output)
+}
+
+fn rasterize<F: Font>(font: &ab_glyph::PxScaleFont<&F>, c: char, bpp: u8) -> Result<Glyph> {
+    let id = font.glyph_id(c);
+    let advance = font.h_advance(id).round() as u16;
+    let outlined = match font.outline_glyph(id.with_scale(font.scale())) {
+        Some(outlined) => outlined,
+        // Whitespace
+        None => return Ok(Glyph { advance, ofs_x: 0, ofs_y: 0, width: 0, height: 0, bitmap: Vec::new() }),
+    };
+
+    let bounds = outlined.px_bounds();
+    let (width, height) = (bounds.width() as u32, bounds.height() as u32);
+    ensure!(width <= u8::MAX as u32 && height <= u8::MAX as u32, "Glyph '{}' is too large", c);
+    // Bounds are relative to the baseline with Y pointing down, LVGL has it pointing up
+    let (ofs_x, ofs_y) = (bounds.min.x as i32, -(bounds.max.y as i32));
+    ensure!(
+        (i8::MIN as i32..=i8::MAX as i32).contains(&ofs_x) && (i8::MIN as i32..=i8::MAX as i32).contains(&ofs_y),
+        "Glyph '{}' offset is out of range", c
+    );
+
+    let max_value = ((1u16 << bpp) - 1) as f32;
+    let mut values = vec![0u8; (width * height) as usize];
+    outlined.draw(|x, y, coverage| {
+        if x < width && y < height {
+            values[(y * width + x) as usize] = (coverage.clamp(0.0, 1.0) * max_value).round() as u8;
+        }
+    });
+
+    Ok(Glyph {
+        advance,
+        ofs_x: ofs_x as i8,
+        ofs_y: ofs_y as i8,
+        width: width as u8,
+        height: height as u8,
+        bitmap: pack_bits(&values, bpp),
+    })
+}
+
+/// Pack values of `bits` width continuously, most significant bits first
+fn pack_bits(values: &[u8], bits: u8) -> Vec<u8> {
+    let mut output = Vec::with_capacity((values.len() * bits as usize).div_ceil(8));
+    let (mut acc, mut acc_bits) = (0u16, 0u8);
+    for &value in values {
+        acc = (acc << bits) | value as u16;
+        acc_bits += bits;
+        if acc_bits >= 8 {
+            acc_bits -= 8;
+            output.push((acc >> acc_bits) as u8);
+            acc &= (1 << acc_bits) - 1;
+        }
+    }
+    if acc_bits > 0 {
+        output.push((acc << (8 - acc_bits)) as u8);
+    }
+    output
+}
+
+/// Table header: total length including the header, and 4-character tag.
+/// Content is padded to 4 bytes.
+fn table(tag: &[u8; 4], mut content: Vec<u8>) -> Vec<u8> {
+    content.resize(content.len().next_multiple_of(4), 0);
+    let mut output = ((content.len() + 8) as u32).to_le_bytes().to_vec();
+    output.extend_from_slice(tag);
+    output.extend(content);
+    output
+}
+
+fn head_table(options: &FontOptions, ascent: i16, descent: i16, min_y: i16, max_y: i16) -> Vec<u8> {
+    let mut head = Vec::new();
+    head.extend(1u32.to_le_bytes()); // version
+    head.extend(3u16.to_le_bytes()); // tables count (cmap, loca, glyf)
+    head.extend(options.size.to_le_bytes());
+    head.extend((ascent as u16).to_le_bytes());
+    head.extend(descent.to_le_bytes());
+    head.extend((ascent as u16).to_le_bytes()); // typo ascent
+    head.extend(descent.to_le_bytes()); // typo descent
+    head.extend(0u16.to_le_bytes()); // typo line gap
+    head.extend(min_y.to_le_bytes());
+    head.extend(max_y.to_le_bytes());
+    head.extend(0u16.to_le_bytes()); // default advance width
+    head.extend(0u16.to_le_bytes()); // kerning scale
+    head.push(1); // index to loc format: u32 offsets
+    head.push(0); // glyph ID format (only used by kerning)
+    head.push(0); // advance width format: whole pixels
+    head.push(options.bpp);
+    head.push(XY_BITS);
+    head.push(WH_BITS);
+    head.push(ADVANCE_WIDTH_BITS);
+    head.push(0); // compression: none
+    head.push(0); // subpixels mode: none
+    head.push(0); // padding
+    head.extend(0i16.to_le_bytes()); // underline position
+    head.extend(0u16.to_le_bytes()); // underline thickness
+    table(b"head", head)
+}
+
+fn cmap_table(chars: &[char]) -> Vec<u8> {
+    // Split sorted code points into contiguous ranges
+    let mut ranges: Vec<(u32, u16, u16)> = Vec::new(); // (start, length, first glyph ID)
+    for (idx, &c) in chars.iter().enumerate() {
+        let glyph_id = idx as u16 + 1; // Glyph 0 is reserved
+        match ranges.last_mut() {
+            Some((start, length, _)) if *start + *length as u32 == c as u32 && *length < u16::MAX => {
+                *length += 1;
+            }
+            _ => ranges.push((c as u32, 1, glyph_id)),
+        }
+    }
+
+    let mut cmap = (ranges.len() as u32).to_le_bytes().to_vec();
+    for (start, length, glyph_id) in ranges {
+        cmap.extend(0u32.to_le_bytes()); // data offset, no data for tiny format
+        cmap.extend(start.to_le_bytes());
+        cmap.extend(length.to_le_bytes());
+        cmap.extend(glyph_id.to_le_bytes());
+        cmap.extend(0u16.to_le_bytes()); // data entries count
+        cmap.push(CMAP_FORMAT0_TINY);
+        cmap.push(0); // padding
+    }
+    table(b"cmap", cmap)
+}
+
+fn glyph_tables(glyphs: &[Glyph]) -> (Vec<u8>, Vec<u8>) {
+    // Glyph 0 is reserved, but its descriptor is still read by the loader
+    let empty = Glyph { advance: 0, ofs_x: 0, ofs_y: 0, width: 0, height: 0, bitmap: Vec::new() };
+
+    let mut offsets = Vec::with_capacity(glyphs.len() + 1);
+    let mut glyf = Vec::new();
+    for glyph in std::iter::once(&empty).chain(glyphs) {
+        // Offsets are relative to the table start, including its header
+        offsets.push(glyf.len() as u32 + 8);
+        glyf.extend(glyph.advance.to_be_bytes());
+        glyf.push(glyph.ofs_x as u8);
+        glyf.push(glyph.ofs_y as u8);
+        glyf.push(glyph.width);
+        glyf.push(glyph.height);
+        glyf.extend(&glyph.bitmap);
+    }
+
+    let mut loca = (offsets.len() as u32).to_le_bytes().to_vec();
+    for offset in offsets {
+        loca.extend(offset.to_le_bytes());
+    }
+    (table(b"loca", loca), table(b"glyf", glyf))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ranges() {
+        let ranges = parse_ranges("0x20-0x7F, 0x410-0x44F,0xB0,65,").unwrap();
+        assert_eq!(ranges, [' '..='\x7f', 'А'..='я', '°'..='°', 'A'..='A']);
+        assert!(parse_ranges("0x20-").is_err());
+        assert!(parse_ranges("0xD800").is_err());
+    }
+
+    #[test]
+    fn bits_are_packed_continuously() {
+        assert_eq!(pack_bits(&[1, 0, 1], 1), [0b1010_0000]);
+        assert_eq!(pack_bits(&[3, 0, 2, 1, 3], 2), [0b1100_1001, 0b1100_0000]);
+        assert_eq!(pack_bits(&[0xf, 0x1, 0xa], 4), [0xf1, 0xa0]);
+        assert_eq!(pack_bits(&[0x12, 0x34], 8), [0x12, 0x34]);
+    }
+
+    #[test]
+    fn table_is_padded() {
+        assert_eq!(table(b"test", vec![1, 2, 3]), [12, 0, 0, 0, b't', b'e', b's', b't', 1, 2, 3, 0]);
+        assert_eq!(table(b"test", vec![]).len(), 8);
+    }
+
+    #[test]
+    fn cmap_ranges() {
+        let cmap = cmap_table(&['A', 'B', 'C', 'a']);
+        assert_eq!(cmap[..12], [8 + 4 + 2 * 16, 0, 0, 0, b'c', b'm', b'a', b'p', 2, 0, 0, 0]);
+        // 'A'..='C' mapped to glyphs 1..=3
+        assert_eq!(cmap[12..28], [0, 0, 0, 0, 0x41, 0, 0, 0, 3, 0, 1, 0, 0, 0, CMAP_FORMAT0_TINY, 0]);
+        // 'a' mapped to glyph 4
+        assert_eq!(cmap[28..44], [0, 0, 0, 0, 0x61, 0, 0, 0, 1, 0, 4, 0, 0, 0, CMAP_FORMAT0_TINY, 0]);
+    }
+
+    #[test]
+    fn glyph_offsets() {
+        let glyphs = [
+            Glyph { advance: 5, ofs_x: 0, ofs_y: -1, width: 2, height: 2, bitmap: vec![0xf0] },
+            Glyph { advance: 3, ofs_x: 0, ofs_y: 0, width: 0, height: 0, bitmap: Vec::new() },
+        ];
+        let (loca, glyf) = glyph_tables(&glyphs);
+        let offsets = loca[12..].chunks(4).map(|o| u32::from_le_bytes(o.try_into().unwrap())).collect::<Vec<_>>();
+        assert_eq!(offsets, [8, 14, 21]);
+        assert_eq!(glyf[8..], [0, 0, 0, 0, 0, 0, 0, 5, 0, 0xff, 2, 2, 0xf0, 0, 3, 0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn invalid_font() {
+        let options = FontOptions { size: 20, bpp: 4, ranges: vec![' '..='~'] };
+        assert!(convert_ttf(b"not a font", &options).is_err());
+        assert!(convert_ttf(b"", &FontOptions { bpp: 3, ..options }).is_err());
+    }
+}
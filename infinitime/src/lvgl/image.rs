@@ -0,0 +1,204 @@
+use anyhow::{anyhow, ensure, Result};
+use std::{collections::HashMap, io::Cursor};
+
+// Reference: lv_img_buf.h (LVGL v8)
+
+/// Maximum image width and height, limited by the header bit fields
+pub const MAX_IMAGE_SIZE: u32 = 2047;
+
+/// LVGL image color formats (`lv_img_cf_t`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColorFormat {
+    /// RGB565, alpha is blended onto black background
+    TrueColor = 4,
+    /// RGB565 followed by 8-bit alpha. The format of InfiniTime's own resources
+    TrueColorAlpha = 5,
+    /// Palette of up to 2, 4, 16 or 256 colors followed by color indices
+    Indexed1Bit = 7,
+    Indexed2Bit = 8,
+    Indexed4Bit = 9,
+    Indexed8Bit = 10,
+    /// Alpha channel only, the image is recolored when drawn
+    Alpha1Bit = 11,
+    Alpha2Bit = 12,
+    Alpha4Bit = 13,
+    Alpha8Bit = 14,
+}
+
+impl ColorFormat {
+    /// Bits per pixel for indexed and alpha-only formats
+    fn packed_bits(&self) -> Option<u8> {
+        match self {
+            Self::Indexed1Bit | Self::Alpha1Bit => Some(1),
+            Self::Indexed2Bit | Self::Alpha2Bit => Some(2),
+            Self::Indexed4Bit | Self::Alpha4Bit => Some(4),
+            Self::Indexed8Bit | Self::Alpha8Bit => Some(8),
+            Self::TrueColor | Self::TrueColorAlpha => None,
+        }
+    }
+}
+
+/// Decoded image, RGBA with 8 bits per channel
+pub struct Rgba {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<[u8; 4]>,
+}
+
+impl Rgba {
+    pub fn from_png(png: &[u8]) -> Result<Self> {
+        let mut decoder = png::Decoder::new(Cursor::new(png));
+        decoder.set_transformations(png::Transformations::normalize_to_color8());
+        let mut reader = decoder.read_info()?;
+        let mut buffer = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut buffer)?;
+        let data = &buffer[..info.buffer_size()];
+
+        let pixels = match info.color_type {
+            png::ColorType::Rgba => data.chunks_exact(4).map(|p| [p[0], p[1], p[2], p[3]]).collect(),
+            png::ColorType::Rgb => data.chunks_exact(3).map(|p| [p[0], p[1], p[2], 0xff]).collect(),
+            png::ColorType::GrayscaleAlpha => data.chunks_exact(2).map(|p| [p[0], p[0], p[0], p[1]]).collect(),
+            png::ColorType::Grayscale => data.iter().map(|&v| [v, v, v, 0xff]).collect(),
+            png::ColorType::Indexed => return Err(anyhow!("Unexpected indexed PNG after expansion")),
+        };
+        Ok(Self { width: info.width, height: info.height, pixels })
+    }
+}
+
+/// Convert PNG image into LVGL binary image file
+pub fn convert_png(png: &[u8], format: ColorFormat) -> Result<Vec<u8>> {
+    convert(&Rgba::from_png(png)?, format)
+}
+
+/// Convert decoded image into LVGL binary image file
+pub fn convert(image: &Rgba, format: ColorFormat) -> Result<Vec<u8>> {
+    ensure!(image.width > 0 && image.height > 0, "Image is empty");
+    ensure!(
+        image.pixels.len() == image.width as usize * image.height as usize,
+        "Image has {} pixels, expected {}x{}", image.pixels.len(), image.width, image.height
+    );
+    ensure!(
+        image.width <= MAX_IMAGE_SIZE && image.height <= MAX_IMAGE_SIZE,
+        "Image is too large: {}x{}, at most {}x{} is supported",
+        image.width, image.height, MAX_IMAGE_SIZE, MAX_IMAGE_SIZE
+    );
+
+    let mut output = header(format, image.width, image.height).to_vec();
+    match format {
+        ColorFormat::TrueColor => {
+            for &[r, g, b, a] in &image.pixels {
+                let blend = |c: u8| (c as u16 * a as u16 / 255) as u8;
+                output.extend_from_slice(&super::rgb565_bytes(blend(r), blend(g), blend(b)));
+            }
+        }
+        ColorFormat::TrueColorAlpha => {
+            for &[r, g, b, a] in &image.pixels {
+                output.extend_from_slice(&super::rgb565_bytes(r, g, b));
+                output.push(a);
+            }
+        }
+        ColorFormat::Indexed1Bit | ColorFormat::Indexed2Bit
+        | ColorFormat::Indexed4Bit | ColorFormat::Indexed8Bit => {
+            let bits = format.packed_bits().unwrap_or(8);
+            let mut palette: Vec<[u8; 4]> = Vec::new();
+            let mut palette_indices = HashMap::new();
+            let mut indices = Vec::with_capacity(image.pixels.len());
+            for pixel in &image.pixels {
+                let index = *palette_indices.entry(*pixel).or_insert_with(|| {
+                    palette.push(*pixel);
+                    palette.len() - 1
+                });
+                ensure!(
+                    index < 1 << bits,
+                    "Image has more than {} colors, which is the most {}-bit palette fits",
+                    1 << bits, bits
+                );
+                indices.push(index as u8);
+            }
+            // Palette always has all 2^bits entries of `lv_color32_t` (BGRA)
+            palette.resize(1 << bits, [0, 0, 0, 0]);
+            for [r, g, b, a] in palette {
+                output.extend_from_slice(&[b, g, r, a]);
+            }
+            output.extend(pack(&indices, image.width, bits));
+        }
+        ColorFormat::Alpha1Bit | ColorFormat::Alpha2Bit
+        | ColorFormat::Alpha4Bit | ColorFormat::Alpha8Bit => {
+            let bits = format.packed_bits().unwrap_or(8);
+            let alpha = image.pixels.iter().map(|p| p[3] >> (8 - bits)).collect::<Vec<_>>();
+            output.extend(pack(&alpha, image.width, bits));
+        }
+    }
+    Ok(output)
+}
+
+/// `lv_img_header_t`: color format (5 bits), 5 reserved bits,
+/// width (11 bits), height (11 bits)
+fn header(format: ColorFormat, width: u32, height: u32) -> [u8; 4] {
+    let value = (format as u32 & 0x1f) | ((width & 0x7ff) << 10) | ((height & 0x7ff) << 21);
+    value.to_le_bytes()
+}
+
+/// Pack values of `bits` width, most significant bits first.
+/// Every row starts at a byte boundary.
+fn pack(values: &[u8], width: u32, bits: u8) -> Vec<u8> {
+    let per_byte = 8 / bits as usize;
+    let mut output = Vec::new();
+    for row in values.chunks(width as usize) {
+        for group in row.chunks(per_byte) {
+            let mut byte = 0;
+            for (i, &value) in group.iter().enumerate() {
+                byte |= value << (8 - bits as usize * (i + 1));
+            }
+            output.push(byte);
+        }
+    }
+    output
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn image(width: u32, height: u32, pixels: &[[u8; 4]]) -> Rgba {
+        Rgba { width, height, pixels: pixels.to_vec() }
+    }
+
+    const BLACK: [u8; 4] = [0, 0, 0, 0xff];
+    const WHITE: [u8; 4] = [0xff, 0xff, 0xff, 0xff];
+    const RED: [u8; 4] = [0xff, 0, 0, 0xff];
+
+    #[test]
+    fn header_layout() {
+        // 3x2 TrueColorAlpha: 5 | 3 << 10 | 2 << 21
+        assert_eq!(header(ColorFormat::TrueColorAlpha, 3, 2), [0x05, 0x0c, 0x40, 0x00]);
+    }
+
+    #[test]
+    fn rows_start_at_byte_boundary() {
+        assert_eq!(pack(&[1, 0, 1, 0, 1, 1], 3, 1), [0b1010_0000, 0b0110_0000]);
+        assert_eq!(pack(&[3, 2, 1], 3, 2), [0b1110_0100]);
+    }
+
+    #[test]
+    fn indexed() {
+        let output = convert(&image(2, 1, &[WHITE, BLACK]), ColorFormat::Indexed1Bit).unwrap();
+        assert_eq!(output[4..], [
+            0xff, 0xff, 0xff, 0xff, // palette: white
+            0x00, 0x00, 0x00, 0xff, // palette: black
+            0b0100_0000,            // white, black
+        ]);
+
+        let error = convert(&image(3, 1, &[WHITE, BLACK, RED]), ColorFormat::Indexed1Bit);
+        assert!(error.is_err());
+    }
+
+    #[test]
+    fn invalid_dimensions() {
+        assert!(convert(&image(0, 0, &[]), ColorFormat::Alpha8Bit).is_err());
+        assert!(convert(&image(0, 1, &[WHITE]), ColorFormat::Alpha8Bit).is_err());
+        assert!(convert(&image(2, 2, &[WHITE]), ColorFormat::Alpha8Bit).is_err());
+        assert!(convert(&image(2048, 1, &[WHITE; 2048]), ColorFormat::Alpha8Bit).is_err());
+    }
+}
@@ -0,0 +1,272 @@
+use std::cmp::Ordering;
+use version_compare::{Manifest, Version};
+
+/// First firmware version that stores settings in the LittleFS filesystem
+/// on the external flash. Downgrading below it loses the settings.
+pub const FS_LAYOUT_BOUNDARY: &str = "1.8.0";
+
+/// First firmware version that loads fonts and images from the resources
+/// stored on the watch filesystem, instead of the internal flash
+pub const RESOURCES_BOUNDARY: &str = "1.11.0";
+
+/// Oldest firmware version providing all the services used by this crate
+/// (most notably the filesystem service required for resources upload)
+pub const MIN_SUPPORTED_VERSION: &str = "1.11.0";
+
+
+/// How the target firmware release relates to the installed one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UpdateKind {
+    Upgrade,
+    Reinstall,
+    Downgrade,
+    /// At least one of the versions couldn't be parsed
+    Unknown,
+}
+
+/// Something the user has to explicitly agree to before proceeding
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Confirmation {
+    /// Target firmware is older than the installed one
+    Downgrade,
+    /// Downgrade crosses the filesystem layout change, settings will be lost
+    CrossesFsLayout,
+    /// Downgrade crosses the introduction of external resources, resources
+    /// stored on the watch won't be used by the target firmware anymore
+    CrossesResourceFormat,
+    /// Target firmware is older than the oldest supported version
+    UnsupportedTarget,
+    /// Resources version doesn't match the installed firmware version
+    ResourcesMismatch,
+}
+
+impl Confirmation {
+    pub fn description(&self) -> &'static str {
+        match self {
+            Self::Downgrade => "The selected firmware is older than the installed one",
+            Self::CrossesFsLayout => "Settings stored on the watch will be lost after downgrading below 1.8.0",
+            Self::CrossesResourceFormat => "Firmware older than 1.11.0 doesn't use resources stored on the watch",
+            Self::UnsupportedTarget => "The selected firmware is too old to be fully supported by this app",
+            Self::ResourcesMismatch => "The selected resources do not match the installed firmware version",
+        }
+    }
+}
+
+/// What has to happen to the resources on the watch after the operation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResourceStep {
+    /// Resources are either not used or already match
+    Keep,
+    /// Resources matching the target firmware have to be installed
+    Install,
+    /// Target firmware doesn't use resources, they can be removed
+    Remove,
+}
+
+/// The rules to follow for a particular operation
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UpdatePlan {
+    pub kind: UpdateKind,
+    pub confirmations: Vec<Confirmation>,
+    pub resource_step: ResourceStep,
+}
+
+impl UpdatePlan {
+    pub fn needs_confirmation(&self) -> bool {
+        !self.confirmations.is_empty()
+    }
+
+    /// Human-readable explanation of all the required confirmations
+    pub fn warning_text(&self) -> String {
+        self.confirmations.iter()
+            .map(Confirmation::description)
+            .collect::<Vec<_>>()
+            .join(". ")
+    }
+}
+
+
+/// Compare versions ignoring non-numeric parts, so that e.g. "v1.14.0"
+/// release tag is treated the same as "1.14.0" reported by the watch
+pub fn compare(a: &str, b: &str) -> Option<Ordering> {
+    let mut manifest = Manifest::default();
+    manifest.ignore_text = true;
+    let a = parse(a, &manifest)?;
+    let b = parse(b, &manifest)?;
+    a.partial_cmp(&b)
+}
+
+fn parse<'a>(version: &'a str, manifest: &'a Manifest) -> Option<Version<'a>> {
+    // Text-only versions (e.g. branch names of custom builds) can't be compared
+    let version = version.trim().trim_start_matches(['v', 'V']);
+    if !version.starts_with(|c: char| c.is_ascii_digit()) {
+        return None;
+    }
+    Version::from_manifest(version, manifest)
+}
+
+pub fn versions_match(a: &str, b: &str) -> bool {
+    match compare(a, b) {
+        Some(ordering) => ordering == Ordering::Equal,
+        None => a.trim() == b.trim(),
+    }
+}
+
+pub fn is_supported(version: &str) -> bool {
+    compare(version, MIN_SUPPORTED_VERSION) != Some(Ordering::Less)
+}
+
+pub fn uses_resources(version: &str) -> bool {
+    compare(version, RESOURCES_BOUNDARY) != Some(Ordering::Less)
+}
+
+pub fn is_update_available(installed: &str, latest: &str) -> bool {
+    classify(installed, latest) == UpdateKind::Upgrade
+}
+
+pub fn classify(installed: &str, target: &str) -> UpdateKind {
+    match compare(target, installed) {
+        Some(Ordering::Greater) => UpdateKind::Upgrade,
+        Some(Ordering::Equal) => UpdateKind::Reinstall,
+        Some(Ordering::Less) => UpdateKind::Downgrade,
+        None => UpdateKind::Unknown,
+    }
+}
+
+/// Rules for flashing `target` firmware over the `installed` one.
+/// Installed version may be unknown, e.g. if the watch is not connected.
+pub fn plan_firmware_update(installed: Option<&str>, target: &str) -> UpdatePlan {
+    let kind = installed.map_or(UpdateKind::Unknown, |i| classify(i, target));
+    let mut confirmations = Vec::new();
+
+    if kind == UpdateKind::Downgrade {
+        confirmations.push(Confirmation::Downgrade);
+        let installed = installed.unwrap_or_default();
+        if crosses(installed, target, FS_LAYOUT_BOUNDARY) {
+            confirmations.push(Confirmation::CrossesFsLayout);
+        }
+        if crosses(installed, target, RESOURCES_BOUNDARY) {
+            confirmations.push(Confirmation::CrossesResourceFormat);
+        }
+    }
+    if !is_supported(target) {
+        confirmations.push(Confirmation::UnsupportedTarget);
+    }
+
+    let resource_step = match (kind, uses_resources(target)) {
+        (_, false) if installed.is_some_and(uses_resources) => ResourceStep::Remove,
+        (_, false) | (UpdateKind::Reinstall, true) => ResourceStep::Keep,
+        (_, true) => ResourceStep::Install,
+    };
+
+    UpdatePlan { kind, confirmations, resource_step }
+}
+
+/// Rules for installing resources of version `resources` on the watch
+/// running `installed` firmware
+pub fn plan_resources_install(installed: Option<&str>, resources: &str) -> UpdatePlan {
+    let kind = installed.map_or(UpdateKind::Unknown, |i| classify(i, resources));
+    let mut confirmations = Vec::new();
+    if matches!(kind, UpdateKind::Upgrade | UpdateKind::Downgrade) {
+        confirmations.push(Confirmation::ResourcesMismatch);
+    }
+    UpdatePlan { kind, confirmations, resource_step: ResourceStep::Install }
+}
+
+/// Whether going from `from` to `to` crosses the `boundary` version downwards
+fn crosses(from: &str, to: &str, boundary: &str) -> bool {
+    matches!(compare(from, boundary), Some(Ordering::Greater | Ordering::Equal))
+        && compare(to, boundary) == Some(Ordering::Less)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use Confirmation::*;
+
+    #[test]
+    fn version_comparison() {
+        assert_eq!(compare("v1.14.0", "1.14.0"), Some(Ordering::Equal));
+        assert_eq!(compare("1.9.0", "1.11.0"), Some(Ordering::Less));
+        assert_eq!(compare("1.14.1", "1.14.0"), Some(Ordering::Greater));
+        assert!(versions_match(" 1.14.0", "v1.14.0"));
+        assert!(!versions_match("1.14.0", "1.15.0"));
+        assert!(versions_match("custom", "custom"));
+    }
+
+    #[test]
+    fn version_boundaries() {
+        for (version, supported, resources) in [
+            ("1.7.1", false, false),
+            ("1.8.0", false, false),
+            ("1.10.0", false, false),
+            ("1.11.0", true, true),
+            ("v1.14.0", true, true),
+            ("unknown", true, true),
+        ] {
+            assert_eq!(is_supported(version), supported, "{version}");
+            assert_eq!(uses_resources(version), resources, "{version}");
+        }
+    }
+
+    #[test]
+    fn update_kind() {
+        for (installed, target, kind) in [
+            ("1.13.0", "1.14.0", UpdateKind::Upgrade),
+            ("1.14.0", "v1.14.0", UpdateKind::Reinstall),
+            ("1.14.0", "1.13.0", UpdateKind::Downgrade),
+            ("1.14.0", "custom", UpdateKind::Unknown),
+        ] {
+            assert_eq!(classify(installed, target), kind, "{installed} -> {target}");
+        }
+        assert!(is_update_available("1.13.0", "v1.14.0"));
+        assert!(!is_update_available("1.14.0", "v1.14.0"));
+    }
+
+    #[test]
+    fn firmware_update_plans() {
+        for (installed, target, kind, confirmations, resource_step) in [
+            (Some("1.13.0"), "1.14.0", UpdateKind::Upgrade, vec![], ResourceStep::Install),
+            (Some("1.14.0"), "1.14.0", UpdateKind::Reinstall, vec![], ResourceStep::Keep),
+            (Some("1.14.0"), "1.13.0", UpdateKind::Downgrade, vec![Downgrade], ResourceStep::Install),
+            (None, "1.14.0", UpdateKind::Unknown, vec![], ResourceStep::Install),
+            (
+                Some("1.14.0"), "1.10.0", UpdateKind::Downgrade,
+                vec![Downgrade, CrossesResourceFormat, UnsupportedTarget], ResourceStep::Remove,
+            ),
+            (
+                Some("1.14.0"), "1.7.0", UpdateKind::Downgrade,
+                vec![Downgrade, CrossesFsLayout, CrossesResourceFormat, UnsupportedTarget], ResourceStep::Remove,
+            ),
+            (Some("1.7.0"), "1.10.0", UpdateKind::Upgrade, vec![UnsupportedTarget], ResourceStep::Keep),
+            (Some("1.10.0"), "1.11.0", UpdateKind::Upgrade, vec![], ResourceStep::Install),
+            (None, "1.10.0", UpdateKind::Unknown, vec![UnsupportedTarget], ResourceStep::Keep),
+        ] {
+            let plan = plan_firmware_update(installed, target);
+            assert_eq!(plan, UpdatePlan { kind, confirmations, resource_step }, "{installed:?} -> {target}");
+        }
+    }
+
+    #[test]
+    fn resources_install_plans() {
+        let plan = plan_resources_install(Some("1.14.0"), "v1.14.0");
+        assert!(!plan.needs_confirmation());
+        let plan = plan_resources_install(Some("1.14.0"), "1.13.0");
+        assert_eq!(plan.confirmations, [ResourcesMismatch]);
+        let plan = plan_resources_install(None, "1.13.0");
+        assert!(!plan.needs_confirmation());
+    }
+
+    #[test]
+    fn warning_text() {
+        let plan = plan_firmware_update(Some("1.14.0"), "1.7.0");
+        assert_eq!(
+            plan.warning_text(),
+            "The selected firmware is older than the installed one. \
+            Settings stored on the watch will be lost after downgrading below 1.8.0. \
+            Firmware older than 1.11.0 doesn't use resources stored on the watch. \
+            The selected firmware is too old to be fully supported by this app"
+        );
+    }
+}
@@ -1,7 +1,7 @@
-use infinitime::{bluer, bt};
+use infinitime::{bluer, bt, gh, journal};
 use std::{sync::Arc, path::PathBuf, env};
 use futures::{pin_mut, StreamExt};
-use gtk::{gio, glib, prelude::{ApplicationExt, BoxExt, GtkWindowExt, SettingsExt, WidgetExt}};
+use gtk::{gio, glib, prelude::{ActionMapExt, ApplicationExt, BoxExt, GtkWindowExt, SettingsExt, WidgetExt}};
 use relm4::{
     adw, gtk, actions::{AccelsPlus, RelmAction, RelmActionGroup},
     Component, ComponentController, ComponentParts,
@@ -11,6 +11,8 @@ use relm4::{
 mod dashboard_page;
 mod devices_page;
 mod fwupd_page;
+mod keyring;
+mod markdown;
 mod settings_page;
 mod icon_names {
     include!(concat!(env!("OUT_DIR"), "/icon_names.rs"));
@@ -19,12 +21,51 @@ mod icon_names {
 
 static APP_ID: &'static str = "io.gitlab.azymohliad.WatchMate";
 static SETTING_NOTIFICATIONS: &'static str = "forward-notifications";
+static SETTING_NOTIFICATIONS_DEVICES: &'static str = "forward-notifications-devices";
+static SETTING_NOTIFICATIONS_REQUIRE_PAIRING: &'static str = "notifications-require-pairing";
 static SETTING_BACKGROUND: &'static str = "run-in-background";
 static SETTING_AUTO_START: &'static str = "auto-start";
 static SETTING_DEVICE_ADDRESS: &'static str = "auto-connect-address";
+static SETTING_DEVICE_ADDRESSES: &'static str = "auto-connect-addresses";
+static SETTING_ADAPTER: &'static str = "bluetooth-adapter";
+static SETTING_DEVICE_NAMES: &'static str = "device-names";
+static SETTING_VERIFY_RESOURCES: &'static str = "verify-resources";
+static SETTING_FIRMWARE_VERSIONS: &'static str = "firmware-versions";
+static SETTING_GITHUB_TOKEN: &'static str = "github-token";
+static SETTING_RELEASE_SOURCE: &'static str = "release-source";
+static SETTING_INCLUDE_PRERELEASES: &'static str = "release-include-prereleases";
+static SETTING_REQUIRE_RESOURCES: &'static str = "release-require-resources";
+static SETTING_UPDATE_CHECK_INTERVAL: &'static str = "update-check-interval";
+static SETTING_LIBRARY_KEEP: &'static str = "firmware-library-keep";
 
 static BROKER: relm4::MessageBroker<Input> = MessageBroker::new();
 
+pub fn flash_journal() -> journal::Journal {
+    journal::Journal::new(glib::user_data_dir().join("watchmate").join("flash-journal.json"))
+}
+
+pub fn firmware_library() -> gh::Library {
+    gh::Library::new(glib::user_data_dir().join("watchmate").join("firmware"))
+}
+
+pub fn github_client() -> gh::Client {
+    gh::Client::new()
+        .with_cache_dir(glib::user_cache_dir().join("watchmate").join("github"))
+        .with_token(keyring::github_token())
+}
+
+pub fn release_source_config(settings: &gio::Settings) -> gh::SourceConfig {
+    let value = settings.string(SETTING_RELEASE_SOURCE);
+    value.parse().unwrap_or_else(|error| {
+        log::warn!("Invalid release source '{}': {}", value, error);
+        gh::SourceConfig::default()
+    })
+}
+
+pub fn release_source(settings: &gio::Settings) -> anyhow::Result<Box<dyn gh::ReleaseSource>> {
+    release_source_config(settings).build(github_client())
+}
+
 
 relm4::new_action_group!(ViewActionGroup, "view");
 relm4::new_stateless_action!(DashboardViewAction, ViewActionGroup, "dashboard");
@@ -40,11 +81,26 @@ relm4::new_stateless_action!(QuitAction, WindowActionGroup, "quit");
 enum Input {
     SetView(View),
     DeviceConnected(Arc<bluer::Device>),
-    DeviceDisconnected,
+    DeviceDisconnected(bluer::Address),
     DeviceReady(Arc<bt::InfiniTime>),
-    DeviceRejected,
+    DeviceRejected(bluer::Address),
+    DeviceSetupFailed(bluer::Address),
+    Adapters(Vec<devices_page::AdapterInfo>),
     FlashAssetFromFile(PathBuf, fwupd_page::AssetType),
-    FlashAssetFromUrl(String, fwupd_page::AssetType),
+    FlashAssetFromRelease(gh::Asset, fwupd_page::AssetType, Option<String>),
+    UpdateAllFromRelease {
+        firmware: gh::Asset,
+        resources: gh::Asset,
+        version: String,
+    },
+    ExpectReconnect(bluer::Address),
+    ResourcesChanged,
+    ReviewFirmwareUpdate(bluer::Address, String),
+    SelectWatch(u32),
+    WatchAlias(Option<bluer::Address>, String),
+    FlashInterrupted(journal::JournalEntry),
+    GithubTokenLoaded(Option<String>),
+    FlashInterruptedDismissed,
     Toast(String),
     ToastStatic(&'static str),
     ToastWithLink {
@@ -58,21 +114,108 @@ enum Input {
     Quit,
 }
 
+/// Connected watch with its own dashboard
+struct Watch {
+    infinitime: Arc<bt::InfiniTime>,
+    dashboard: Controller<dashboard_page::Model>,
+}
+
+impl Watch {
+    fn address(&self) -> bluer::Address {
+        self.infinitime.device().address()
+    }
+}
+
 struct Model {
     // UI state
     active_view: View,
-    is_connected: bool,
     // Components
+    // - Dashboard shown while no watch is connected
     dashboard_page: Controller<dashboard_page::Model>,
     devices_page: Controller<devices_page::Model>,
     fwupd_page: Controller<fwupd_page::Model>,
     settings_page: Controller<settings_page::Model>,
     // Other
-    infinitime: Option<Arc<bt::InfiniTime>>,
+    watches: Vec<Watch>,
+    // Watch shown on the dashboard
+    active_address: Option<bluer::Address>,
+    // Watch the firmware update page is working with
+    fwupd_address: Option<bluer::Address>,
+    // Names for the watch switcher, in the same order as `watches`
+    watch_names: gtk::StringList,
+    interrupted_flash: Option<journal::JournalEntry>,
+    settings: gio::Settings,
+    dashboards: gtk::Stack,
     toast_overlay: adw::ToastOverlay,
     hide_on_startup: bool,  // Temporary hack
 }
 
+impl Model {
+    fn launch_dashboard(
+        root: &adw::ApplicationWindow,
+        settings: &gio::Settings,
+        watch_names: &gtk::StringList,
+        address: Option<bluer::Address>,
+        sender: &ComponentSender<Self>,
+    ) -> Controller<dashboard_page::Model> {
+        dashboard_page::Model::builder()
+            .launch((root.clone(), settings.clone(), watch_names.clone()))
+            .forward(&sender.input_sender(), move |message| match message {
+                dashboard_page::Output::FlashAssetFromFile(file, atype) => Input::FlashAssetFromFile(file, atype),
+                dashboard_page::Output::FlashAssetFromRelease(asset, atype, version) => Input::FlashAssetFromRelease(asset, atype, version),
+                dashboard_page::Output::UpdateAllFromRelease { firmware, resources, version } => {
+                    Input::UpdateAllFromRelease { firmware, resources, version }
+                }
+                dashboard_page::Output::WatchAlias(alias) => Input::WatchAlias(address, alias),
+                dashboard_page::Output::FlashInterruptedDismissed => Input::FlashInterruptedDismissed,
+            })
+    }
+
+    fn watch_index(&self, address: bluer::Address) -> Option<usize> {
+        self.watches.iter().position(|w| w.address() == address)
+    }
+
+    fn watch(&self, address: Option<bluer::Address>) -> Option<&Watch> {
+        address.and_then(|a| self.watches.iter().find(|w| w.address() == a))
+    }
+
+    /// Dashboard of the shown watch, or the placeholder one
+    fn active_dashboard(&self) -> &Controller<dashboard_page::Model> {
+        self.watch(self.active_address).map_or(&self.dashboard_page, |w| &w.dashboard)
+    }
+
+    fn show_watch(&mut self, address: Option<bluer::Address>) {
+        let visible = self.active_view == View::Dashboard;
+        self.active_dashboard().emit(dashboard_page::Input::Visible(false));
+        self.active_address = address;
+        self.dashboards.set_visible_child_name(&address.map(|a| a.to_string()).unwrap_or(String::from("none")));
+        self.active_dashboard().emit(dashboard_page::Input::Visible(visible));
+    }
+
+    /// Let every dashboard know its position in the watch switcher
+    fn update_watch_indices(&self) {
+        for (index, watch) in self.watches.iter().enumerate() {
+            watch.dashboard.emit(dashboard_page::Input::WatchIndex(index as u32));
+        }
+        self.dashboard_page.emit(dashboard_page::Input::WatchIndex(0));
+    }
+
+    /// Flash the shown watch on the firmware update page. Refused while
+    /// the page is busy with the previous operation, which is shown instead.
+    fn attach_fwupd_page(&mut self) -> bool {
+        if self.fwupd_page.model().is_busy() {
+            self.toast_overlay.add_toast(adw::Toast::new("Wait until the current firmware update finishes"));
+            return false;
+        }
+        if let Some(watch) = self.watch(self.active_address) {
+            let infinitime = watch.infinitime.clone();
+            self.fwupd_address = Some(infinitime.device().address());
+            self.fwupd_page.emit(fwupd_page::Input::Connected(infinitime));
+        }
+        true
+    }
+}
+
 #[relm4::component]
 impl Component for Model {
     type CommandOutput = ();
@@ -98,8 +241,11 @@ impl Component for Model {
                 #[wrap(Some)]
                 set_child = &gtk::Stack {
                     add_named[Some("dashboard_view")] = &gtk::Box {
-                        // set_visible: watch!(components.dashboard.model.device.is_some()),
-                        append: model.dashboard_page.widget(),
+                        #[local]
+                        dashboards -> gtk::Stack {
+                            set_hexpand: true,
+                            set_transition_type: gtk::StackTransitionType::Crossfade,
+                        },
                     },
                     add_named[Some("devices_view")] = &gtk::Box {
                         append: model.devices_page.widget(),
@@ -126,45 +272,71 @@ impl Component for Model {
         let settings = gio::Settings::new(APP_ID);
 
         // Components
-        let dashboard_page = dashboard_page::Model::builder()
-            .launch((root.clone(), settings.clone()))
-            .forward(&sender.input_sender(), |message| match message {
-                dashboard_page::Output::FlashAssetFromFile(file, atype) => Input::FlashAssetFromFile(file, atype),
-                dashboard_page::Output::FlashAssetFromUrl(url, atype) => Input::FlashAssetFromUrl(url, atype),
-            });
+        let watch_names = gtk::StringList::new(&[]);
+        let dashboard_page = Self::launch_dashboard(&root, &settings, &watch_names, None, &sender);
+        let dashboards = gtk::Stack::new();
+        dashboards.add_named(dashboard_page.widget(), Some("none"));
 
         let devices_page = devices_page::Model::builder()
             .launch(settings.clone())
             .forward(&sender.input_sender(), |message| match message {
                 devices_page::Output::DeviceConnected(device) => Input::DeviceConnected(device),
+                devices_page::Output::Adapters(adapters) => Input::Adapters(adapters),
             });
 
         let fwupd_page = fwupd_page::Model::builder()
-            .launch(())
+            .launch(settings.clone())
             .detach();
 
         let settings_page = settings_page::Model::builder()
             .launch(settings.clone())
             .detach();
 
+        // Load GitHub token, moving it out of the settings where older versions kept it
+        let legacy_token = Some(settings.string(SETTING_GITHUB_TOKEN).to_string()).filter(|t| !t.is_empty());
+        let sender_ = sender.clone();
+        relm4::spawn(async move {
+            match keyring::load_github_token(legacy_token).await {
+                Ok(token) => sender_.input(Input::GithubTokenLoaded(token)),
+                Err(error) => log::error!("Failed to read GitHub token from the keyring: {}", error),
+            }
+        });
+
+        // Check for firmware operations interrupted by crash or suspend
+        let sender_ = sender.clone();
+        relm4::spawn(async move {
+            match flash_journal().recover_interrupted().await {
+                Ok(entries) => if let Some(entry) = entries.into_iter().last() {
+                    sender_.input(Input::FlashInterrupted(entry));
+                }
+                Err(error) => log::error!("Failed to read flash journal: {}", error),
+            }
+        });
+
         // Initialize model
         let model = Model {
             // UI state
             active_view: View::Devices,
-            is_connected: false,
             // Components
             dashboard_page,
             devices_page,
             fwupd_page,
             settings_page,
             // Other
-            infinitime: None,
+            watches: Vec::new(),
+            active_address: None,
+            fwupd_address: None,
+            watch_names,
+            interrupted_flash: None,
+            settings: settings.clone(),
+            dashboards,
             toast_overlay: adw::ToastOverlay::new(),
             hide_on_startup: start_in_background,
         };
 
         // Widgets
         let toast_overlay = model.toast_overlay.clone();
+        let dashboards = model.dashboards.clone();
         let widgets = view_output!();
 
         // Settings
@@ -214,6 +386,20 @@ impl Component for Model {
         )));
         global_group.register_for_widget(&widgets.main_window);
 
+        // Application-wide, so that it can be activated from desktop notifications
+        // with the watch address and the firmware version as the parameter
+        let review_update_action = gio::SimpleAction::new("review-firmware-update", Some(&glib::VariantType::new("(ss)").unwrap()));
+        review_update_action.connect_activate(glib::clone!(#[strong] sender, move |_, parameter| {
+            let parameter = parameter.and_then(|p| p.get::<(String, String)>());
+            if let Some((address, version)) = parameter {
+                match address.parse() {
+                    Ok(address) => sender.input(Input::ReviewFirmwareUpdate(address, version)),
+                    Err(error) => log::error!("Invalid watch address '{}': {}", address, error),
+                }
+            }
+        }));
+        app.add_action(&review_update_action);
+
         ComponentParts { model, widgets }
     }
 
@@ -228,42 +414,92 @@ impl Component for Model {
                     if view == View::Devices {
                         self.devices_page.emit(devices_page::Input::StartDiscovery);
                     }
+                    if view == View::Settings {
+                        // Powered state might have changed
+                        self.devices_page.emit(devices_page::Input::RefreshAdapters);
+                    }
+                    self.active_dashboard().emit(dashboard_page::Input::Visible(view == View::Dashboard));
                     self.active_view = view;
                 }
             }
             Input::DeviceConnected(device) => {
                 log::info!("Device connected: {}", device.address());
-                self.is_connected = true;
+                if self.watch_index(device.address()).is_some() {
+                    return;
+                }
                 relm4::spawn(async move {
-                    match bt::InfiniTime::new(device).await {
+                    match bt::InfiniTime::new(device.clone()).await {
                         Ok(infinitime) => {
                             sender.input(Input::DeviceReady(Arc::new(infinitime)));
                         }
                         Err(error) => {
-                            sender.input(Input::DeviceRejected);
-                            log::error!("Device is rejected: {}", error);
-                            sender.input(Input::ToastStatic("Device is rejected by the app"));
+                            // Don't leave it connected, nothing else would disconnect it
+                            if let Err(error) = device.disconnect().await {
+                                log::warn!("Failed to disconnect device: {}", error);
+                            }
+                            if error.downcast_ref::<bt::NotInfiniTime>().is_some() {
+                                log::error!("Device is rejected: {}", error);
+                                sender.input(Input::DeviceRejected(device.address()));
+                                sender.input(Input::ToastStatic("Device is rejected by the app"));
+                            } else {
+                                log::error!("Failed to set up device: {}", error);
+                                sender.input(Input::DeviceSetupFailed(device.address()));
+                                sender.input(Input::ToastStatic("Failed to set up the device"));
+                            }
                         }
                     }
                 });
             }
-            Input::DeviceDisconnected => {
-                log::info!("PineTime disconnected");
-                if let Some(infinitime) = self.infinitime.take() {
-                    self.devices_page.emit(devices_page::Input::DeviceConnectionLost(infinitime.device().address()));
+            Input::DeviceDisconnected(address) => {
+                log::info!("PineTime disconnected: {}", address);
+                let Some(index) = self.watch_index(address) else {
+                    return;
+                };
+                self.devices_page.emit(devices_page::Input::DeviceConnectionLost(address));
+                if self.fwupd_address == Some(address) {
+                    self.fwupd_page.emit(fwupd_page::Input::Disconnected);
+                }
+                if self.active_address == Some(address) {
+                    let next = self.watches.iter().map(Watch::address).find(|a| *a != address);
+                    self.show_watch(next);
+                }
+                // Dropping the dashboard shuts it down with its tasks
+                let watch = self.watches.remove(index);
+                self.dashboards.remove(watch.dashboard.widget());
+                self.watch_names.remove(index as u32);
+                self.update_watch_indices();
+                // Stay on the firmware update page, the watch reboots after upgrade
+                if self.watches.is_empty() && self.active_view != View::FirmwareUpdate {
+                    sender.input(Input::SetView(View::Devices));
                 }
-                self.dashboard_page.emit(dashboard_page::Input::Disconnected);
-                self.fwupd_page.emit(fwupd_page::Input::Disconnected);
-                sender.input(Input::SetView(View::Devices));
             }
             Input::DeviceReady(infinitime) => {
-                log::info!("PineTime recognized");
-                self.infinitime = Some(infinitime.clone());
+                let address = infinitime.device().address();
+                log::info!("PineTime recognized: {}", address);
+                if self.watch_index(address).is_some() {
+                    return;
+                }
+                let dashboard = Self::launch_dashboard(root, &self.settings, &self.watch_names, Some(address), &sender);
+                self.dashboards.add_named(dashboard.widget(), Some(&address.to_string()));
+                dashboard.emit(dashboard_page::Input::Connected(infinitime.clone()));
+                if let Some(entry) = self.interrupted_flash.as_ref().filter(|e| e.device_address == address.to_string()) {
+                    dashboard.emit(dashboard_page::Input::FlashInterrupted(entry.clone()));
+                }
+                self.watches.push(Watch { infinitime: infinitime.clone(), dashboard });
+                self.watch_names.append(&address.to_string());
+                self.update_watch_indices();
+
+                if self.active_address.is_none() || self.active_view == View::Devices {
+                    self.show_watch(Some(address));
+                }
                 if self.active_view == View::Devices {
                     self.active_view = View::Dashboard;
+                    self.active_dashboard().emit(dashboard_page::Input::Visible(true));
+                }
+                // The watch reconnects after the firmware upgrade
+                if self.fwupd_address == Some(address) {
+                    self.fwupd_page.emit(fwupd_page::Input::Connected(infinitime.clone()));
                 }
-                self.dashboard_page.emit(dashboard_page::Input::Connected(infinitime.clone()));
-                self.fwupd_page.emit(fwupd_page::Input::Connected(infinitime.clone()));
                 // Handle disconnection
                 relm4::spawn(async move {
                     match infinitime.get_property_stream().await {
@@ -276,20 +512,88 @@ impl Component for Model {
                             log::error!("Failed to get property stream: {}", error);
                         }
                     }
-                    sender.input(Input::DeviceDisconnected);
+                    sender.input(Input::DeviceDisconnected(address));
                 });
             }
-            Input::DeviceRejected => {
-                self.devices_page.emit(devices_page::Input::StartDiscovery);
+            Input::DeviceRejected(address) => {
+                self.devices_page.emit(devices_page::Input::DeviceRejected(address));
+            }
+            Input::DeviceSetupFailed(address) => {
+                self.devices_page.emit(devices_page::Input::DeviceSetupFailed(address));
+            }
+            Input::Adapters(adapters) => {
+                self.settings_page.emit(settings_page::Input::Adapters(adapters));
             }
             Input::FlashAssetFromFile(file, atype) => {
-                self.fwupd_page.emit(fwupd_page::Input::FlashAssetFromFile(file, atype));
+                if self.attach_fwupd_page() {
+                    self.fwupd_page.emit(fwupd_page::Input::FlashAssetFromFile(file, atype));
+                }
                 sender.input(Input::SetView(View::FirmwareUpdate));
             }
-            Input::FlashAssetFromUrl(url, atype) => {
-                self.fwupd_page.emit(fwupd_page::Input::FlashAssetFromUrl(url, atype));
+            Input::FlashAssetFromRelease(asset, atype, version) => {
+                if self.attach_fwupd_page() {
+                    self.fwupd_page.emit(fwupd_page::Input::FlashAssetFromRelease(asset, atype, version));
+                }
                 sender.input(Input::SetView(View::FirmwareUpdate));
             }
+            Input::UpdateAllFromRelease { firmware, resources, version } => {
+                if self.attach_fwupd_page() {
+                    self.fwupd_page.emit(fwupd_page::Input::UpdateAllFromRelease { firmware, resources, version });
+                }
+                sender.input(Input::SetView(View::FirmwareUpdate));
+            }
+            Input::ExpectReconnect(address) => {
+                self.devices_page.emit(devices_page::Input::ExpectReconnect(address));
+            }
+            Input::ResourcesChanged => {
+                if let Some(watch) = self.watch(self.fwupd_address) {
+                    watch.dashboard.emit(dashboard_page::Input::RefreshResources);
+                }
+            }
+            Input::ReviewFirmwareUpdate(address, version) => {
+                root.present();
+                if self.watch_index(address).is_none() {
+                    log::warn!("Watch {} is no longer connected", address);
+                    return;
+                }
+                self.show_watch(Some(address));
+                sender.input(Input::SetView(View::Dashboard));
+                self.active_dashboard().emit(dashboard_page::Input::ReviewFirmwareUpdate(version));
+            }
+            Input::SelectWatch(index) => {
+                if let Some(address) = self.watches.get(index as usize).map(Watch::address) {
+                    self.show_watch(Some(address));
+                    self.update_watch_indices();
+                }
+            }
+            Input::WatchAlias(address, alias) => {
+                if let Some(index) = address.and_then(|a| self.watch_index(a)) {
+                    self.watch_names.splice(index as u32, 1, &[&alias]);
+                    // Replacing the item may move the selection of the switchers
+                    self.update_watch_indices();
+                }
+            }
+            Input::FlashInterrupted(entry) => {
+                log::warn!("Interrupted {} update detected: {:?}", entry.asset, entry);
+                // Shown once the interrupted watch is connected
+                for watch in self.watches.iter().filter(|w| w.address().to_string() == entry.device_address) {
+                    watch.dashboard.emit(dashboard_page::Input::FlashInterrupted(entry.clone()));
+                }
+                self.interrupted_flash = Some(entry);
+            }
+            Input::GithubTokenLoaded(token) => {
+                // It's in the keyring now
+                self.settings.reset(SETTING_GITHUB_TOKEN);
+                self.settings_page.emit(settings_page::Input::GithubToken(token));
+            }
+            Input::FlashInterruptedDismissed => {
+                if self.interrupted_flash.take().is_some() {
+                    self.dashboard_page.emit(dashboard_page::Input::FlashInterruptedDismiss);
+                    for watch in &self.watches {
+                        watch.dashboard.emit(dashboard_page::Input::FlashInterruptedDismiss);
+                    }
+                }
+            }
             Input::Toast(message) => {
                 self.toast_overlay.add_toast(adw::Toast::new(&message));
             }
@@ -1,13 +1,12 @@
 use crate::ui::{self, fwupd_page::AssetType};
-use infinitime::{tokio, bt};
+use infinitime::{tokio, bt, gh, journal::JournalEntry, policy};
 
-use std::{sync::Arc, path::PathBuf};
+use std::{collections::HashMap, sync::Arc, path::PathBuf, time::Duration};
 use futures::{stream, StreamExt};
-use gtk::prelude::{BoxExt, ButtonExt, OrientableExt, ListBoxRowExt, WidgetExt};
-use adw::prelude::{PreferencesRowExt, ExpanderRowExt};
-use relm4::{adw, gtk::{self, gio}, ComponentController, ComponentParts, ComponentSender, Component, Controller, JoinHandle, RelmWidgetExt};
+use gtk::prelude::{ApplicationExt, BoxExt, ButtonExt, GtkApplicationExt, GtkWindowExt, ListModelExt, OrientableExt, ListBoxRowExt, ObjectExt, SettingsExt, SettingsExtManual, ToVariant, WidgetExt};
+use adw::prelude::{PreferencesRowExt, ExpanderRowExt, MessageDialogExt};
+use relm4::{adw, gtk::{self, gio, glib}, ComponentController, ComponentParts, ComponentSender, Component, Controller, JoinHandle, RelmWidgetExt};
 use anyhow::{Result, Context};
-use version_compare::Version;
 
 mod media_player;
 mod fwupd;
@@ -20,19 +19,45 @@ pub enum Input {
     Disconnected,
     LatestFirmwareVersion(Option<String>),
     FlashAssetFromFile(PathBuf, AssetType),
-    FlashAssetFromUrl(String, AssetType),
+    FlashAssetFromRelease(gh::Asset, AssetType, Option<String>),
+    UpdateAllFromRelease {
+        firmware: gh::Asset,
+        resources: gh::Asset,
+        version: String,
+    },
     BatteryLevel(u8),
     HeartRate(u8),
     StepCount(u32),
     Alias(String),
     Address(String),
     FirmwareVersion(String),
+    ResourcesMarker(Option<bt::ResourcesMarker>),
+    RefreshResources,
+    OfferResources,
+    InstallResources(String),
+    Visible(bool),
+    FlashInterrupted(JournalEntry),
+    FlashInterruptedDetails,
+    FlashInterruptedDismiss,
+    CheckForUpdates,
+    RestartUpdateChecks,
+    ReviewFirmwareUpdate(String),
+    FirmwarePanelExpanded(bool),
+    WatchIndex(u32),
+    WatchSelected(u32),
 }
 
 #[derive(Debug)]
 pub enum Output {
     FlashAssetFromFile(PathBuf, AssetType),
-    FlashAssetFromUrl(String, AssetType),
+    FlashAssetFromRelease(gh::Asset, AssetType, Option<String>),
+    UpdateAllFromRelease {
+        firmware: gh::Asset,
+        resources: gh::Asset,
+        version: String,
+    },
+    WatchAlias(String),
+    FlashInterruptedDismissed,
 }
 
 pub struct Model {
@@ -46,6 +71,19 @@ pub struct Model {
     fw_version: Option<String>,
     fw_latest: Option<String>,
     fw_update_available: bool,
+    // Latest firmware version the user was notified about
+    fw_update_notified: Option<String>,
+    fw_panel_expanded: bool,
+    resources_loaded: bool,
+    resources_marker: Option<bt::ResourcesMarker>,
+    // Firmware version the matching resources should be offered for
+    resources_offer: Option<String>,
+    firmware_change_checked: bool,
+    is_visible: bool,
+    interrupted_flash: Option<JournalEntry>,
+    // - Watch switcher, the names are shared by all dashboards
+    watch_names: gtk::StringList,
+    watch_index: u32,
     // Components
     player_panel: Controller<media_player::Model>,
     notifications_panel: Controller<notifications::Model>,
@@ -53,6 +91,9 @@ pub struct Model {
     // Other
     infinitime: Option<Arc<bt::InfiniTime>>,
     data_task: Option<JoinHandle<()>>,
+    update_check_task: Option<JoinHandle<()>>,
+    update_check_handler: Option<glib::SignalHandlerId>,
+    settings: gio::Settings,
 }
 
 impl Model {
@@ -77,6 +118,8 @@ impl Model {
             .map(Input::FirmwareVersion)
             .context("Failed to read firmware version"));
 
+        Self::read_resources_marker(&infinitime, &sender).await;
+
         send_checked(infinitime.read_battery_level().await
             .map(Input::BatteryLevel)
             .context("Failed to read battery level"));
@@ -90,6 +133,17 @@ impl Model {
             .context("Failed to read step count"));
     }
 
+    async fn read_resources_marker(infinitime: &bt::InfiniTime, sender: &ComponentSender<Self>) {
+        // Not worth a toast, older firmware versions don't even have the filesystem service
+        match infinitime.read_resources_marker().await {
+            Ok(marker) => sender.input(Input::ResourcesMarker(marker)),
+            Err(error) => {
+                log::warn!("Failed to read resources marker: {}", error);
+                sender.input(Input::ResourcesMarker(None));
+            }
+        }
+    }
+
     async fn run_info_listener(infinitime: Arc<bt::InfiniTime>, sender: ComponentSender<Self>) {
         let log_error = |err| {
             log::error!("Failed to create data stream: {}", &err);
@@ -121,13 +175,111 @@ impl Model {
         }
     }
 
+    fn interrupted_flash_guidance(entry: &JournalEntry) -> String {
+        format!(
+            "{} update of the watch {} started at {} was interrupted{}.\n\n\
+            If the watch is unresponsive, hold its button for about 8 seconds to reboot it. \
+            Keep holding the button while the PineTime logo is shown: release it when the logo \
+            turns blue to revert to the previous firmware, or when it turns red to start the \
+            recovery firmware. Then connect the watch and flash the {} again.",
+            entry.asset,
+            entry.device_address,
+            entry.start_time().format("%Y-%m-%d %H:%M"),
+            entry.firmware_before.as_ref().map(|v| format!(" (firmware {v} was installed)")).unwrap_or_default(),
+            entry.asset.to_lowercase(),
+        )
+    }
+
+    fn resources_label(&self) -> String {
+        match (&self.resources_marker, &self.fw_version) {
+            _ if !self.resources_loaded => String::from("Loading..."),
+            (None, _) => String::from("Unknown"),
+            (Some(bt::ResourcesMarker { version: None, .. }), _) => String::from("Unknown version"),
+            (Some(marker), Some(firmware)) => {
+                let version = marker.version.as_deref().unwrap_or_default();
+                if marker.matches_firmware(firmware) == Some(true) {
+                    format!("{version} (matches firmware)")
+                } else {
+                    format!("{version} (firmware is {firmware})")
+                }
+            }
+            (Some(marker), None) => marker.version.clone().unwrap_or_default(),
+        }
+    }
+
+    /// Record the firmware version of the connected watch, and return
+    /// the previously recorded one if it was different
+    fn record_firmware_version(&self, address: &str, version: &str) -> Option<String> {
+        let mut versions = self.settings.get::<HashMap<String, String>>(ui::SETTING_FIRMWARE_VERSIONS);
+        let previous = versions.insert(address.to_string(), version.to_string());
+        if previous.as_deref() == Some(version) {
+            return None;
+        }
+        if let Err(error) = self.settings.set(ui::SETTING_FIRMWARE_VERSIONS, versions.to_variant()) {
+            log::error!("Failed to save firmware version: {}", error);
+        }
+        previous
+    }
+
+    fn check_firmware_changed(&mut self) {
+        if let (Some(address), Some(version)) = (&self.address, &self.fw_version) {
+            if let Some(previous) = self.record_firmware_version(address, version) {
+                log::info!("Firmware of {} changed: {} -> {}", address, previous, version);
+                if policy::uses_resources(version) {
+                    self.resources_offer = Some(version.clone());
+                }
+            }
+        }
+    }
+
+    fn resources_mismatch(&self) -> bool {
+        match (&self.resources_marker, &self.fw_version) {
+            (Some(marker), Some(firmware)) => marker.matches_firmware(firmware) == Some(false),
+            _ => false,
+        }
+    }
+
     fn check_fw_update_available(&mut self) {
-        let latest = self.fw_latest.as_ref()
-            .and_then(|v| Version::from(v));
-        let current = self.fw_version.as_ref()
-            .and_then(|v| Version::from(v));
-        if let (Some(latest), Some(current)) = (latest, current) {
-            self.fw_update_available = latest > current;
+        if let (Some(latest), Some(current)) = (&self.fw_latest, &self.fw_version) {
+            self.fw_update_available = policy::is_update_available(current, latest);
+            if self.fw_update_available && self.fw_update_notified.as_ref() != Some(latest) {
+                self.fw_update_notified = Some(latest.clone());
+                self.notify_fw_update(current, latest);
+            }
+        }
+    }
+
+    /// Post a desktop notification, unless the app window is in focus
+    /// and the update indicator is already visible
+    fn notify_fw_update(&self, current: &str, latest: &str) {
+        let app = relm4::main_application();
+        if self.is_visible && app.active_window().is_some_and(|w| w.is_visible() && w.is_active()) {
+            return;
+        }
+        let Some(address) = &self.address else {
+            return;
+        };
+        let watch = self.alias.as_deref().unwrap_or("the watch");
+        let target = (address.as_str(), latest).to_variant();
+        let notification = gio::Notification::new("Firmware update available");
+        notification.set_body(Some(&format!("InfiniTime {latest} is available for {watch}, it runs {current} now")));
+        notification.set_default_action_and_target_value("app.review-firmware-update", Some(&target));
+        notification.add_button_with_target_value("Review Update", "app.review-firmware-update", Some(&target));
+        app.send_notification(Some(&format!("firmware-update-{address}")), &notification);
+    }
+
+    /// Periodically refresh firmware releases while the watch is connected
+    fn start_update_checks(&mut self, sender: ComponentSender<Self>) {
+        self.update_check_task.take().map(|h| h.abort());
+        let hours = self.settings.uint(ui::SETTING_UPDATE_CHECK_INTERVAL);
+        if hours > 0 && self.infinitime.is_some() {
+            let period = Duration::from_secs(hours as u64 * 3600);
+            self.update_check_task = Some(relm4::spawn(async move {
+                loop {
+                    tokio::time::sleep(period).await;
+                    sender.input(Input::CheckForUpdates);
+                }
+            }));
         }
     }
 }
@@ -135,7 +287,7 @@ impl Model {
 #[relm4::component(pub)]
 impl Component for Model {
     type CommandOutput = ();
-    type Init = (adw::ApplicationWindow, gio::Settings);
+    type Init = (adw::ApplicationWindow, gio::Settings, gtk::StringList);
     type Input = Input;
     type Output = Output;
     type Widgets = Widgets;
@@ -160,8 +312,24 @@ impl Component for Model {
 
             adw::HeaderBar {
                 #[wrap(Some)]
-                set_title_widget = &gtk::Label {
-                    set_label: "Watchmate",
+                set_title_widget = &gtk::Box {
+                    gtk::Label {
+                        set_label: "Watchmate",
+                        #[watch]
+                        set_visible: model.watch_names.n_items() < 2,
+                    },
+
+                    gtk::DropDown {
+                        set_tooltip_text: Some("Connected watches"),
+                        set_model: Some(&model.watch_names),
+                        #[watch]
+                        set_selected: model.watch_index,
+                        #[watch]
+                        set_visible: model.watch_names.n_items() >= 2,
+                        connect_selected_notify[sender] => move |dropdown| {
+                            sender.input(Input::WatchSelected(dropdown.selected()));
+                        },
+                    },
                 },
 
                 pack_start = &gtk::Button {
@@ -178,6 +346,14 @@ impl Component for Model {
                 }
             },
 
+            adw::Banner {
+                set_title: "Previous firmware update was interrupted",
+                set_button_label: Some("Details"),
+                #[watch]
+                set_revealed: model.interrupted_flash.is_some(),
+                connect_button_clicked => Input::FlashInterruptedDetails,
+            },
+
             gtk::ScrolledWindow {
                 set_hscrollbar_policy: gtk::PolicyType::Never,
                 set_vexpand: true,
@@ -384,10 +560,48 @@ impl Component for Model {
                                     },
                                 },
 
+                                gtk::ListBoxRow {
+                                    set_selectable: false,
+                                    #[watch]
+                                    set_sensitive: model.resources_loaded,
+
+                                    gtk::Box {
+                                        set_orientation: gtk::Orientation::Horizontal,
+                                        set_margin_all: 12,
+                                        set_spacing: 10,
+
+                                        gtk::Label {
+                                            set_label: "Resources",
+                                            set_hexpand: true,
+                                            set_halign: gtk::Align::Start,
+                                        },
+
+                                        gtk::Label {
+                                            #[watch]
+                                            set_label: &model.resources_label(),
+                                            add_css_class: "dim-label",
+                                            set_hexpand: true,
+                                            set_halign: gtk::Align::End,
+                                        },
+
+                                        gtk::Image {
+                                            #[watch]
+                                            set_visible: model.resources_mismatch(),
+                                            set_tooltip_text: Some("Resources do not match the firmware, flash matching resources from the firmware menu"),
+                                            set_icon_name: Some("dialog-warning-symbolic"),
+                                        },
+                                    },
+                                },
+
                                 adw::ExpanderRow {
                                     set_title: "Firmware Version",
                                     #[watch]
                                     set_sensitive: model.fw_version.is_some(),
+                                    #[watch]
+                                    set_expanded: model.fw_panel_expanded,
+                                    connect_expanded_notify[sender] => move |row| {
+                                        sender.input(Input::FirmwarePanelExpanded(row.is_expanded()));
+                                    },
 
                                     add_suffix = &gtk::Box {
                                         set_spacing: 10,
@@ -443,25 +657,28 @@ impl Component for Model {
         }
     }
 
-    fn init((window, settings): Self::Init, root: Self::Root, sender: ComponentSender<Self>) -> ComponentParts<Self> {
+    fn init((window, settings, watch_names): Self::Init, root: Self::Root, sender: ComponentSender<Self>) -> ComponentParts<Self> {
 
         let player_panel = media_player::Model::builder()
             .launch(())
             .detach();
 
         let notifications_panel = notifications::Model::builder()
-            .launch(settings)
+            .launch(settings.clone())
             .detach();
 
         let firmware_panel = fwupd::Model::builder()
-            .launch(window)
+            .launch((window, settings.clone()))
             .forward(&sender.input_sender(), |message| match message {
                 fwupd::Output::LatestFirmwareVersion(f) => Input::LatestFirmwareVersion(f),
                 fwupd::Output::FlashAssetFromFile(f, t) => Input::FlashAssetFromFile(f, t),
-                fwupd::Output::FlashAssetFromUrl(u, t) => Input::FlashAssetFromUrl(u, t),
+                fwupd::Output::FlashAssetFromRelease(u, t, v) => Input::FlashAssetFromRelease(u, t, v),
+                fwupd::Output::UpdateAllFromRelease { firmware, resources, version } => {
+                    Input::UpdateAllFromRelease { firmware, resources, version }
+                }
             });
 
-        let model = Model {
+        let mut model = Model {
             battery_level: None,
             heart_rate: None,
             step_count: None,
@@ -470,15 +687,33 @@ impl Component for Model {
             fw_version: None,
             fw_latest: None,
             fw_update_available: false,
+            fw_update_notified: None,
+            fw_panel_expanded: false,
+            resources_loaded: false,
+            resources_marker: None,
+            resources_offer: None,
+            firmware_change_checked: false,
+            is_visible: false,
+            interrupted_flash: None,
+            watch_names,
+            watch_index: 0,
             player_panel,
             notifications_panel,
             firmware_panel,
             infinitime: None,
             data_task: None,
+            update_check_task: None,
+            update_check_handler: None,
+            settings,
         };
 
         let widgets = view_output!();
 
+        model.update_check_handler = Some(model.settings.connect_changed(
+            Some(ui::SETTING_UPDATE_CHECK_INTERVAL),
+            glib::clone!(#[strong] sender, move |_, _| sender.input(Input::RestartUpdateChecks)),
+        ));
+
         ComponentParts { model, widgets }
     }
 
@@ -493,6 +728,7 @@ impl Component for Model {
                 self.notifications_panel.emit(
                     notifications::Input::Device(Some(infinitime.clone()))
                 );
+                self.start_update_checks(sender.clone());
                 // Read data from the watch
                 self.data_task = Some(relm4::spawn(async move {
                     // Read initial values
@@ -509,9 +745,14 @@ impl Component for Model {
                 self.address = None;
                 self.fw_version = None;
                 self.fw_update_available = false;
+                self.resources_loaded = false;
+                self.resources_marker = None;
+                self.resources_offer = None;
+                self.firmware_change_checked = false;
                 self.infinitime = None;
-                // Abort data update task
+                // Abort data update and update check tasks
                 self.data_task.take().map(|h| h.abort());
+                self.update_check_task.take().map(|h| h.abort());
                 // Propagate to components
                 self.player_panel.emit(media_player::Input::Device(None));
                 self.notifications_panel.emit(notifications::Input::Device(None));
@@ -523,8 +764,11 @@ impl Component for Model {
             Input::FlashAssetFromFile(f, t) => {
                 sender.output(Output::FlashAssetFromFile(f, t)).unwrap();
             }
-            Input::FlashAssetFromUrl(u, t) => {
-                sender.output(Output::FlashAssetFromUrl(u, t)).unwrap();
+            Input::FlashAssetFromRelease(u, t, v) => {
+                sender.output(Output::FlashAssetFromRelease(u, t, v)).unwrap();
+            }
+            Input::UpdateAllFromRelease { firmware, resources, version } => {
+                sender.output(Output::UpdateAllFromRelease { firmware, resources, version }).unwrap();
             }
             // -- Watch data --
             Input::BatteryLevel(soc) => {
@@ -537,7 +781,8 @@ impl Component for Model {
                 self.step_count = Some(count);
             }
             Input::Alias(alias) => {
-                self.alias = Some(alias);
+                self.alias = Some(alias.clone());
+                sender.output(Output::WatchAlias(alias)).unwrap();
             }
             Input::Address(address) => {
                 self.address = Some(address);
@@ -549,6 +794,117 @@ impl Component for Model {
                 self.fw_version = Some(version);
                 self.check_fw_update_available();
             }
+            Input::ResourcesMarker(marker) => {
+                self.resources_loaded = true;
+                self.resources_marker = marker;
+                if !self.firmware_change_checked {
+                    self.firmware_change_checked = true;
+                    self.check_firmware_changed();
+                }
+                sender.input(Input::OfferResources);
+            }
+            Input::Visible(visible) => {
+                self.is_visible = visible;
+                sender.input(Input::OfferResources);
+            }
+            Input::OfferResources => {
+                // Wait until the dashboard is shown, so that the offer doesn't
+                // interfere with the firmware update in progress, and until
+                // the marker is read after the resources might have been updated
+                if !self.is_visible || !self.resources_loaded {
+                    return;
+                }
+                if let Some(version) = self.resources_offer.take() {
+                    let matches = self.resources_marker.as_ref()
+                        .and_then(|m| m.matches_firmware(&version))
+                        .unwrap_or(false);
+                    if !matches {
+                        let dialog = adw::MessageDialog::builder()
+                            .heading("Firmware Changed")
+                            .body(format!(
+                                "The watch now runs firmware {version}. Fonts and images may be missing \
+                                unless matching resources are installed. Install them now?"
+                            ))
+                            .modal(true)
+                            .build();
+                        dialog.set_transient_for(relm4::main_application().active_window().as_ref());
+                        dialog.add_responses(&[("later", "Later"), ("install", "Install Resources")]);
+                        dialog.set_response_appearance("install", adw::ResponseAppearance::Suggested);
+                        dialog.set_default_response(Some("install"));
+                        dialog.connect_response(Some("install"), move |_, _| {
+                            sender.input(Input::InstallResources(version.clone()));
+                        });
+                        dialog.present();
+                    }
+                }
+            }
+            Input::InstallResources(version) => {
+                self.firmware_panel.emit(fwupd::Input::FlashResourcesForVersion(version));
+            }
+            Input::RefreshResources => {
+                if let Some(infinitime) = self.infinitime.clone() {
+                    self.resources_loaded = false;
+                    relm4::spawn(async move {
+                        Self::read_resources_marker(&infinitime, &sender).await;
+                    });
+                }
+            }
+            Input::FlashInterrupted(entry) => {
+                self.interrupted_flash = Some(entry);
+            }
+            Input::FlashInterruptedDetails => {
+                if let Some(entry) = &self.interrupted_flash {
+                    let dialog = adw::MessageDialog::builder()
+                        .heading("Interrupted Update")
+                        .body(Self::interrupted_flash_guidance(entry))
+                        .modal(true)
+                        .build();
+                    dialog.set_transient_for(relm4::main_application().active_window().as_ref());
+                    dialog.add_response("close", "Close");
+                    dialog.add_response("dismiss", "Dismiss");
+                    dialog.connect_response(Some("dismiss"), move |_, _| {
+                        sender.input(Input::FlashInterruptedDismiss);
+                    });
+                    dialog.present();
+                }
+            }
+            Input::FlashInterruptedDismiss => {
+                if self.interrupted_flash.take().is_some() {
+                    sender.output(Output::FlashInterruptedDismissed).unwrap();
+                }
+            }
+            Input::CheckForUpdates => {
+                log::info!("Checking for firmware updates");
+                self.firmware_panel.emit(fwupd::Input::CheckLatestRelease);
+            }
+            Input::RestartUpdateChecks => {
+                self.start_update_checks(sender);
+            }
+            Input::ReviewFirmwareUpdate(version) => {
+                self.fw_panel_expanded = true;
+                self.firmware_panel.emit(fwupd::Input::SelectVersion(version));
+            }
+            Input::FirmwarePanelExpanded(expanded) => {
+                self.fw_panel_expanded = expanded;
+            }
+            Input::WatchIndex(index) => {
+                self.watch_index = index;
+            }
+            Input::WatchSelected(index) => {
+                // Only the shown dashboard switches the watch, the others just
+                // follow the changes of the shared list
+                if self.is_visible && index != self.watch_index && index != gtk::INVALID_LIST_POSITION {
+                    ui::BROKER.send(ui::Input::SelectWatch(index));
+                }
+            }
+        }
+    }
+
+    fn shutdown(&mut self, _widgets: &mut Self::Widgets, _output: relm4::Sender<Self::Output>) {
+        self.data_task.take().map(|h| h.abort());
+        self.update_check_task.take().map(|h| h.abort());
+        if let Some(handler) = self.update_check_handler.take() {
+            self.settings.disconnect(handler);
         }
     }
 }
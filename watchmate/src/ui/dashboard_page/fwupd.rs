@@ -1,31 +1,40 @@
 use super::AssetType;
 use crate::ui;
-use infinitime::gh;
+use infinitime::{bt, gh, journal::{JournalEntry, JournalStatus}, policy, tokio};
 
 use anyhow::Result;
 use relm4::{
     actions::{RelmAction, RelmActionGroup},
-    adw,
+    adw::{self, prelude::MessageDialogExt},
     gtk::{self, gio, glib, prelude::*},
     Component, ComponentController, ComponentParts, ComponentSender, Controller, JoinHandle,
     RelmWidgetExt,
 };
 use relm4_components::{alert::*, open_dialog::*, save_dialog::*};
 use std::path::PathBuf;
-use version_compare as vercomp;
 
 #[derive(Debug)]
 pub enum Input {
     None,
     CurrentFirmwareVersion(String),
+    FlashResourcesForVersion(String),
     RequestReleases,
+    CheckLatestRelease,
+    LoadMoreReleases,
+    SetReleaseSource(u32, String),
+    IncludePrereleases(bool),
+    RequireResources(bool),
     SelectedRelease(u32),
+    SelectVersion(String),
     ReleaseNotes,
+    ExpandReleaseNotes(bool),
+    NotesSinceInstalled(bool),
 
     // Firmware & Resources Download
     DownloadFirmware,
     DownloadResources,
     DownloadAsset(gh::Asset),
+    DownloadProgress(bt::ProgressEvent),
     CancelDownloading,
     FinishedDownloading(Result<Vec<u8>>),
     SaveFile(PathBuf),
@@ -39,19 +48,36 @@ pub enum Input {
     FlashResourcesFromReleaseClicked,
     FlashResourcesFromRelease,
     FlashResourcesFromFile(PathBuf),
+    OpenResourcesFolderDialog,
+    RemoveResourcesClicked,
+    RemoveResources,
+    UpdateAllFromReleaseClicked,
+    UpdateAllFromRelease,
+    FlashExpertAssetClicked(gh::AssetKind),
+    FlashExpertAsset,
+    ShowJournal,
 }
 
 #[derive(Debug)]
 pub enum Output {
     FlashAssetFromFile(PathBuf, AssetType),
-    FlashAssetFromUrl(String, AssetType),
+    FlashAssetFromRelease(gh::Asset, AssetType, Option<String>),
+    UpdateAllFromRelease {
+        firmware: gh::Asset,
+        resources: gh::Asset,
+        version: String,
+    },
     LatestFirmwareVersion(Option<String>),
 }
 
 #[derive(Debug)]
 pub enum CommandOutput {
-    FirmwareReleasesResponse(Result<Vec<gh::ReleaseInfo>>),
+    // Request generation, page and the response
+    FirmwareReleasesResponse(u32, u32, Result<gh::Cached<gh::ReleasePage>>),
+    // Request generation and the first page of releases
+    LatestReleaseResponse(u32, Result<gh::Cached<gh::ReleasePage>>),
     SaveFileResponse(Result<()>),
+    JournalResponse(Result<Vec<JournalEntry>>),
 }
 
 #[derive(Debug, Default, PartialEq)]
@@ -91,20 +117,38 @@ impl FirmwareReleasesState {
 pub struct Model {
     // UI state
     releases: FirmwareReleasesState,
+    source: gh::SourceConfig,
+    filter: gh::ReleaseFilter,
+    // Indices of the releases matching the filter, as listed in `tags`
+    filtered: Vec<usize>,
+    releases_page: u32,
+    // Incremented on every new list request, to ignore responses to the older ones
+    releases_generation: u32,
+    has_more_releases: bool,
+    loading_more_releases: bool,
     tags: Option<gtk::StringList>,
     selected_index: u32,
     resources_available: bool,
     current_version: String,
+    notes_expanded: bool,
+    notes_since_installed: bool,
+    // Pango markup of the release notes, rendered only when expanded
+    release_notes: String,
     // Firmware download state
     download_task: Option<JoinHandle<()>>,
+    download_progress: (u32, u32),
     download_content: Option<Vec<u8>>,
     download_filepath: Option<PathBuf>,
     // Components
     dfu_open_dialog: Controller<OpenDialog>,
     res_open_dialog: Controller<OpenDialog>,
+    res_dir_open_dialog: Controller<OpenDialog>,
     save_dialog: Controller<SaveDialog>,
-    firmware_downgrade_warning: Controller<Alert>,
     resource_mismatch_warning: Controller<Alert>,
+    resources_removal_warning: Controller<Alert>,
+    expert_warning: Controller<Alert>,
+    expert_asset_kind: Option<gh::AssetKind>,
+    settings: gio::Settings,
 }
 
 impl Model {
@@ -118,32 +162,222 @@ impl Model {
         }
     }
 
+    fn installed_version(&self) -> Option<&str> {
+        Some(self.current_version.as_str()).filter(|v| !v.is_empty())
+    }
+
+    fn firmware_plan(&self, release: &gh::ReleaseInfo) -> policy::UpdatePlan {
+        let plan = policy::plan_firmware_update(self.installed_version(), &release.tag);
+        if plan.needs_confirmation() {
+            log::warn!("Flashing firmware {}: {}", release.tag, plan.warning_text());
+        }
+        plan
+    }
+
+    /// Ask the user to confirm the firmware update that requires it
+    fn show_firmware_warning(plan: &policy::UpdatePlan, confirm: impl Fn() -> Input + 'static, sender: ComponentSender<Self>) {
+        let heading = match plan.kind {
+            policy::UpdateKind::Downgrade => "Warning: downgrading!",
+            _ => "Warning: unsupported firmware!",
+        };
+        let dialog = adw::MessageDialog::builder()
+            .heading(heading)
+            .body(format!("{}.\n\nAre you sure you want to proceed?", plan.warning_text()))
+            .modal(true)
+            .build();
+        dialog.set_transient_for(relm4::main_application().active_window().as_ref());
+        dialog.add_responses(&[("cancel", "Cancel"), ("proceed", "Proceed")]);
+        dialog.set_response_appearance("proceed", adw::ResponseAppearance::Destructive);
+        dialog.set_default_response(Some("cancel"));
+        dialog.set_close_response("cancel");
+        dialog.connect_response(None, move |_, response| {
+            if response == "proceed" {
+                sender.input(confirm());
+            }
+        });
+        dialog.present();
+    }
+
+    fn journal_row(entry: &JournalEntry) -> adw::ActionRow {
+        let title = match (&entry.firmware_before, &entry.firmware_after) {
+            (Some(before), Some(after)) => format!("{}: {} → {}", entry.asset, before, after),
+            (Some(before), None) => format!("{} (firmware {})", entry.asset, before),
+            _ => entry.asset.clone(),
+        };
+        let status = match &entry.status {
+            JournalStatus::InProgress => String::from("In progress"),
+            JournalStatus::Succeeded => String::from("Succeeded"),
+            JournalStatus::Failed(error) => format!("Failed: {error}"),
+            JournalStatus::Aborted => String::from("Aborted"),
+            JournalStatus::Interrupted => String::from("Interrupted"),
+        };
+        let subtitle = format!(
+            "{} · {} · {:.1} KB{} · {}\n{}",
+            entry.start_time().format("%Y-%m-%d %H:%M"),
+            entry.device_address,
+            entry.bytes as f32 / 1024.0,
+            entry.duration_secs.map(|d| format!(" in {d:.0} s")).unwrap_or_default(),
+            status,
+            entry.source,
+        );
+        adw::ActionRow::builder()
+            .title(title)
+            .subtitle(subtitle)
+            .subtitle_selectable(true)
+            .build()
+    }
+
+    fn show_journal(entries: Vec<JournalEntry>) {
+        let list = gtk::ListBox::new();
+        list.set_selection_mode(gtk::SelectionMode::None);
+        list.add_css_class("boxed-list");
+        for entry in entries.iter().rev() {
+            list.append(&Self::journal_row(entry));
+        }
+        if entries.is_empty() {
+            list.append(&adw::ActionRow::builder().title("Nothing has been flashed yet").build());
+        }
+
+        let scroll_view = gtk::ScrolledWindow::builder()
+            .hscrollbar_policy(gtk::PolicyType::Never)
+            .min_content_height(360)
+            .child(&list)
+            .build();
+        let dialog = adw::MessageDialog::builder()
+            .heading("Flash History")
+            .extra_child(&scroll_view)
+            .modal(true)
+            .build();
+        dialog.set_transient_for(relm4::main_application().active_window().as_ref());
+        dialog.add_response("close", "Close");
+        dialog.present();
+    }
+
     fn selected_release_info(&self) -> Option<&gh::ReleaseInfo> {
         if let FirmwareReleasesState::Some(releases) = &self.releases {
-            releases.get(self.selected_index as usize)
+            self.filtered.get(self.selected_index as usize).and_then(|&i| releases.get(i))
         } else {
             None
         }
     }
+
+    fn request_releases(&self, page: u32, sender: &ComponentSender<Self>) {
+        let generation = self.releases_generation;
+        match ui::release_source(&self.settings) {
+            Ok(source) => {
+                sender.oneshot_command(async move {
+                    CommandOutput::FirmwareReleasesResponse(generation, page, source.list_releases(page).await)
+                });
+            }
+            Err(error) => {
+                sender.oneshot_command(async move {
+                    CommandOutput::FirmwareReleasesResponse(generation, page, Err(error))
+                });
+            }
+        }
+    }
+
+    /// Add releases to the list, keeping the selection
+    fn append_releases(&mut self, new_releases: Vec<gh::ReleaseInfo>) {
+        if let FirmwareReleasesState::Some(releases) = &mut self.releases {
+            let tags = self.tags.get_or_insert_with(|| gtk::StringList::new(&[]));
+            for release in new_releases {
+                if self.filter.matches(&release) {
+                    self.filtered.push(releases.len());
+                    tags.append(&release.tag);
+                }
+                releases.push(release);
+            }
+        }
+        self.refresh_release_notes();
+    }
+
+    /// Rebuild the list after the filter is changed
+    fn apply_filter(&mut self) {
+        if let FirmwareReleasesState::Some(releases) = &self.releases {
+            self.filtered = (0..releases.len()).filter(|&i| self.filter.matches(&releases[i])).collect();
+            let tags = self.filtered.iter().map(|&i| releases[i].tag.as_str()).collect::<Vec<_>>();
+            self.tags = Some(gtk::StringList::new(&tags));
+            self.selected_index = 0;
+        }
+        self.refresh_release_notes();
+    }
+
+    /// Latest release of the chosen channel, regardless of the other filters
+    fn latest_version(&self) -> Option<String> {
+        self.latest_version_of(self.releases.as_option()?)
+    }
+
+    fn latest_version_of(&self, releases: &[gh::ReleaseInfo]) -> Option<String> {
+        releases.iter()
+            .find(|r| !r.draft && (self.filter.include_prereleases || !r.prerelease))
+            .map(|r| r.tag.clone())
+    }
+
+    /// Release notes of the selected release, or of every release since
+    /// the installed one, in Markdown
+    fn release_notes_markdown(&self) -> Option<String> {
+        let release = self.selected_release_info()?;
+        let installed = self.installed_version().filter(|_| self.notes_since_installed);
+        let Some(installed) = installed else {
+            return Some(gh::combined_notes(&[release]));
+        };
+
+        let releases = self.releases.as_option()?;
+        let between = gh::releases_between(releases, installed, &release.tag);
+        if between.is_empty() {
+            return Some(format!("*{} is not newer than the installed firmware {installed}*", release.tag));
+        }
+        let mut notes = gh::combined_notes(&between);
+        // Releases are listed newest first, so older ones might be not loaded yet
+        let oldest_loaded = releases.last().map(|r| r.tag.as_str()).unwrap_or_default();
+        if self.has_more_releases && policy::compare(oldest_loaded, installed) == Some(std::cmp::Ordering::Greater) {
+            notes.push_str(&format!("\n\n*Releases older than {oldest_loaded} are not loaded yet*"));
+        }
+        Some(notes)
+    }
+
+    fn refresh_release_notes(&mut self) {
+        self.release_notes = match self.notes_expanded {
+            true => self.release_notes_markdown().map(|md| ui::markdown::to_pango(&md)).unwrap_or_default(),
+            false => String::new(),
+        };
+    }
+
+    /// Fetch the next page when the last listed release is selected,
+    /// or when nothing matches the filter
+    fn load_more_if_needed(&mut self, sender: &ComponentSender<Self>) {
+        let last_selected = self.selected_index as usize + 1 >= self.filtered.len();
+        if self.has_more_releases && !self.loading_more_releases && last_selected {
+            sender.input(Input::LoadMoreReleases);
+        }
+    }
 }
 
 #[relm4::component(pub)]
 impl Component for Model {
     type CommandOutput = CommandOutput;
-    type Init = adw::ApplicationWindow;
+    type Init = (adw::ApplicationWindow, gio::Settings);
     type Input = Input;
     type Output = Output;
     type Widgets = Widgets;
 
     menu! {
         extra_menu: {
+            "Update Firmware & Resources" => UpdateAllAction,
             "Flash Resources" => FlashResourcesAction,
+            "Flash Resources from Folder" => FlashResourcesFolderAction,
+            "Remove Resources" => RemoveResourcesAction,
             section! {
                 "Download Firmware" => DownloadFirmwareAction,
                 "Download Resources" => DownloadResourcesAction,
             },
             section! {
                 "Release Notes" => ReleaseNotesAction,
+                "Flash History" => FlashHistoryAction,
+            },
+            section! {
+                "Flash Recovery Loader" => FlashRecoveryLoaderAction,
             },
         }
     }
@@ -156,19 +390,76 @@ impl Component for Model {
             set_spacing: 10,
 
             gtk::Label {
-                set_label: "Update from GitHub release",
+                set_label: "Update from release",
                 set_halign: gtk::Align::Start,
             },
 
+            gtk::Box {
+                set_spacing: 10,
+
+                #[name = "source_kind"]
+                gtk::DropDown::from_strings(&gh::SourceKind::ALL.map(|k| k.label())) {
+                    set_tooltip_text: Some("Release source"),
+                    set_selected: gh::SourceKind::ALL.iter()
+                        .position(|k| *k == model.source.kind)
+                        .unwrap_or_default() as u32,
+                    connect_selected_notify[sender, source_location] => move |wgt| {
+                        sender.input(Input::SetReleaseSource(wgt.selected(), source_location.text().to_string()));
+                    }
+                },
+
+                #[name = "source_location"]
+                gtk::Entry {
+                    set_hexpand: true,
+                    set_text: &model.source.location,
+                    #[watch]
+                    set_placeholder_text: Some(model.source.kind.placeholder()),
+                    set_tooltip_text: Some("Repository, repository URL or folder path. Press Enter to apply"),
+                    connect_activate[sender, source_kind] => move |wgt| {
+                        sender.input(Input::SetReleaseSource(source_kind.selected(), wgt.text().to_string()));
+                    }
+                },
+
+                gtk::MenuButton {
+                    set_tooltip_text: Some("Release filters"),
+                    set_icon_name: "view-more-symbolic",
+                    #[wrap(Some)]
+                    set_popover = &gtk::Popover {
+                        gtk::Box {
+                            set_orientation: gtk::Orientation::Vertical,
+                            set_spacing: 6,
+
+                            gtk::CheckButton {
+                                set_label: Some("Include prereleases"),
+                                set_active: model.filter.include_prereleases,
+                                connect_toggled[sender] => move |wgt| {
+                                    sender.input(Input::IncludePrereleases(wgt.is_active()));
+                                }
+                            },
+
+                            gtk::CheckButton {
+                                set_label: Some("Only releases with resources"),
+                                set_active: model.filter.require_resources,
+                                connect_toggled[sender] => move |wgt| {
+                                    sender.input(Input::RequireResources(wgt.is_active()));
+                                }
+                            },
+                        },
+                    },
+                },
+            },
+
             gtk::Box {
                 set_spacing: 10,
 
                 gtk::DropDown {
                     set_hexpand: true,
                     #[watch]
-                    set_visible: model.releases.is_some(),
+                    set_visible: model.releases.is_some() && !model.filtered.is_empty(),
                     #[watch]
                     set_model: model.tags.as_ref(),
+                    #[watch]
+                    set_selected: model.selected_index,
                     #[wrap(Some)]
                     set_factory = &gtk::SignalListItemFactory {
                         connect_setup => |_, item| {
@@ -191,7 +482,7 @@ impl Component for Model {
 
                 adw::SplitButton {
                     #[watch]
-                    set_visible: model.releases.is_some(),
+                    set_visible: model.releases.is_some() && !model.filtered.is_empty(),
                     #[watch]
                     set_sensitive: !model.download_task.is_some(),
                     set_label: "Flash",
@@ -203,17 +494,18 @@ impl Component for Model {
                 gtk::Label {
                     set_hexpand: true,
                     #[watch]
-                    set_visible: !model.releases.is_some(),
+                    set_visible: !model.releases.is_some() || model.filtered.is_empty(),
                     #[watch]
                     set_label: match &model.releases {
                         FirmwareReleasesState::None => "Firmware releases are not loaded",
                         FirmwareReleasesState::Requested => "Getting firmware releases...",
                         FirmwareReleasesState::Error => "Failed to get firmware releases",
-                        _ => "",
+                        FirmwareReleasesState::Some(_) if model.loading_more_releases => "Getting more releases...",
+                        FirmwareReleasesState::Some(_) => "No releases match the filters",
                     },
                 },
 
-                if model.download_task.is_some() || model.releases.is_requested() {
+                if model.releases.is_requested() || model.loading_more_releases {
                     gtk::Spinner {
                         set_spinning: true,
                     }
@@ -226,6 +518,80 @@ impl Component for Model {
                 }
             },
 
+            gtk::Expander {
+                set_label: Some("Release notes"),
+                #[watch]
+                set_visible: model.selected_release_info().is_some(),
+                #[watch]
+                set_expanded: model.notes_expanded,
+                connect_expanded_notify[sender] => move |wgt| {
+                    sender.input(Input::ExpandReleaseNotes(wgt.is_expanded()));
+                },
+
+                #[wrap(Some)]
+                set_child = &gtk::Box {
+                    set_orientation: gtk::Orientation::Vertical,
+                    set_margin_top: 6,
+                    set_spacing: 6,
+
+                    gtk::Box {
+                        set_spacing: 10,
+
+                        gtk::CheckButton {
+                            set_label: Some("Changes since installed version"),
+                            set_hexpand: true,
+                            #[watch]
+                            set_active: model.notes_since_installed,
+                            #[watch]
+                            set_sensitive: model.installed_version().is_some(),
+                            connect_toggled[sender] => move |wgt| {
+                                sender.input(Input::NotesSinceInstalled(wgt.is_active()));
+                            }
+                        },
+
+                        gtk::LinkButton {
+                            set_label: "Open in Browser",
+                            #[watch]
+                            set_uri: model.selected_release_info().map(|r| r.url.as_str()).unwrap_or_default(),
+                        },
+                    },
+
+                    gtk::ScrolledWindow {
+                        set_hscrollbar_policy: gtk::PolicyType::Never,
+                        set_propagate_natural_height: true,
+                        set_max_content_height: 360,
+
+                        gtk::Label {
+                            set_halign: gtk::Align::Fill,
+                            set_valign: gtk::Align::Start,
+                            set_xalign: 0.0,
+                            set_wrap: true,
+                            set_wrap_mode: gtk::pango::WrapMode::WordChar,
+                            set_selectable: true,
+                            set_use_markup: true,
+                            #[watch]
+                            set_label: &model.release_notes,
+                        },
+                    },
+                },
+            },
+
+            gtk::ProgressBar {
+                #[watch]
+                set_visible: model.download_task.is_some(),
+                set_show_text: true,
+                #[watch]
+                set_fraction: match model.download_progress {
+                    (_, 0) => 0.0,
+                    (current, total) => current as f64 / total as f64,
+                },
+                #[watch]
+                set_text: Some(&format!(
+                    "Downloading: {:.1} KB / {:.1} KB",
+                    model.download_progress.0 as f32 / 1024.0, model.download_progress.1 as f32 / 1024.0
+                )),
+            },
+
             gtk::Separator {
                 set_orientation: gtk::Orientation::Horizontal,
             },
@@ -255,18 +621,23 @@ impl Component for Model {
     }
 
     fn init(
-        main_window: Self::Init,
+        (main_window, settings): Self::Init,
         root: Self::Root,
         sender: ComponentSender<Self>,
     ) -> ComponentParts<Self> {
         let file_filter = gtk::FileFilter::new();
         file_filter.add_pattern("*.zip");
 
+        // Raw mcuboot images are accepted too, the init packet is generated on the fly
+        let dfu_file_filter = gtk::FileFilter::new();
+        dfu_file_filter.add_pattern("*.zip");
+        dfu_file_filter.add_pattern("*.bin");
+
         let dfu_open_dialog = OpenDialog::builder()
             .transient_for_native(&main_window)
             .launch(OpenDialogSettings {
                 create_folders: false,
-                filters: vec![file_filter.clone()],
+                filters: vec![dfu_file_filter],
                 ..Default::default()
             })
             .forward(&sender.input_sender(), |message| match message {
@@ -286,6 +657,19 @@ impl Component for Model {
                 OpenDialogResponse::Cancel => Input::None,
             });
 
+        // Resources directory containing resources.json, e.g. InfiniTime build output
+        let res_dir_open_dialog = OpenDialog::builder()
+            .transient_for_native(&main_window)
+            .launch(OpenDialogSettings {
+                folder_mode: true,
+                create_folders: false,
+                ..Default::default()
+            })
+            .forward(&sender.input_sender(), |message| match message {
+                OpenDialogResponse::Accept(path) => Input::FlashResourcesFromFile(path),
+                OpenDialogResponse::Cancel => Input::None,
+            });
+
         let save_dialog = SaveDialog::builder()
             .transient_for_native(&main_window)
             .launch(SaveDialogSettings::default())
@@ -294,12 +678,12 @@ impl Component for Model {
                 SaveDialogResponse::Cancel => Input::CancelDownloading,
             });
 
-        let firmware_downgrade_warning = Alert::builder()
+        let resource_mismatch_warning = Alert::builder()
             .transient_for(&main_window)
             .launch(AlertSettings {
-                text: Some(String::from("Warning: downgrading!")),
+                text: Some(String::from("Warning: version mismatch!")),
                 secondary_text: Some(String::from(
-                    "Are you sure you want to downgrade the firmware?",
+                    "Selected resources do not match the current firmware version",
                 )),
                 confirm_label: Some(String::from("Proceed")),
                 cancel_label: Some(String::from("Cancel")),
@@ -309,17 +693,41 @@ impl Component for Model {
                 extra_child: None,
             })
             .forward(sender.input_sender(), |message| match message {
-                AlertResponse::Confirm => Input::FlashFirmwareFromRelease,
+                AlertResponse::Confirm => Input::FlashResourcesFromRelease,
                 AlertResponse::Cancel => Input::None,
                 AlertResponse::Option => Input::None,
             });
 
-        let resource_mismatch_warning = Alert::builder()
+        let resources_removal_warning = Alert::builder()
             .transient_for(&main_window)
             .launch(AlertSettings {
-                text: Some(String::from("Warning: version mismatch!")),
+                text: Some(String::from("Remove resources?")),
                 secondary_text: Some(String::from(
-                    "Selected resources do not match the current firmware version",
+                    "All files installed by the resources of the selected release \
+                    will be deleted from the watch. Fonts and images that depend \
+                    on them will be missing until resources are installed again.",
+                )),
+                confirm_label: Some(String::from("Remove")),
+                cancel_label: Some(String::from("Cancel")),
+                option_label: None,
+                is_modal: true,
+                destructive_accept: true,
+                extra_child: None,
+            })
+            .forward(sender.input_sender(), |message| match message {
+                AlertResponse::Confirm => Input::RemoveResources,
+                AlertResponse::Cancel => Input::None,
+                AlertResponse::Option => Input::None,
+            });
+
+        let expert_warning = Alert::builder()
+            .transient_for(&main_window)
+            .launch(AlertSettings {
+                text: Some(String::from("Warning: expert mode!")),
+                secondary_text: Some(String::from(
+                    "This replaces system components of the watch. If interrupted, \
+                    the watch may need to be recovered via SWD. Proceed only if you \
+                    know what you are doing.",
                 )),
                 confirm_label: Some(String::from("Proceed")),
                 cancel_label: Some(String::from("Cancel")),
@@ -329,25 +737,43 @@ impl Component for Model {
                 extra_child: None,
             })
             .forward(sender.input_sender(), |message| match message {
-                AlertResponse::Confirm => Input::FlashResourcesFromRelease,
+                AlertResponse::Confirm => Input::FlashExpertAsset,
                 AlertResponse::Cancel => Input::None,
                 AlertResponse::Option => Input::None,
             });
 
         let model = Model {
             releases: FirmwareReleasesState::default(),
+            source: ui::release_source_config(&settings),
+            filter: gh::ReleaseFilter {
+                include_prereleases: settings.boolean(ui::SETTING_INCLUDE_PRERELEASES),
+                require_resources: settings.boolean(ui::SETTING_REQUIRE_RESOURCES),
+            },
+            filtered: Vec::new(),
+            releases_page: 0,
+            releases_generation: 0,
+            has_more_releases: false,
+            loading_more_releases: false,
             tags: None,
             selected_index: 0,
             resources_available: false,
             current_version: String::new(),
+            notes_expanded: false,
+            notes_since_installed: false,
+            release_notes: String::new(),
             download_task: None,
+            download_progress: (0, 0),
             download_content: None,
             download_filepath: None,
             dfu_open_dialog,
             res_open_dialog,
+            res_dir_open_dialog,
             save_dialog,
-            firmware_downgrade_warning,
             resource_mismatch_warning,
+            resources_removal_warning,
+            expert_warning,
+            expert_asset_kind: None,
+            settings,
         };
 
         let widgets = view_output!();
@@ -359,12 +785,30 @@ impl Component for Model {
                 }
             ),
         ));
+        group.add_action(RelmAction::<UpdateAllAction>::new_stateless(
+            glib::clone!(#[strong] sender, move |_| {
+                    sender.input(Input::UpdateAllFromReleaseClicked);
+                }
+            ),
+        ));
         group.add_action(RelmAction::<FlashResourcesAction>::new_stateless(
             glib::clone!(#[strong] sender, move |_| {
                     sender.input(Input::FlashResourcesFromReleaseClicked);
                 }
             ),
         ));
+        group.add_action(RelmAction::<FlashResourcesFolderAction>::new_stateless(
+            glib::clone!(#[strong] sender, move |_| {
+                    sender.input(Input::OpenResourcesFolderDialog);
+                }
+            ),
+        ));
+        group.add_action(RelmAction::<RemoveResourcesAction>::new_stateless(
+            glib::clone!(#[strong] sender, move |_| {
+                    sender.input(Input::RemoveResourcesClicked);
+                }
+            ),
+        ));
         group.add_action(RelmAction::<DownloadFirmwareAction>::new_stateless(
             glib::clone!(#[strong] sender, move |_| {
                     sender.input(Input::DownloadFirmware);
@@ -377,6 +821,18 @@ impl Component for Model {
                 }
             ),
         ));
+        group.add_action(RelmAction::<FlashRecoveryLoaderAction>::new_stateless(
+            glib::clone!(#[strong] sender, move |_| {
+                    sender.input(Input::FlashExpertAssetClicked(gh::AssetKind::RecoveryLoaderDfu));
+                }
+            ),
+        ));
+        group.add_action(RelmAction::<FlashHistoryAction>::new_stateless(
+            glib::clone!(#[strong] sender, move |_| {
+                    sender.input(Input::ShowJournal);
+                }
+            ),
+        ));
         group.add_action(RelmAction::<ReleaseNotesAction>::new_stateless(
             glib::clone!(#[strong] sender, move |_| {
                     sender.input(Input::ReleaseNotes);
@@ -394,28 +850,122 @@ impl Component for Model {
             Input::None => {}
             Input::CurrentFirmwareVersion(version) => {
                 self.current_version = version;
+                self.refresh_release_notes();
+            }
+            Input::FlashResourcesForVersion(version) => {
+                let asset = self.releases.as_option()
+                    .and_then(|releases| releases.iter().find(|r| policy::versions_match(&r.tag, &version)))
+                    .and_then(|release| Some((release, release.get_resources_asset()?)));
+                match asset {
+                    Some((release, asset)) => {
+                        let asset = asset.clone();
+                        let version = Some(release.tag.clone());
+                        sender.output(Output::FlashAssetFromRelease(asset, AssetType::Resources, version)).unwrap();
+                    }
+                    None => {
+                        ui::BROKER.send(ui::Input::Toast(format!("Resources for firmware {version} not found")));
+                    }
+                }
             }
             Input::RequestReleases => {
+                self.releases_generation = self.releases_generation.wrapping_add(1);
                 self.releases = FirmwareReleasesState::Requested;
-                sender.oneshot_command(async move {
-                    CommandOutput::FirmwareReleasesResponse(gh::list_releases().await)
-                });
+                self.loading_more_releases = false;
+                self.request_releases(1, &sender);
+            }
+            Input::CheckLatestRelease => {
+                // Leave the list, selection and notes alone, only the latest version matters
+                let generation = self.releases_generation;
+                match ui::release_source(&self.settings) {
+                    Ok(source) => {
+                        sender.oneshot_command(async move {
+                            CommandOutput::LatestReleaseResponse(generation, source.list_releases(1).await)
+                        });
+                    }
+                    Err(error) => log::warn!("Failed to check for firmware updates: {error}"),
+                }
+            }
+            Input::LoadMoreReleases => {
+                if self.releases.is_some() && self.has_more_releases && !self.loading_more_releases {
+                    self.loading_more_releases = true;
+                    self.request_releases(self.releases_page + 1, &sender);
+                }
+            }
+            Input::IncludePrereleases(include) => {
+                self.filter.include_prereleases = include;
+                if let Err(error) = self.settings.set_boolean(ui::SETTING_INCLUDE_PRERELEASES, include) {
+                    log::error!("Failed to save release filter: {error}");
+                }
+                self.apply_filter();
+                sender.output(Output::LatestFirmwareVersion(self.latest_version())).unwrap();
+                self.load_more_if_needed(&sender);
+            }
+            Input::RequireResources(require) => {
+                self.filter.require_resources = require;
+                if let Err(error) = self.settings.set_boolean(ui::SETTING_REQUIRE_RESOURCES, require) {
+                    log::error!("Failed to save release filter: {error}");
+                }
+                self.apply_filter();
+                self.load_more_if_needed(&sender);
+            }
+            Input::SetReleaseSource(index, location) => {
+                let kind = gh::SourceKind::ALL.get(index as usize).copied().unwrap_or(gh::SourceKind::GitHub);
+                let location = match location.trim() {
+                    "" if kind == gh::SourceKind::GitHub => gh::INFINITIME_REPO.to_string(),
+                    location => location.to_string(),
+                };
+                let source = gh::SourceConfig { kind, location };
+                if source != self.source {
+                    let empty = source.location.is_empty();
+                    if let Err(error) = self.settings.set_string(ui::SETTING_RELEASE_SOURCE, &source.to_string()) {
+                        log::error!("Failed to save release source: {error}");
+                    }
+                    self.source = source;
+                    if empty {
+                        // Wait until the location is entered
+                        self.releases_generation = self.releases_generation.wrapping_add(1);
+                        self.releases = FirmwareReleasesState::None;
+                        self.tags = None;
+                        sender.output(Output::LatestFirmwareVersion(None)).unwrap();
+                    } else {
+                        sender.input(Input::RequestReleases);
+                    }
+                }
             }
             Input::SelectedRelease(index) => {
                 self.selected_index = index;
                 if let Some(release) = self.selected_release_info() {
                     self.resources_available = release.get_resources_asset().is_some();
                 }
+                self.refresh_release_notes();
+                self.load_more_if_needed(&sender);
+            }
+            Input::SelectVersion(version) => {
+                let releases = self.releases.as_option().map(Vec::as_slice).unwrap_or_default();
+                match self.filtered.iter().position(|&i| releases[i].tag == version) {
+                    Some(index) => {
+                        sender.input(Input::SelectedRelease(index as u32));
+                        sender.input(Input::NotesSinceInstalled(true));
+                        sender.input(Input::ExpandReleaseNotes(true));
+                    }
+                    None => {
+                        ui::BROKER.send(ui::Input::Toast(format!("Firmware release {version} is not listed")));
+                    }
+                }
             }
             Input::ReleaseNotes => {
-                if let Some(release) = self.selected_release_info() {
-                    gtk::UriLauncher::new(&release.url).launch(
-                        adw::ApplicationWindow::NONE,
-                        gio::Cancellable::NONE,
-                        |_| (),
-                    );
+                sender.input(Input::ExpandReleaseNotes(true));
+            }
+            Input::ExpandReleaseNotes(expanded) => {
+                if expanded != self.notes_expanded {
+                    self.notes_expanded = expanded;
+                    self.refresh_release_notes();
                 }
             }
+            Input::NotesSinceInstalled(enabled) => {
+                self.notes_since_installed = enabled;
+                self.refresh_release_notes();
+            }
             Input::DownloadFirmware => {
                 if let Some(release) = self.selected_release_info() {
                     match release.get_dfu_asset() {
@@ -441,16 +991,44 @@ impl Component for Model {
                 }
             }
             Input::DownloadAsset(asset) => {
-                let url = asset.url;
-                let filename = asset.name;
+                let filename = asset.name.clone();
+                let source = ui::release_source(&self.settings);
+                // The downloaded asset belongs to the selected release
+                let tag = self.selected_release_info().map(|r| r.tag.clone());
+                let keep = self.settings.uint(ui::SETTING_LIBRARY_KEEP) as usize;
+                self.download_progress = (0, asset.size);
                 let task = relm4::spawn(async move {
-                    sender.input(Input::FinishedDownloading(
-                        gh::download_content(url.as_str()).await,
-                    ))
+                    let library = ui::firmware_library();
+                    let (progress_tx, mut progress_rx) = bt::progress_channel(32);
+                    let sender_ = sender.clone();
+                    let progress_updater = async move {
+                        while let Some(event) = progress_rx.recv().await {
+                            sender_.input(Input::DownloadProgress(event));
+                        }
+                    };
+                    let result = match (source, &tag) {
+                        (Ok(source), Some(tag)) => {
+                            let download = library.download(source.as_ref(), tag, &asset, Some(progress_tx));
+                            tokio::join!(progress_updater, download).1
+                        }
+                        (Ok(source), None) => tokio::join!(progress_updater, source.download(&asset, Some(progress_tx))).1,
+                        (Err(error), _) => Err(error),
+                    };
+                    if keep > 0 && tag.is_some() {
+                        if let Err(error) = library.prune(keep, tag).await {
+                            log::warn!("Failed to prune firmware library: {}", error);
+                        }
+                    }
+                    sender.input(Input::FinishedDownloading(result))
                 });
                 self.download_task = Some(task);
                 self.save_dialog.emit(SaveDialogMsg::SaveAs(filename));
             }
+            Input::DownloadProgress(event) => {
+                if let bt::ProgressEvent::Numbers { current, total } = event {
+                    self.download_progress = (current, total);
+                }
+            }
             Input::CancelDownloading => {
                 self.download_task.take().map(|h| h.abort());
                 self.download_content = None;
@@ -465,8 +1043,8 @@ impl Component for Model {
                     }
                     Err(error) => {
                         self.download_content = None;
-                        log::error!("Failed to download DFU file: {}", error);
-                        ui::BROKER.send(ui::Input::ToastStatic("Failed to download DFU file"));
+                        log::error!("Failed to download asset: {}", error);
+                        ui::BROKER.send(ui::Input::Toast(format!("Failed to download: {error}")));
                     }
                 }
             }
@@ -480,18 +1058,14 @@ impl Component for Model {
             Input::OpenResourcesFileDialog => {
                 self.res_open_dialog.emit(OpenDialogMsg::Open);
             }
+            Input::OpenResourcesFolderDialog => {
+                self.res_dir_open_dialog.emit(OpenDialogMsg::Open);
+            }
             Input::FlashFirmwareFromReleaseClicked => {
                 if let Some(release) = self.selected_release_info() {
-                    let mut manifest = vercomp::Manifest::default();
-                    manifest.ignore_text = true;
-                    let selected = vercomp::Version::from_manifest(&release.tag, &manifest);
-                    let current = vercomp::Version::from_manifest(&self.current_version, &manifest);
-                    if let (Some(selected), Some(current)) = (selected, current) {
-                        if selected < current {
-                            self.firmware_downgrade_warning.emit(AlertMsg::Show);
-                        } else {
-                            sender.input(Input::FlashFirmwareFromRelease);
-                        }
+                    let plan = self.firmware_plan(release);
+                    if plan.needs_confirmation() {
+                        Self::show_firmware_warning(&plan, || Input::FlashFirmwareFromRelease, sender);
                     } else {
                         sender.input(Input::FlashFirmwareFromRelease);
                     }
@@ -501,9 +1075,10 @@ impl Component for Model {
                 if let Some(release) = self.selected_release_info() {
                     match release.get_dfu_asset() {
                         Some(asset) => {
-                            let url = asset.url.clone();
+                            let asset = asset.clone();
                             let atype = AssetType::Firmware;
-                            sender.output(Output::FlashAssetFromUrl(url, atype)).unwrap();
+                            let version = Some(release.tag.clone());
+                            sender.output(Output::FlashAssetFromRelease(asset, atype, version)).unwrap();
                         }
                         None => {
                             ui::BROKER.send(ui::Input::ToastStatic("DFU file not found"));
@@ -517,16 +1092,9 @@ impl Component for Model {
             }
             Input::FlashResourcesFromReleaseClicked => {
                 if let Some(release) = self.selected_release_info() {
-                    let mut manifest = vercomp::Manifest::default();
-                    manifest.ignore_text = true;
-                    let selected = vercomp::Version::from_manifest(&release.tag, &manifest);
-                    let current = vercomp::Version::from_manifest(&self.current_version, &manifest);
-                    if let (Some(selected), Some(current)) = (selected, current) {
-                        if selected != current {
-                            self.resource_mismatch_warning.emit(AlertMsg::Show);
-                        } else {
-                            sender.input(Input::FlashResourcesFromRelease);
-                        }
+                    let plan = policy::plan_resources_install(self.installed_version(), &release.tag);
+                    if plan.needs_confirmation() {
+                        self.resource_mismatch_warning.emit(AlertMsg::Show);
                     } else {
                         sender.input(Input::FlashResourcesFromRelease);
                     }
@@ -536,9 +1104,10 @@ impl Component for Model {
                 if let Some(release) = self.selected_release_info() {
                     match release.get_resources_asset() {
                         Some(asset) => {
-                            let url = asset.url.clone();
+                            let asset = asset.clone();
                             let atype = AssetType::Resources;
-                            sender.output(Output::FlashAssetFromUrl(url, atype)).unwrap();
+                            let version = Some(release.tag.clone());
+                            sender.output(Output::FlashAssetFromRelease(asset, atype, version)).unwrap();
                         }
                         None => {
                             ui::BROKER.send(ui::Input::ToastStatic("Resources asset not found"));
@@ -550,6 +1119,91 @@ impl Component for Model {
                 let atype = AssetType::Resources;
                 sender.output(Output::FlashAssetFromFile(filepath, atype)).unwrap();
             }
+            Input::RemoveResourcesClicked => {
+                if let Some(release) = self.selected_release_info() {
+                    if release.get_resources_asset().is_some() {
+                        self.resources_removal_warning.emit(AlertMsg::Show);
+                    } else {
+                        ui::BROKER.send(ui::Input::ToastStatic("Resources asset not found"));
+                    }
+                }
+            }
+            Input::RemoveResources => {
+                if let Some(asset) = self.selected_release_info().and_then(|r| r.get_resources_asset()) {
+                    let asset = asset.clone();
+                    let atype = AssetType::ResourcesRemoval;
+                    sender.output(Output::FlashAssetFromRelease(asset, atype, None)).unwrap();
+                }
+            }
+            Input::UpdateAllFromReleaseClicked => {
+                if let Some(release) = self.selected_release_info() {
+                    let plan = self.firmware_plan(release);
+                    if plan.needs_confirmation() {
+                        Self::show_firmware_warning(&plan, || Input::UpdateAllFromRelease, sender);
+                    } else {
+                        sender.input(Input::UpdateAllFromRelease);
+                    }
+                }
+            }
+            Input::UpdateAllFromRelease => {
+                if let Some(release) = self.selected_release_info() {
+                    let plan = policy::plan_firmware_update(self.installed_version(), &release.tag);
+                    match (release.get_dfu_asset(), release.get_resources_asset(), plan.resource_step) {
+                        (None, _, _) => {
+                            ui::BROKER.send(ui::Input::ToastStatic("DFU file not found"));
+                        }
+                        (Some(firmware), Some(resources), policy::ResourceStep::Install) => {
+                            sender.output(Output::UpdateAllFromRelease {
+                                firmware: firmware.clone(),
+                                resources: resources.clone(),
+                                version: release.tag.clone(),
+                            }).unwrap();
+                        }
+                        (Some(_), None, policy::ResourceStep::Install) => {
+                            ui::BROKER.send(ui::Input::ToastStatic("Resources asset not found"));
+                        }
+                        (Some(firmware), _, _) => {
+                            ui::BROKER.send(ui::Input::ToastStatic(if policy::uses_resources(&release.tag) {
+                                "Resources already match the firmware, only the firmware is flashed"
+                            } else {
+                                "Selected firmware doesn't use resources, only the firmware is flashed"
+                            }));
+                            let asset = firmware.clone();
+                            let atype = AssetType::Firmware;
+                            let version = Some(release.tag.clone());
+                            sender.output(Output::FlashAssetFromRelease(asset, atype, version)).unwrap();
+                        }
+                    }
+                }
+            }
+            Input::FlashExpertAssetClicked(kind) => {
+                if !kind.is_expert() {
+                    return;
+                }
+                if let Some(release) = self.selected_release_info() {
+                    if release.get_asset(kind).is_some() {
+                        self.expert_asset_kind = Some(kind);
+                        self.expert_warning.emit(AlertMsg::Show);
+                    } else {
+                        ui::BROKER.send(ui::Input::ToastStatic("Asset not found in this release"));
+                    }
+                }
+            }
+            Input::FlashExpertAsset => {
+                let kind = self.expert_asset_kind.take();
+                let asset = kind.zip(self.selected_release_info())
+                    .and_then(|(kind, release)| release.get_asset(kind));
+                let atype = kind.and_then(AssetType::from_kind);
+                if let (Some(asset), Some(atype)) = (asset, atype) {
+                    let asset = asset.clone();
+                    sender.output(Output::FlashAssetFromRelease(asset, atype, None)).unwrap();
+                }
+            }
+            Input::ShowJournal => {
+                sender.oneshot_command(async move {
+                    CommandOutput::JournalResponse(ui::flash_journal().entries().await)
+                });
+            }
         }
     }
 
@@ -560,24 +1214,55 @@ impl Component for Model {
         _root: &Self::Root,
     ) {
         match msg {
-            CommandOutput::FirmwareReleasesResponse(response) => match response {
-                Ok(releases) => {
-                    let tags = releases
-                        .iter()
-                        .map(|r| r.tag.as_str())
-                        .collect::<Vec<&str>>();
-                    let latest = tags.first().map(|t| t.to_string());
-                    self.tags = Some(gtk::StringList::new(&tags));
-                    self.releases = FirmwareReleasesState::Some(releases);
-                    sender.output(Output::LatestFirmwareVersion(latest)).unwrap();
+            CommandOutput::FirmwareReleasesResponse(generation, page, response) => match response {
+                // Response to the outdated request, e.g. before the source was changed
+                _ if generation != self.releases_generation => {}
+                Ok(gh::Cached { value, error }) => {
+                    if let Some(error) = error {
+                        log::warn!("Failed to refresh firmware releases: {error}");
+                        ui::BROKER.send(ui::Input::Toast(format!("Showing cached releases: {error}")));
+                    }
+                    if page == 1 {
+                        self.releases = FirmwareReleasesState::Some(Vec::new());
+                        self.filtered.clear();
+                        self.tags = None;
+                        self.selected_index = 0;
+                    }
+                    self.releases_page = page;
+                    self.has_more_releases = value.has_more;
+                    self.loading_more_releases = false;
+                    self.append_releases(value.releases);
+                    sender.output(Output::LatestFirmwareVersion(self.latest_version())).unwrap();
+                    self.load_more_if_needed(&sender);
+                }
+                Err(error) if page > 1 => {
+                    // Keep the releases that are already loaded
+                    self.loading_more_releases = false;
+                    self.has_more_releases = false;
+                    log::error!("Failed to fetch more firmware releases: {error}");
+                    ui::BROKER.send(ui::Input::Toast(error.to_string()));
                 }
                 Err(error) => {
                     self.tags = None;
                     self.releases = FirmwareReleasesState::Error;
                     sender.output(Output::LatestFirmwareVersion(None)).unwrap();
                     log::error!("Failed to fetch firmware releases: {error}");
+                    ui::BROKER.send(ui::Input::Toast(error.to_string()));
                 }
             },
+            CommandOutput::LatestReleaseResponse(generation, response) => match response {
+                // The source was changed meanwhile
+                _ if generation != self.releases_generation => {}
+                Ok(gh::Cached { value, error }) => {
+                    if let Some(error) = error {
+                        log::warn!("Failed to check for firmware updates: {error}");
+                    }
+                    if let Some(latest) = self.latest_version_of(&value.releases) {
+                        sender.output(Output::LatestFirmwareVersion(Some(latest))).unwrap();
+                    }
+                }
+                Err(error) => log::warn!("Failed to check for firmware updates: {error}"),
+            },
             CommandOutput::SaveFileResponse(response) => match response {
                 Ok(()) => {
                     ui::BROKER.send(ui::Input::ToastStatic("Firmware downloaded"));
@@ -587,6 +1272,13 @@ impl Component for Model {
                     ui::BROKER.send(ui::Input::ToastStatic("Failed to save DFU file"));
                 }
             },
+            CommandOutput::JournalResponse(response) => match response {
+                Ok(entries) => Self::show_journal(entries),
+                Err(error) => {
+                    log::error!("Failed to read flash journal: {error}");
+                    ui::BROKER.send(ui::Input::ToastStatic("Failed to read flash history"));
+                }
+            },
         }
     }
 }
@@ -597,11 +1289,26 @@ relm4::new_stateless_action!(
     FirmwareUpdateGroup,
     "flash-firmware"
 );
+relm4::new_stateless_action!(
+    UpdateAllAction,
+    FirmwareUpdateGroup,
+    "update-all"
+);
 relm4::new_stateless_action!(
     FlashResourcesAction,
     FirmwareUpdateGroup,
     "flash-resources"
 );
+relm4::new_stateless_action!(
+    FlashResourcesFolderAction,
+    FirmwareUpdateGroup,
+    "flash-resources-folder"
+);
+relm4::new_stateless_action!(
+    RemoveResourcesAction,
+    FirmwareUpdateGroup,
+    "remove-resources"
+);
 relm4::new_stateless_action!(
     DownloadFirmwareAction,
     FirmwareUpdateGroup,
@@ -612,6 +1319,16 @@ relm4::new_stateless_action!(
     FirmwareUpdateGroup,
     "download-resouces"
 );
+relm4::new_stateless_action!(
+    FlashRecoveryLoaderAction,
+    FirmwareUpdateGroup,
+    "flash-recovery-loader"
+);
+relm4::new_stateless_action!(
+    FlashHistoryAction,
+    FirmwareUpdateGroup,
+    "open-flash-history"
+);
 relm4::new_stateless_action!(
     ReleaseNotesAction,
     FirmwareUpdateGroup,
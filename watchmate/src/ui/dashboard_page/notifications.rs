@@ -1,7 +1,8 @@
 use crate::ui;
-use infinitime::{zbus, bt, fdo::notifications};
-use std::sync::Arc;
-use gtk::{gio, prelude::{BoxExt, OrientableExt, WidgetExt, SettingsExt, SettingsExtManual}};
+use infinitime::{bluer, zbus, bt, fdo::notifications};
+use futures::{pin_mut, StreamExt};
+use std::{collections::HashMap, sync::Arc};
+use gtk::{gio, glib, prelude::{BoxExt, ObjectExt, OrientableExt, WidgetExt, SettingsExt, SettingsExtManual, ToVariant}};
 use relm4::{gtk, ComponentParts, ComponentSender, Component, JoinHandle, RelmWidgetExt};
 
 
@@ -10,6 +11,8 @@ pub enum Input {
     Device(Option<Arc<bt::InfiniTime>>),
     SetNotificationSession(bool),
     NotificationSessionEnded,
+    Paired(bool),
+    RequirePairingChanged,
 }
 
 #[derive(Default)]
@@ -17,6 +20,11 @@ pub struct Model {
     infinitime: Option<Arc<bt::InfiniTime>>,
     is_enabled: bool,
     task: Option<JoinHandle<()>>,
+    // Pairing state of the watch, `None` until it's read
+    paired: Option<bool>,
+    pairing_task: Option<JoinHandle<()>>,
+    require_pairing_handler: Option<glib::SignalHandlerId>,
+    settings: Option<gio::Settings>,
 }
 
 impl Model {
@@ -49,6 +57,75 @@ impl Model {
         }
     }
 
+    /// Start or stop the notification session according to the switch,
+    /// pairing of the watch and the "Require pairing" setting
+    fn update_session(&mut self, sender: ComponentSender<Self>) {
+        let require_pairing = self.settings.as_ref()
+            .is_some_and(|s| s.boolean(ui::SETTING_NOTIFICATIONS_REQUIRE_PAIRING));
+        if !self.is_enabled || self.infinitime.is_none() {
+            self.stop_notifications_task();
+        } else if require_pairing && self.paired != Some(true) {
+            if self.task.is_some() || self.paired.is_some() {
+                log::warn!("Notifications are not forwarded, because the watch is not paired");
+                ui::BROKER.send(ui::Input::ToastStatic("Pair the watch to forward notifications"));
+            }
+            self.stop_notifications_task();
+        } else if self.task.is_none() {
+            self.start_notifications_task(sender);
+        }
+    }
+
+    /// Follow changes of the watch pairing state. BlueZ doesn't expose the link
+    /// encryption, but it encrypts connections to paired devices, and drops
+    /// them if the watch no longer has the keys.
+    fn start_pairing_monitor(&mut self, infinitime: Arc<bt::InfiniTime>, sender: ComponentSender<Self>) {
+        self.stop_pairing_monitor();
+        self.pairing_task = Some(relm4::spawn(async move {
+            // Subscribe first to not miss the changes
+            let stream = infinitime.get_property_stream().await;
+            let paired = infinitime.device().is_paired().await;
+            sender.input(Input::Paired(paired.unwrap_or(false)));
+            match stream {
+                Ok(stream) => {
+                    pin_mut!(stream);
+                    while let Some(property) = stream.next().await {
+                        if let bluer::DeviceProperty::Paired(paired) = property {
+                            sender.input(Input::Paired(paired));
+                        }
+                    }
+                }
+                Err(error) => log::error!("Failed to get property stream: {}", error),
+            }
+        }));
+    }
+
+    fn stop_pairing_monitor(&mut self) {
+        self.pairing_task.take().map(|h| h.abort());
+        self.paired = None;
+    }
+
+    /// Whether notifications are forwarded to the watch. Watches without their
+    /// own setting follow the global one.
+    fn read_enabled(&self, address: &str) -> bool {
+        match &self.settings {
+            Some(settings) => settings.get::<HashMap<String, bool>>(ui::SETTING_NOTIFICATIONS_DEVICES)
+                .get(address)
+                .copied()
+                .unwrap_or_else(|| settings.boolean(ui::SETTING_NOTIFICATIONS)),
+            None => false,
+        }
+    }
+
+    fn save_enabled(&self, address: &str, enabled: bool) {
+        if let Some(settings) = &self.settings {
+            let mut devices = settings.get::<HashMap<String, bool>>(ui::SETTING_NOTIFICATIONS_DEVICES);
+            devices.insert(address.to_string(), enabled);
+            if let Err(error) = settings.set(ui::SETTING_NOTIFICATIONS_DEVICES, devices.to_variant()) {
+                log::error!("Failed to save notifications setting: {}", error);
+            }
+        }
+    }
+
     fn stop_notifications_task(&mut self) {
         // TODO: Is it safe to abort, or does it makes sense to
         // hook up a message channel to finish gracefully?
@@ -78,8 +155,9 @@ impl Component for Model {
                 set_halign: gtk::Align::Start,
             },
 
-            #[name = "switch"]
             gtk::Switch {
+                #[watch]
+                set_active: model.is_enabled,
                 #[watch]
                 set_state: model.is_enabled && model.task.is_some(),
                 set_halign: gtk::Align::End,
@@ -93,32 +171,62 @@ impl Component for Model {
 
     fn init(settings: Self::Init, root: Self::Root, sender: ComponentSender<Self>) -> ComponentParts<Self> {
         let is_enabled = settings.boolean(ui::SETTING_NOTIFICATIONS);
-        let model = Self { is_enabled, ..Default::default() };
+        let mut model = Self { is_enabled, settings: Some(settings.clone()), ..Default::default() };
         let widgets = view_output!();
-        settings.bind(ui::SETTING_NOTIFICATIONS, &widgets.switch, "active").build();
+
+        model.require_pairing_handler = Some(settings.connect_changed(
+            Some(ui::SETTING_NOTIFICATIONS_REQUIRE_PAIRING),
+            glib::clone!(#[strong] sender, move |_, _| sender.input(Input::RequirePairingChanged)),
+        ));
+
         ComponentParts { model, widgets }
     }
 
     fn update(&mut self, msg: Self::Input, sender: ComponentSender<Self>, _root: &Self::Root) {
         match msg {
             Input::Device(infinitime) => {
-                self.infinitime = infinitime;
-                match self.infinitime {
-                    Some(_) if self.is_enabled => self.start_notifications_task(sender),
-                    Some(_) => {},
-                    None => self.stop_notifications_task(),
+                self.infinitime = infinitime.clone();
+                self.stop_notifications_task();
+                match infinitime {
+                    Some(infinitime) => {
+                        self.is_enabled = self.read_enabled(&infinitime.device().address().to_string());
+                        self.start_pairing_monitor(infinitime, sender.clone());
+                        self.update_session(sender);
+                    }
+                    None => self.stop_pairing_monitor(),
                 }
             }
             Input::SetNotificationSession(state) => {
-                self.is_enabled = state;
-                match state {
-                    true => self.start_notifications_task(sender),
-                    false => self.stop_notifications_task(),
+                // The switch also reports the state set from the model
+                if state == self.is_enabled && state == self.task.is_some() {
+                    return;
                 }
+                if let Some(infinitime) = &self.infinitime {
+                    self.save_enabled(&infinitime.device().address().to_string(), state);
+                }
+                self.is_enabled = state;
+                self.update_session(sender);
             }
             Input::NotificationSessionEnded => {
                 self.task = None;
             }
+            Input::Paired(paired) => {
+                if self.paired != Some(paired) {
+                    self.paired = Some(paired);
+                    self.update_session(sender);
+                }
+            }
+            Input::RequirePairingChanged => {
+                self.update_session(sender);
+            }
+        }
+    }
+
+    fn shutdown(&mut self, _widgets: &mut Self::Widgets, _output: relm4::Sender<Self::Output>) {
+        self.stop_notifications_task();
+        self.stop_pairing_monitor();
+        if let (Some(settings), Some(handler)) = (&self.settings, self.require_pairing_handler.take()) {
+            settings.disconnect(handler);
         }
     }
 }
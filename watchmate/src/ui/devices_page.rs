@@ -1,9 +1,10 @@
 use crate::ui;
 use std::str::FromStr;
-use infinitime::{ bluer, bt };
-use std::sync::Arc;
-use futures::{pin_mut, StreamExt};
-use gtk::{gio, prelude::{BoxExt, ButtonExt, OrientableExt, ListBoxRowExt, WidgetExt, SettingsExt}};
+use infinitime::{ bluer::{self, agent}, bt, tokio };
+use std::{cell::Cell, collections::HashSet, sync::Arc, time::Duration};
+use futures::{channel::oneshot, pin_mut, StreamExt};
+use gtk::{gio, glib, prelude::{BoxExt, ButtonExt, EditableExt, GtkWindowExt, OrientableExt, ListBoxRowExt, WidgetExt, SettingsExt}};
+use adw::prelude::MessageDialogExt;
 use relm4::{
     adw, gtk,
     factory::{FactoryComponent, FactorySender, FactoryVecDeque, DynamicIndex},
@@ -15,6 +16,7 @@ use relm4::{
 pub enum Input {
     InitSession,
     InitAdapter,
+    RefreshAdapters,
     AdapterAdded(String),
     AdapterRemoved(String),
     StartDiscovery,
@@ -29,19 +31,27 @@ pub enum Input {
     DeviceDisconnecting(Arc<bluer::Device>),
     DeviceConnectionFailed,
     DeviceConnectionLost(bluer::Address),
-    SaveAddress(Option<bluer::Address>),
+    DeviceRejected(bluer::Address),
+    DeviceSetupFailed(bluer::Address),
+    ExpectReconnect(bluer::Address),
+    SetSaved(bluer::Address, bool),
+    PasskeyRequested(bluer::Address, oneshot::Sender<Option<u32>>),
+    ConfirmationRequested(bluer::Address, u32, oneshot::Sender<bool>),
 }
 
 #[derive(Debug)]
 pub enum Output {
     DeviceConnected(Arc<bluer::Device>),
+    Adapters(Vec<AdapterInfo>),
 }
 
 #[derive(Debug)]
 pub enum CommandOutput {
     InitSessionResult(bluer::Result<bluer::Session>),
     InitAdapterResult(bluer::Result<bluer::Adapter>),
+    AdaptersResult(bluer::Result<Vec<AdapterInfo>>),
     GattServicesResult(bluer::Result<bluer::gatt::local::ApplicationHandle>),
+    AgentResult(bluer::Result<agent::AgentHandle>),
     KnownDevices(Vec<DeviceInfo>),
 }
 
@@ -51,24 +61,188 @@ pub struct Model {
     session: Option<Arc<bluer::Session>>,
     adapter: Option<Arc<bluer::Adapter>>,
     gatt_server: Option<bluer::gatt::local::ApplicationHandle>,
+    agent: Option<agent::AgentHandle>,
     discovery_task: Option<JoinHandle<()>>,
 
-    saved_address: Option<bluer::Address>,
-    autoconnect_address: Option<bluer::Address>,
-    disconnecting_address: Option<bluer::Address>,
+    saved_addresses: HashSet<bluer::Address>,
+    autoconnect_addresses: HashSet<bluer::Address>,
+    disconnecting_addresses: HashSet<bluer::Address>,
+    reconnect_addresses: HashSet<bluer::Address>,
+}
+
+#[derive(Debug, Clone)]
+pub struct AdapterInfo {
+    pub name: String,
+    pub address: bluer::Address,
+    pub alias: String,
+    pub powered: bool,
+}
+
+impl AdapterInfo {
+    async fn new(adapter: &bluer::Adapter) -> bluer::Result<Self> {
+        Ok(Self {
+            name: adapter.name().to_string(),
+            address: adapter.address().await?,
+            alias: adapter.alias().await?,
+            powered: adapter.is_powered().await?,
+        })
+    }
 }
 
 impl Model {
-    async fn init_adapter(session: Arc<bluer::Session>) -> bluer::Result<bluer::Adapter> {
-        let adapter = session.default_adapter().await?;
+    /// Addresses of the watches to connect to automatically. The single
+    /// address saved by the older versions is migrated into the list.
+    fn read_saved_addresses(settings: &gio::Settings) -> HashSet<bluer::Address> {
+        let mut addresses = settings.strv(super::SETTING_DEVICE_ADDRESSES).iter()
+            .filter_map(|address| bluer::Address::from_str(address.as_str()).ok())
+            .collect::<HashSet<_>>();
+        let legacy = settings.string(super::SETTING_DEVICE_ADDRESS);
+        if let Ok(address) = bluer::Address::from_str(legacy.as_str()) {
+            addresses.insert(address);
+            Self::write_saved_addresses(settings, &addresses);
+        }
+        if !legacy.is_empty() {
+            _ = settings.set_string(super::SETTING_DEVICE_ADDRESS, "");
+        }
+        addresses
+    }
+
+    fn device_filter(&self) -> bt::DeviceFilter {
+        let names = self.settings.strv(super::SETTING_DEVICE_NAMES).iter()
+            .map(|name| name.to_string())
+            .collect();
+        bt::DeviceFilter::new(names)
+    }
+
+    fn write_saved_addresses(settings: &gio::Settings, addresses: &HashSet<bluer::Address>) {
+        let mut addresses = addresses.iter().map(|a| a.to_string()).collect::<Vec<_>>();
+        addresses.sort();
+        _ = settings.set_strv(super::SETTING_DEVICE_ADDRESSES, addresses.as_slice());
+    }
+
+    async fn list_adapters(session: Arc<bluer::Session>) -> bluer::Result<Vec<AdapterInfo>> {
+        let mut adapters = Vec::new();
+        for name in session.adapter_names().await? {
+            adapters.push(AdapterInfo::new(&session.adapter(&name)?).await?);
+        }
+        Ok(adapters)
+    }
+
+    /// The preferred adapter if it's present, otherwise the default one if it's
+    /// powered, otherwise any powered one
+    async fn pick_adapter(session: &bluer::Session, preferred: &str) -> bluer::Result<bluer::Adapter> {
+        let names = session.adapter_names().await?;
+        if names.iter().any(|n| n == preferred) {
+            return session.adapter(preferred);
+        }
+        let default = session.default_adapter().await?;
+        if default.is_powered().await? {
+            return Ok(default);
+        }
+        for name in names {
+            let adapter = session.adapter(&name)?;
+            if adapter.is_powered().await.unwrap_or(false) {
+                return Ok(adapter);
+            }
+        }
+        Ok(default)
+    }
+
+    async fn init_adapter(session: Arc<bluer::Session>, preferred: String) -> bluer::Result<bluer::Adapter> {
+        let adapter = Self::pick_adapter(&session, &preferred).await?;
+        // Watches are recognized by the app, as BlueZ can't filter by services or name
         adapter.set_discovery_filter(bluer::DiscoveryFilter {
             transport: bluer::DiscoveryTransport::Le,
-            pattern: Some(String::from("InfiniTime")),
             ..Default::default()
         }).await?;
         Ok(adapter)
     }
 
+    /// Pairing agent, which is used by BlueZ for the pairing requests made by
+    /// this app. It's not registered as the default one, so pairing initiated
+    /// elsewhere is still handled by the desktop environment.
+    fn pairing_agent(sender: ComponentSender<Self>) -> agent::Agent {
+        let passkey_sender = sender.clone();
+        let confirmation_sender = sender;
+        agent::Agent {
+            request_default: false,
+            request_passkey: Some(Box::new(move |request| {
+                let sender = passkey_sender.clone();
+                Box::pin(async move {
+                    let (reply_tx, reply_rx) = oneshot::channel();
+                    sender.input(Input::PasskeyRequested(request.device, reply_tx));
+                    match reply_rx.await {
+                        Ok(Some(passkey)) => Ok(passkey),
+                        Ok(None) => Err(agent::ReqError::Rejected),
+                        Err(_) => Err(agent::ReqError::Canceled),
+                    }
+                })
+            })),
+            request_confirmation: Some(Box::new(move |request| {
+                let sender = confirmation_sender.clone();
+                Box::pin(async move {
+                    let (reply_tx, reply_rx) = oneshot::channel();
+                    sender.input(Input::ConfirmationRequested(request.device, request.passkey, reply_tx));
+                    match reply_rx.await {
+                        Ok(true) => Ok(()),
+                        Ok(false) => Err(agent::ReqError::Rejected),
+                        Err(_) => Err(agent::ReqError::Canceled),
+                    }
+                })
+            })),
+            ..Default::default()
+        }
+    }
+
+    fn show_passkey_dialog(address: bluer::Address, reply: oneshot::Sender<Option<u32>>) {
+        let entry = gtk::Entry::builder()
+            .input_purpose(gtk::InputPurpose::Digits)
+            .max_length(6)
+            .placeholder_text("000000")
+            .activates_default(true)
+            .build();
+        let dialog = adw::MessageDialog::builder()
+            .heading("Pair Watch")
+            .body(format!("Enter the passkey shown on the watch ({address})"))
+            .extra_child(&entry)
+            .modal(true)
+            .build();
+        dialog.set_transient_for(relm4::main_application().active_window().as_ref());
+        dialog.add_responses(&[("cancel", "Cancel"), ("pair", "Pair")]);
+        dialog.set_response_appearance("pair", adw::ResponseAppearance::Suggested);
+        dialog.set_default_response(Some("pair"));
+        // The reply is sent once, any other response including closing rejects pairing
+        let reply = Cell::new(Some(reply));
+        dialog.connect_response(None, move |_, response| {
+            if let Some(reply) = reply.take() {
+                let passkey = match response {
+                    "pair" => entry.text().trim().parse::<u32>().ok(),
+                    _ => None,
+                };
+                _ = reply.send(passkey);
+            }
+        });
+        dialog.present();
+    }
+
+    fn show_confirmation_dialog(address: bluer::Address, passkey: u32, reply: oneshot::Sender<bool>) {
+        let dialog = adw::MessageDialog::builder()
+            .heading("Pair Watch")
+            .body(format!("Does the watch ({address}) show passkey {passkey:06}?"))
+            .modal(true)
+            .build();
+        dialog.set_transient_for(relm4::main_application().active_window().as_ref());
+        dialog.add_responses(&[("cancel", "No"), ("pair", "Yes, Pair")]);
+        dialog.set_response_appearance("pair", adw::ResponseAppearance::Suggested);
+        let reply = Cell::new(Some(reply));
+        dialog.connect_response(None, move |_, response| {
+            if let Some(reply) = reply.take() {
+                _ = reply.send(response == "pair");
+            }
+        });
+        dialog.present();
+    }
+
     async fn run_session_stream(session: Arc<bluer::Session>, sender: ComponentSender<Self>) {
         match session.events().await {
             Ok(stream) => {
@@ -90,6 +264,34 @@ impl Model {
         }
     }
 
+    /// Whether the discovered device is a watch. Its name and services
+    /// might only arrive with the later advertisements.
+    async fn recognize_device(device: &bluer::Device, filter: &bt::DeviceFilter) -> bool {
+        if bt::InfiniTime::check_device(device, filter).await {
+            return true;
+        }
+        // Only wait for the devices that haven't advertised everything yet
+        let has_name = matches!(device.name().await, Ok(Some(_)));
+        let has_uuids = matches!(device.uuids().await, Ok(Some(uuids)) if !uuids.is_empty());
+        if has_name && has_uuids {
+            return false;
+        }
+        let Ok(events) = device.events().await else {
+            return false;
+        };
+        pin_mut!(events);
+        let wait = async {
+            while let Some(bluer::DeviceEvent::PropertyChanged(property)) = events.next().await {
+                let relevant = matches!(property, bluer::DeviceProperty::Name(_) | bluer::DeviceProperty::Uuids(_));
+                if relevant && bt::InfiniTime::check_device(device, filter).await {
+                    return true;
+                }
+            }
+            false
+        };
+        tokio::time::timeout(Duration::from_secs(10), wait).await.unwrap_or(false)
+    }
+
     async fn run_discovery(adapter: Arc<bluer::Adapter>, sender: ComponentSender<Self>) {
         match adapter.discover_devices().await {
             Ok(stream) => {
@@ -210,10 +412,7 @@ impl Component for Model {
     }
 
     fn init(settings: Self::Init, root: Self::Root, sender: ComponentSender<Self>) -> ComponentParts<Self> {
-        let saved_address = match settings.string(super::SETTING_DEVICE_ADDRESS).as_str() {
-            "" => None,
-            address => bluer::Address::from_str(address).ok()
-        };
+        let saved_addresses = Self::read_saved_addresses(&settings);
 
         let devices = FactoryVecDeque::builder()
             .launch(gtk::ListBox::new())
@@ -222,7 +421,7 @@ impl Component for Model {
                 DeviceOutput::Disconnected(device) => Input::DeviceDisconnected(device),
                 DeviceOutput::Disconnecting(device) => Input::DeviceDisconnecting(device),
                 DeviceOutput::ConnectionFailed => Input::DeviceConnectionFailed,
-                DeviceOutput::SaveAddress(address) => Input::SaveAddress(address),
+                DeviceOutput::SetSaved(address, saved) => Input::SetSaved(address, saved),
             });
 
         let model = Self {
@@ -231,15 +430,22 @@ impl Component for Model {
             session: None,
             adapter: None,
             gatt_server: None,
+            agent: None,
             discovery_task: None,
-            autoconnect_address: saved_address.clone(),
-            saved_address,
-            disconnecting_address: None,
+            autoconnect_addresses: saved_addresses.clone(),
+            saved_addresses,
+            disconnecting_addresses: HashSet::new(),
+            reconnect_addresses: HashSet::new(),
         };
 
         let factory_widget = model.devices.widget();
         let widgets = view_output!();
 
+        model.settings.connect_changed(
+            Some(super::SETTING_ADAPTER),
+            glib::clone!(#[strong] sender, move |_, _| sender.input(Input::InitAdapter)),
+        );
+
         sender.input(Input::InitSession);
 
         ComponentParts { model, widgets }
@@ -254,23 +460,41 @@ impl Component for Model {
             }
 
             Input::InitAdapter => {
+                if let Some(session) = self.session.clone() {
+                    let preferred = self.settings.string(super::SETTING_ADAPTER).to_string();
+                    sender.oneshot_command(async move {
+                        CommandOutput::InitAdapterResult(Self::init_adapter(session, preferred).await)
+                    });
+                }
+            }
+
+            Input::RefreshAdapters => {
                 if let Some(session) = self.session.clone() {
                     sender.oneshot_command(async move {
-                        CommandOutput::InitAdapterResult(Self::init_adapter(session).await)
+                        CommandOutput::AdaptersResult(Self::list_adapters(session).await)
                     });
                 }
             }
 
-            Input::AdapterAdded(_name) => {
-                if self.adapter.is_none() {
+            Input::AdapterAdded(name) => {
+                log::info!("Bluetooth adapter added: {}", name);
+                sender.input(Input::RefreshAdapters);
+                // Switch back when the preferred adapter is plugged in again
+                if self.adapter.is_none() || name.as_str() == self.settings.string(super::SETTING_ADAPTER).as_str() {
                     sender.input(Input::InitAdapter);
                 }
             }
 
             Input::AdapterRemoved(name) => {
+                sender.input(Input::RefreshAdapters);
                 if self.adapter.as_ref().map(|a| a.name()) == Some(&name) {
-                    log::warn!("Bluetooth adapter is lost");
+                    log::warn!("Bluetooth adapter is lost: {}", name);
+                    self.discovery_task.take().map(|h| h.abort());
+                    self.gatt_server = None;
                     self.adapter = None;
+                    self.devices.guard().clear();
+                    // Fall back to another adapter if there is any
+                    sender.input(Input::InitAdapter);
                 }
             }
 
@@ -302,8 +526,9 @@ impl Component for Model {
             Input::DeviceInfoReady(info) => {
                 let address = info.address;
                 let mut devices = self.devices.guard();
+                let disconnected = info.state == DeviceState::Disconnected;
                 devices.push_front(info);
-                if Some(address) == self.autoconnect_address {
+                if disconnected && self.autoconnect_addresses.contains(&address) {
                     log::debug!("Detected lost device: {}. Trying to reconnect...", address);
                     sender.input(Input::StopDiscovery);
                     devices.send(0, DeviceInput::Connect);
@@ -314,9 +539,10 @@ impl Component for Model {
                 if let Some(adapter) = &self.adapter {
                     if let Ok(device) = adapter.device(address) {
                         let device = Arc::new(device);
-                        let saved = Some(address) == self.saved_address;
+                        let saved = self.saved_addresses.contains(&address);
+                        let filter = self.device_filter();
                         relm4::spawn(async move {
-                            if bt::InfiniTime::check_device(&device).await {
+                            if Self::recognize_device(&device, &filter).await {
                                 log::debug!("Device discovered: {}", address);
                                 match DeviceInfo::new(device, saved).await {
                                     Ok(info) => sender.input(Input::DeviceInfoReady(info)),
@@ -343,8 +569,8 @@ impl Component for Model {
             Input::DeviceSelected(index) => {
                 log::debug!("Device selected: {}", index);
                 sender.input(Input::StopDiscovery);
-                self.autoconnect_address = None;
                 if let Some(device) = self.devices.get(index as usize) {
+                    self.autoconnect_addresses.remove(&device.address);
                     if device.state != DeviceState::Transitioning {
                         self.devices.send(index as usize, DeviceInput::Connect);
                     }
@@ -353,25 +579,26 @@ impl Component for Model {
 
             Input::DeviceConnected(device) => {
                 log::debug!("Device connected successfully: {}", device.address());
-                self.autoconnect_address = None;
-                _ = self.settings.set_string(super::SETTING_DEVICE_ADDRESS, &device.address().to_string());
-                sender.input(Input::SaveAddress(Some(device.address())));
+                self.autoconnect_addresses.remove(&device.address());
+                sender.input(Input::SetSaved(device.address(), true));
                 sender.output(Output::DeviceConnected(device)).unwrap();
+                // Keep looking for the other saved watches
+                if !self.autoconnect_addresses.is_empty() {
+                    sender.input(Input::StartDiscovery);
+                }
             }
 
             Input::DeviceDisconnected(device) => {
                 log::debug!("Device disconnected successfully: {}", device.address());
-                if Some(device.address()) == self.autoconnect_address {
-                    self.autoconnect_address = None;
-                }
-                self.disconnecting_address = None;
+                self.autoconnect_addresses.remove(&device.address());
+                self.disconnecting_addresses.remove(&device.address());
                 // Repopulate known devices
                 sender.input(Input::StopDiscovery);
                 sender.input(Input::StartDiscovery);
             }
 
             Input::DeviceDisconnecting(device) => {
-                self.disconnecting_address = Some(device.address());
+                self.disconnecting_addresses.insert(device.address());
             }
 
             Input::DeviceConnectionFailed => {
@@ -387,17 +614,61 @@ impl Component for Model {
                 if let Some((idx, _)) = result {
                     devices.send(idx, DeviceInput::StateUpdated(DeviceState::Disconnected));
                 }
-                if Some(address) != self.disconnecting_address && Some(address) == self.saved_address {
-                    self.autoconnect_address = Some(address);
+                // Reconnect if the device is saved, or if the disconnection was expected
+                // (e.g. reboot after firmware upgrade)
+                let expected = self.reconnect_addresses.remove(&address);
+                let disconnecting = self.disconnecting_addresses.contains(&address);
+                if !disconnecting && (expected || self.saved_addresses.contains(&address)) {
+                    self.autoconnect_addresses.insert(address);
+                    sender.input(Input::StopDiscovery);
                     sender.input(Input::StartDiscovery);
                 }
             }
 
-            Input::SaveAddress(address) => {
-                self.saved_address = address;
-                let address_str = address.map(|a| a.to_string()).unwrap_or_default();
-                _ = self.settings.set_string(super::SETTING_DEVICE_ADDRESS, &address_str);
-                self.devices.broadcast(DeviceInput::SavedAddress(address));
+            Input::DeviceRejected(address) => {
+                log::debug!("Device rejected: {}", address);
+                sender.input(Input::DeviceSetupFailed(address));
+                // It was saved on connection, don't connect to it on the next start
+                sender.input(Input::SetSaved(address, false));
+            }
+
+            Input::DeviceSetupFailed(address) => {
+                log::debug!("Device setup failed: {}", address);
+                let devices = self.devices.guard();
+                let result = devices.iter().enumerate().find(|(_, d)| d.address == address);
+                if let Some((idx, _)) = result {
+                    devices.send(idx, DeviceInput::StateUpdated(DeviceState::Disconnected));
+                }
+                // Might fail the same way again, so it's only connected
+                // automatically on the next start if it's saved
+                self.autoconnect_addresses.remove(&address);
+                self.reconnect_addresses.remove(&address);
+                sender.input(Input::StartDiscovery);
+            }
+
+            Input::ExpectReconnect(address) => {
+                self.reconnect_addresses.insert(address);
+            }
+
+            Input::SetSaved(address, saved) => {
+                let changed = match saved {
+                    true => self.saved_addresses.insert(address),
+                    false => self.saved_addresses.remove(&address),
+                };
+                if changed {
+                    Self::write_saved_addresses(&self.settings, &self.saved_addresses);
+                    self.devices.broadcast(DeviceInput::SavedAddresses(self.saved_addresses.clone()));
+                }
+            }
+
+            Input::PasskeyRequested(address, reply) => {
+                log::info!("Passkey requested for {}", address);
+                Self::show_passkey_dialog(address, reply);
+            }
+
+            Input::ConfirmationRequested(address, passkey, reply) => {
+                log::info!("Passkey confirmation requested for {}", address);
+                Self::show_confirmation_dialog(address, passkey, reply);
             }
         }
     }
@@ -408,8 +679,13 @@ impl Component for Model {
                 Ok(session) => {
                     let session = Arc::new(session);
                     self.session = Some(session.clone());
-                    relm4::spawn(Self::run_session_stream(session, sender.clone()));
+                    relm4::spawn(Self::run_session_stream(session.clone(), sender.clone()));
+                    let agent = Self::pairing_agent(sender.clone());
+                    sender.oneshot_command(async move {
+                        CommandOutput::AgentResult(session.register_agent(agent).await)
+                    });
                     sender.input(Input::InitAdapter);
+                    sender.input(Input::RefreshAdapters);
                 }
                 Err(error) => {
                     log::error!("Failed to initialize bluetooth session: {error}");
@@ -417,7 +693,15 @@ impl Component for Model {
             }
             CommandOutput::InitAdapterResult(result) => match result {
                 Ok(adapter) => {
-                    log::debug!("Bluetooth adapter is initialized");
+                    if self.adapter.as_ref().map(|a| a.name()) == Some(adapter.name()) {
+                        return;
+                    }
+                    log::info!("Using bluetooth adapter {}", adapter.name());
+                    // Connected watches stay on the previous adapter until they disconnect
+                    self.discovery_task.take().map(|h| h.abort());
+                    self.devices.guard().clear();
+                    // Dropping the handle unregisters GATT services from the previous adapter
+                    self.gatt_server = None;
                     let adapter = Arc::new(adapter);
                     self.adapter = Some(adapter.clone());
 
@@ -428,12 +712,25 @@ impl Component for Model {
                     });
 
                     // Read known devices list
-                    let saved_address = self.saved_address.clone();
+                    let saved_addresses = self.saved_addresses.clone();
+                    let filter = self.device_filter();
                     sender.oneshot_command(async move {
                         let mut devices = Vec::new();
-                        for device in bt::InfiniTime::list_known_devices(&adapter).await.unwrap() {
-                            let saved = Some(device.address()) == saved_address;
-                            devices.push(DeviceInfo::new(Arc::new(device), saved).await.unwrap())
+                        // The adapter might be unplugged meanwhile
+                        let known_devices = match bt::InfiniTime::list_known_devices(&adapter, &filter).await {
+                            Ok(known_devices) => known_devices,
+                            Err(error) => {
+                                log::error!("Failed to list known devices: {error}");
+                                Vec::new()
+                            }
+                        };
+                        for device in known_devices {
+                            let address = device.address();
+                            let saved = saved_addresses.contains(&address);
+                            match DeviceInfo::new(Arc::new(device), saved).await {
+                                Ok(info) => devices.push(info),
+                                Err(error) => log::warn!("Failed to read device {address} info: {error}"),
+                            }
                         }
                         CommandOutput::KnownDevices(devices)
                     });
@@ -442,6 +739,22 @@ impl Component for Model {
                     log::error!("Failed to initialize bluetooth adapter: {error}");
                 }
             }
+            CommandOutput::AdaptersResult(result) => match result {
+                Ok(adapters) => {
+                    sender.output(Output::Adapters(adapters)).unwrap();
+                }
+                Err(error) => {
+                    log::error!("Failed to list bluetooth adapters: {error}");
+                }
+            }
+            CommandOutput::AgentResult(result) => match result {
+                Ok(handle) => {
+                    self.agent = Some(handle);
+                }
+                Err(error) => {
+                    log::error!("Failed to register pairing agent: {error}");
+                }
+            }
             CommandOutput::GattServicesResult(result) => match result {
                 Ok(handle) => {
                     self.gatt_server = Some(handle);
@@ -453,37 +766,30 @@ impl Component for Model {
             }
 
             CommandOutput::KnownDevices(devices) => {
-                let connected = devices.iter()
-                    .find(|d| d.state == DeviceState::Connected)
-                    .map(|d| d.address);
-
                 let mut devices_guard = self.devices.guard();
                 for device in devices {
                     devices_guard.push_back(device);
                 }
 
                 // Automatic device selection logic
-                if let Some(address) = connected {
-                    // If suitable device is already connected - just report it as connected
-                    if let Some(adapter) = &self.adapter {
-                        if let Ok(device) = adapter.device(address) {
-                            let device = Arc::new(device);
-                            sender.output(Output::DeviceConnected(device)).unwrap();
-                            self.autoconnect_address = None;
-                            log::info!("InfiniTime ({}) is already connected", address.to_string());
-                        }
-                    }
-                } else {
-                    if let Some((i, d)) = devices_guard.iter().enumerate().find(
-                        |(_, d)| Some(d.address) == self.autoconnect_address
-                    ) {
+                let (mut connected, mut connecting) = (false, false);
+                for (i, d) in devices_guard.iter().enumerate() {
+                    if d.state == DeviceState::Connected {
+                        connected = true;
+                        // If suitable device is already connected - just report it as connected
+                        sender.output(Output::DeviceConnected(d.device.clone())).unwrap();
+                        self.autoconnect_addresses.remove(&d.address);
+                        log::info!("InfiniTime ({}) is already connected", d.address.to_string());
+                    } else if self.autoconnect_addresses.contains(&d.address) {
                         log::info!("Trying to connect to InfiniTime ({})", d.address.to_string());
                         devices_guard.send(i, DeviceInput::Connect);
-                    } else {
-                        // Otherwise, start discovery
-                        sender.input(Input::StartDiscovery);
+                        connecting = true;
                     }
                 }
+                // Otherwise, start discovery
+                if !connecting && (!connected || !self.autoconnect_addresses.is_empty()) {
+                    sender.input(Input::StartDiscovery);
+                }
             }
         }
     }
@@ -495,6 +801,8 @@ pub struct DeviceInfo {
     alias: String,
     rssi: Option<i16>,
     state: DeviceState,
+    paired: bool,
+    pairing: bool,
     device: Arc<bluer::Device>,
     saved: bool,
 }
@@ -511,6 +819,8 @@ impl DeviceInfo {
             alias: device.alias().await?,
             rssi: device.rssi().await?,
             state,
+            paired: device.is_paired().await?,
+            pairing: false,
             device,
             saved,
         })
@@ -530,7 +840,9 @@ pub enum DeviceInput {
     Disconnect,
     StateUpdated(DeviceState),
     SavedToggle,
-    SavedAddress(Option<bluer::Address>),
+    SavedAddresses(HashSet<bluer::Address>),
+    Pair,
+    PairingFinished(bool),
 }
 
 #[derive(Debug)]
@@ -539,7 +851,7 @@ pub enum DeviceOutput {
     Disconnected(Arc<bluer::Device>),
     Disconnecting(Arc<bluer::Device>),
     ConnectionFailed,
-    SaveAddress(Option<bluer::Address>),
+    SetSaved(bluer::Address, bool),
 }
 
 // Factory for device list
@@ -609,6 +921,22 @@ impl FactoryComponent for DeviceInfo {
                     },
                 },
 
+                gtk::Image {
+                    set_icon_name: Some("channel-secure-symbolic"),
+                    set_tooltip_text: Some("Paired, the connection is encrypted"),
+                    #[watch]
+                    set_visible: self.paired,
+                },
+
+                gtk::Button {
+                    set_tooltip_text: Some("Not paired. Click to pair with the passkey shown on the watch"),
+                    set_icon_name: "channel-insecure-symbolic",
+                    add_css_class: "flat",
+                    #[watch]
+                    set_visible: !self.paired && !self.pairing && self.state == DeviceState::Connected,
+                    connect_clicked => DeviceInput::Pair,
+                },
+
                 gtk::Button {
                     set_tooltip_text: Some("Click to disconnect"),
                     set_icon_name: "cross-symbolic",
@@ -620,7 +948,7 @@ impl FactoryComponent for DeviceInfo {
 
                 gtk::Spinner {
                     #[watch]
-                    set_visible: self.state == DeviceState::Transitioning,
+                    set_visible: self.state == DeviceState::Transitioning || self.pairing,
                     set_spinning: true,
                 },
             },
@@ -694,15 +1022,39 @@ impl FactoryComponent for DeviceInfo {
             }
 
             DeviceInput::SavedToggle => {
-                let address = match self.saved {
-                    true => None,
-                    false => Some(self.address),
-                };
-                _ = sender.output(DeviceOutput::SaveAddress(address))
+                _ = sender.output(DeviceOutput::SetSaved(self.address, !self.saved))
+            }
+
+            DeviceInput::SavedAddresses(addresses) => {
+                self.saved = addresses.contains(&self.address);
+            }
+
+            DeviceInput::Pair => {
+                self.pairing = true;
+                let device = self.device.clone();
+                relm4::spawn(async move {
+                    let result = async {
+                        device.pair().await?;
+                        // Let the watch reconnect without asking for a confirmation
+                        device.set_trusted(true).await
+                    }.await;
+                    match result {
+                        Ok(()) => {
+                            log::info!("Paired with {}", device.address());
+                            sender.input(DeviceInput::PairingFinished(true));
+                        }
+                        Err(error) => {
+                            log::error!("Pairing failure: {}", error);
+                            ui::BROKER.send(ui::Input::Toast(format!("Pairing failed: {}", error.message)));
+                            sender.input(DeviceInput::PairingFinished(false));
+                        }
+                    }
+                });
             }
 
-            DeviceInput::SavedAddress(address) => {
-                self.saved = Some(self.address) == address;
+            DeviceInput::PairingFinished(paired) => {
+                self.pairing = false;
+                self.paired = paired;
             }
         }
     }
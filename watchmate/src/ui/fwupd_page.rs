@@ -1,11 +1,14 @@
 use crate::ui;
 use infinitime::{
     tokio::{self, io::AsyncReadExt},
-    bt::{self, ProgressEvent, InfiniTime}, gh
+    bt::{self, FirmwareVerification, ProgressEvent, InfiniTime}, gh,
+    journal::{JournalEntry, JournalStatus},
 };
 
-use std::{sync::Arc, path::PathBuf};
-use gtk::prelude::{BoxExt, ButtonExt, OrientableExt, WidgetExt};
+use anyhow::Result;
+use std::{sync::Arc, path::PathBuf, time::{Duration, Instant}};
+use gtk::{gio, prelude::{BoxExt, ButtonExt, GtkApplicationExt, GtkWindowExt, OrientableExt, SettingsExt, WidgetExt}};
+use adw::prelude::MessageDialogExt;
 use relm4::{adw, gtk, ComponentParts, ComponentSender, Component, JoinHandle, RelmWidgetExt};
 
 #[derive(Debug)]
@@ -14,26 +17,50 @@ pub enum Input {
     Disconnected,
 
     FlashAssetFromFile(PathBuf, AssetType),
-    FlashAssetFromUrl(String, AssetType),
+    FlashAssetFromRelease(gh::Asset, AssetType, Option<String>),
+    UpdateAllFromRelease {
+        firmware: gh::Asset,
+        resources: gh::Asset,
+        version: String,
+    },
 
     ContentReady(Vec<u8>),
+    BundleReady(Vec<u8>, Vec<u8>),
+    ResourcePlanReady(bt::ResourcePlan),
+    ResourcePlanAccepted,
+    ResourcePlanRejected,
+    ImageWarningAccepted,
+    ImageWarningRejected,
 
     OtaProgress(ProgressEvent),
     OtaFinished,
     OtaFailed(String),
 
+    VerificationFinished(FirmwareVerification),
+    VerificationFailed(String),
+
     Retry,
     Abort,
 }
 
 pub enum Source {
     File(Arc<PathBuf>),
-    Url(Arc<String>),
+    /// Release asset and its release tag, if known
+    Release(Arc<gh::Asset>, Option<String>),
+    Bundle { firmware: Arc<gh::Asset>, resources: Arc<gh::Asset>, tag: String },
+}
+
+/// Resources upload either follows the plan reviewed by the user,
+/// or makes its own one (e.g. as a part of firmware & resources update)
+enum ResourceUpload {
+    Planned(bt::ResourcePlan),
+    Unplanned { verify: bool },
 }
 
 #[derive(PartialEq, Default)]
 pub enum State {
     InProgress,
+    Verifying,
     Aborted,
     #[default]
     Finished,
@@ -44,6 +71,9 @@ pub enum AssetType {
     #[default]
     Firmware,
     Resources,
+    RecoveryLoader,
+    /// Removal of the files listed in the resources archive
+    ResourcesRemoval,
 }
 
 impl AssetType {
@@ -51,6 +81,17 @@ impl AssetType {
         match self {
             AssetType::Firmware => "Firmware",
             AssetType::Resources => "Resources",
+            AssetType::RecoveryLoader => "Recovery loader",
+            AssetType::ResourcesRemoval => "Resources removal",
+        }
+    }
+
+    pub fn from_kind(kind: gh::AssetKind) -> Option<Self> {
+        match kind {
+            gh::AssetKind::ApplicationDfu => Some(AssetType::Firmware),
+            gh::AssetKind::Resources => Some(AssetType::Resources),
+            gh::AssetKind::RecoveryLoaderDfu => Some(AssetType::RecoveryLoader),
+            _ => None,
         }
     }
 }
@@ -64,23 +105,143 @@ pub struct Model {
     asset_type: AssetType,
     asset_content: Option<Arc<Vec<u8>>>,
     asset_source: Option<Source>,
+    expected_version: Option<String>,
+    image_version: Option<String>,
+    // Human-readable asset origin (file path or URL) for the journal
+    asset_origin: String,
+    journal_entry: Option<i64>,
+    // Firmware & resources update
+    queued_resources: Option<(Arc<Vec<u8>>, String)>,
+    // Resources upload plan accepted by the user
+    resource_plan: Option<bt::ResourcePlan>,
+    report: Vec<String>,
 
     infinitime: Option<Arc<bt::InfiniTime>>,
     task_handle: Option<JoinHandle<()>>,
+    settings: Option<gio::Settings>,
 }
 
+// How long to wait for the watch to reboot and reconnect after firmware upgrade
+const VERIFICATION_TIMEOUT: Duration = Duration::from_secs(180);
+
 impl Model {
-    fn download_asset(url: Arc<String>, sender: ComponentSender<Self>) -> JoinHandle<()> {
+    /// Whether an operation is still running, including the verification after it
+    pub fn is_busy(&self) -> bool {
+        matches!(self.state, State::InProgress | State::Verifying)
+    }
+
+    fn release_source(&self) -> Result<Box<dyn gh::ReleaseSource>> {
+        match &self.settings {
+            Some(settings) => ui::release_source(settings),
+            None => gh::SourceConfig::default().build(gh::Client::new()),
+        }
+    }
+
+    /// Firmware library and the number of releases to keep in it
+    fn firmware_library(&self) -> (gh::Library, usize) {
+        let keep = self.settings.as_ref().map_or(0, |s| s.uint(ui::SETTING_LIBRARY_KEEP) as usize);
+        (ui::firmware_library(), keep)
+    }
+
+    async fn prune_library(library: &gh::Library, keep: usize, current: Option<String>) {
+        if keep > 0 {
+            match library.prune(keep, current).await {
+                Ok(removed) => for entry in removed {
+                    log::info!("Removed {} {} from the firmware library", entry.tag, entry.name);
+                },
+                Err(error) => log::warn!("Failed to prune firmware library: {}", error),
+            }
+        }
+    }
+
+    fn download_asset(&self, asset: Arc<gh::Asset>, tag: Option<String>, sender: ComponentSender<Self>) -> JoinHandle<()> {
+        let source = self.release_source();
+        let (library, keep) = self.firmware_library();
         relm4::spawn(async move {
-            match gh::download_content(url.as_str()).await {
+            // Assets of unknown releases are not stored in the library
+            let library_tag = tag.as_deref().map(|tag| (&library, tag));
+            let result = match source {
+                Ok(source) => Self::download_with_progress(source.as_ref(), library_tag, &asset, sender.clone()).await,
+                Err(error) => Err(error),
+            };
+            if tag.is_some() {
+                Self::prune_library(&library, keep, tag).await;
+            }
+            match result {
                 Ok(content) => sender.input(Input::ContentReady(content)),
-                Err(_) => sender.input(Input::OtaFailed("Downloading failed".to_string())),
+                Err(error) => {
+                    log::error!("Failed to download '{}': {}", asset.direct_url, error);
+                    sender.input(Input::OtaFailed(format!("Downloading failed: {error}")));
+                }
+            }
+        })
+    }
+
+    fn download_bundle(
+        &self, firmware: Arc<gh::Asset>, resources: Arc<gh::Asset>, tag: String, sender: ComponentSender<Self>,
+    ) -> JoinHandle<()> {
+        let source = self.release_source();
+        let (library, keep) = self.firmware_library();
+        relm4::spawn(async move {
+            // Download both assets before flashing anything, so that the
+            // watch isn't left with mismatching resources due to network failure
+            let library_tag = Some((&library, tag.as_str()));
+            let result = match source {
+                Ok(source) => match Self::download_with_progress(source.as_ref(), library_tag, &firmware, sender.clone()).await {
+                    Ok(firmware) => Self::download_with_progress(source.as_ref(), library_tag, &resources, sender.clone()).await
+                        .map(|resources| (firmware, resources)),
+                    Err(error) => Err(error),
+                },
+                Err(error) => Err(error),
+            };
+            Self::prune_library(&library, keep, Some(tag)).await;
+            match result {
+                Ok((firmware, resources)) => sender.input(Input::BundleReady(firmware, resources)),
+                Err(error) => {
+                    log::error!("Failed to download firmware and resources: {}", error);
+                    sender.input(Input::OtaFailed(format!("Downloading failed: {error}")));
+                }
             }
         })
     }
 
+    async fn download_with_progress(
+        source: &dyn gh::ReleaseSource, library: Option<(&gh::Library, &str)>, asset: &gh::Asset,
+        sender: ComponentSender<Self>,
+    ) -> Result<Vec<u8>> {
+        let (progress_tx, mut progress_rx) = bt::progress_channel(32);
+        let sender_ = sender.clone();
+        let progress_updater = async move {
+            while let Some(event) = progress_rx.recv().await {
+                sender_.input(Input::OtaProgress(event));
+            }
+        };
+        sender.input(Input::OtaProgress(ProgressEvent::Message(format!("Downloading {}", asset.name))));
+        let download = async move {
+            match library {
+                Some((library, tag)) => library.download(source, tag, asset, Some(progress_tx)).await,
+                None => source.download(asset, Some(progress_tx)).await,
+            }
+        };
+        let (_, result) = tokio::join!(progress_updater, download);
+        result
+    }
+
     fn read_asset_file(filepath: Arc<PathBuf>, sender: ComponentSender<Self>) -> JoinHandle<()> {
         relm4::spawn(async move {
+            if filepath.is_dir() {
+                // Resources directory, e.g. from the InfiniTime build output
+                let dir = filepath.clone();
+                match tokio::task::spawn_blocking(move || bt::pack_resources_dir(&dir)).await {
+                    Ok(Ok(content)) => sender.input(Input::ContentReady(content)),
+                    Ok(Err(err)) => {
+                        sender.input(Input::OtaFailed(err.to_string()));
+                        log::error!("Failed to pack resources from '{:?}': {}", &filepath, err);
+                    }
+                    Err(err) => sender.input(Input::OtaFailed(err.to_string())),
+                }
+                return;
+            }
             match tokio::fs::File::open(filepath.as_path()).await {
                 Ok(mut file) => {
                     let mut content = Vec::new();
@@ -97,7 +258,10 @@ impl Model {
         })
     }
 
-    fn flash_asset(infinitime: Arc<InfiniTime>, content: Arc<Vec<u8>>, asset_type: AssetType, sender: ComponentSender<Self>) -> JoinHandle<()> {
+    fn flash_asset(
+        infinitime: Arc<InfiniTime>, content: Arc<Vec<u8>>, asset_type: AssetType, version: Option<String>,
+        upload: ResourceUpload, entry: JournalEntry, sender: ComponentSender<Self>,
+    ) -> JoinHandle<()> {
         let (progress_tx, mut progress_rx) = bt::progress_channel(32);
 
         let sender_ = sender.clone();
@@ -108,14 +272,42 @@ impl Model {
         };
 
         let flasher = async move {
-            match asset_type {
-                AssetType::Firmware => {
+            let journal = ui::flash_journal();
+            let mut entry = entry;
+            entry.firmware_before = infinitime.read_firmware_version().await.ok();
+            if let Err(error) = journal.begin(&entry).await {
+                log::error!("Failed to write flash journal: {}", error);
+            }
+
+            let started = Instant::now();
+            let result = match asset_type {
+                AssetType::Firmware | AssetType::RecoveryLoader => {
                     infinitime.firmware_upgrade(&content, Some(progress_tx)).await
                 }
-                AssetType::Resources => {
-                    infinitime.upload_resources(&content, Some(progress_tx)).await
+                AssetType::Resources => match upload {
+                    ResourceUpload::Planned(plan) => {
+                        infinitime.upload_resources_with_plan(&content, &plan, version.as_deref(), Some(progress_tx)).await
+                            .map(|()| log::info!("Resources uploaded: {}", plan.summary()))
+                    }
+                    ResourceUpload::Unplanned { verify } => {
+                        infinitime.upload_resources(&content, verify, version.as_deref(), Some(progress_tx)).await
+                            .map(|plan| log::info!("Resources uploaded: {}", plan.summary()))
+                    }
+                },
+                AssetType::ResourcesRemoval => {
+                    infinitime.uninstall_resources(&content, Some(progress_tx)).await
                 }
+            };
+
+            let status = match &result {
+                Ok(()) => JournalStatus::Succeeded,
+                Err(error) => JournalStatus::Failed(error.to_string()),
+            };
+            let duration = started.elapsed().as_secs_f32();
+            if let Err(error) = journal.finish(entry.id, status, Some(duration)).await {
+                log::error!("Failed to write flash journal: {}", error);
             }
+            result
         };
 
         relm4::spawn(async move {
@@ -126,12 +318,202 @@ impl Model {
             }
         })
     }
+
+    fn plan_resources(infinitime: Arc<InfiniTime>, content: Arc<Vec<u8>>, verify: bool, sender: ComponentSender<Self>) -> JoinHandle<()> {
+        relm4::spawn(async move {
+            match infinitime.plan_resources_upload(&content, verify).await {
+                Ok(plan) => sender.input(Input::ResourcePlanReady(plan)),
+                Err(err) => sender.input(Input::OtaFailed(err.to_string())),
+            }
+        })
+    }
+
+    fn show_resource_plan(plan: &bt::ResourcePlan, sender: ComponentSender<Self>) {
+        let mut details = Vec::new();
+        for (title, paths) in [
+            ("Directories to create", &plan.dirs),
+            ("Files to write", &plan.writes),
+            ("Obsolete files to delete", &plan.deletions),
+        ] {
+            if !paths.is_empty() {
+                details.push(format!("{title}:\n{}", paths.join("\n")));
+            }
+        }
+        if !plan.skips.is_empty() {
+            details.push(format!("{} files are already up to date", plan.skips.len()));
+        }
+
+        let label = gtk::Label::builder()
+            .label(details.join("\n\n"))
+            .halign(gtk::Align::Start)
+            .selectable(true)
+            .wrap(true)
+            .build();
+        let scroll_view = gtk::ScrolledWindow::builder()
+            .hscrollbar_policy(gtk::PolicyType::Never)
+            .min_content_height(240)
+            .child(&label)
+            .build();
+        let dialog = adw::MessageDialog::builder()
+            .heading("Upload resources?")
+            .body(plan.summary())
+            .extra_child(&scroll_view)
+            .modal(true)
+            .build();
+        dialog.set_transient_for(relm4::main_application().active_window().as_ref());
+        dialog.add_responses(&[("cancel", "Cancel"), ("upload", "Upload")]);
+        dialog.set_response_appearance("upload", adw::ResponseAppearance::Suggested);
+        dialog.set_default_response(Some("upload"));
+        dialog.set_close_response("cancel");
+        dialog.connect_response(None, move |_, response| {
+            sender.input(match response {
+                "upload" => Input::ResourcePlanAccepted,
+                _ => Input::ResourcePlanRejected,
+            });
+        });
+        dialog.present();
+    }
+
+    fn show_image_warning(error: &str, sender: ComponentSender<Self>) {
+        let dialog = adw::MessageDialog::builder()
+            .heading("Flash unbootable image?")
+            .body(format!(
+                "{error}. The image is most likely not bootable by PineTime bootloader, \
+                and the watch may be left without working firmware"
+            ))
+            .modal(true)
+            .build();
+        dialog.set_transient_for(relm4::main_application().active_window().as_ref());
+        dialog.add_responses(&[("cancel", "Cancel"), ("flash", "Flash Anyway")]);
+        dialog.set_response_appearance("flash", adw::ResponseAppearance::Destructive);
+        dialog.set_default_response(Some("cancel"));
+        dialog.set_close_response("cancel");
+        dialog.connect_response(None, move |_, response| {
+            sender.input(match response {
+                "flash" => Input::ImageWarningAccepted,
+                _ => Input::ImageWarningRejected,
+            });
+        });
+        dialog.present();
+    }
+
+    fn verify_firmware(infinitime: Arc<InfiniTime>, expected: Option<String>, sender: ComponentSender<Self>) -> JoinHandle<()> {
+        relm4::spawn(async move {
+            match infinitime.verify_firmware_version(expected.as_deref()).await {
+                Ok(verification) => sender.input(Input::VerificationFinished(verification)),
+                Err(err) => sender.input(Input::VerificationFailed(err.to_string())),
+            }
+        })
+    }
+
+    fn start_flashing(&mut self, infinitime: Arc<InfiniTime>, content: Arc<Vec<u8>>, sender: ComponentSender<Self>) {
+        let entry = JournalEntry::new(
+            infinitime.device().address().to_string(),
+            self.asset_type.name().to_string(),
+            self.asset_origin.clone(),
+            content.len() as u64,
+        );
+        self.journal_entry = Some(entry.id);
+        self.asset_content = Some(content.clone());
+        let version = self.expected_version.clone();
+        // The plan is outdated after the first attempt. Retries go through
+        // `flash_content`, which makes and confirms it again
+        let upload = match self.resource_plan.take() {
+            Some(plan) => ResourceUpload::Planned(plan),
+            None => ResourceUpload::Unplanned { verify: self.verify_resources() },
+        };
+        self.task_handle = Some(Self::flash_asset(infinitime, content, self.asset_type, version, upload, entry, sender));
+    }
+
+    /// Start flashing the downloaded or read asset (or planning it, for resources)
+    fn flash_content(&mut self, content: Arc<Vec<u8>>, sender: ComponentSender<Self>) {
+        let Some(infinitime) = self.infinitime.clone() else {
+            sender.input(Input::OtaFailed("Watch is disconnected".to_string()));
+            return;
+        };
+        if let AssetType::Firmware | AssetType::RecoveryLoader = self.asset_type {
+            // The watch reboots after the upgrade, make sure it gets reconnected
+            ui::BROKER.send(ui::Input::ExpectReconnect(infinitime.device().address()));
+        }
+        self.asset_source = None;
+        if let AssetType::Resources = self.asset_type {
+            // Let the user review the changes before anything is written
+            self.progress_status = String::from("Checking files on the watch");
+            self.asset_content = Some(content.clone());
+            self.task_handle = Some(Self::plan_resources(infinitime, content, self.verify_resources(), sender));
+        } else {
+            self.start_flashing(infinitime, content, sender);
+        }
+    }
+
+    fn verify_resources(&self) -> bool {
+        self.settings.as_ref()
+            .map_or(false, |s| s.boolean(ui::SETTING_VERIFY_RESOURCES))
+    }
+
+    fn finish_journal_entry(&mut self, status: JournalStatus) {
+        if let Some(id) = self.journal_entry.take() {
+            relm4::spawn(async move {
+                if let Err(error) = ui::flash_journal().finish(id, status, None).await {
+                    log::error!("Failed to write flash journal: {}", error);
+                }
+            });
+        }
+    }
+
+    /// Record the firmware version the watch runs after the upgrade
+    fn record_verification(&mut self, verification: &FirmwareVerification) {
+        if let Some(id) = self.journal_entry.take() {
+            let verification = verification.clone();
+            relm4::spawn(async move {
+                let journal = ui::flash_journal();
+                let result = match verification {
+                    FirmwareVerification::Confirmed(actual) | FirmwareVerification::Unverified(actual) => {
+                        journal.set_firmware_after(id, actual).await
+                    }
+                    FirmwareVerification::Mismatch { expected, actual } => {
+                        let status = JournalStatus::Failed(format!("Watch runs {actual} instead of {expected}"));
+                        match journal.set_firmware_after(id, actual).await {
+                            Ok(()) => journal.finish(id, status, None).await,
+                            Err(error) => Err(error),
+                        }
+                    }
+                };
+                if let Err(error) = result {
+                    log::error!("Failed to write flash journal: {}", error);
+                }
+            });
+        }
+    }
+
+    fn flash_queued_resources(&mut self, sender: ComponentSender<Self>) -> bool {
+        match (self.queued_resources.take(), self.infinitime.clone()) {
+            (Some((content, origin)), Some(infinitime)) => {
+                self.progress_status = String::from("Uploading resources");
+                self.progress_current = 0;
+                self.progress_total = 0;
+                self.state = State::InProgress;
+                self.asset_type = AssetType::Resources;
+                self.asset_origin = origin;
+                self.start_flashing(infinitime, content, sender);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn verification_timeout(sender: ComponentSender<Self>) -> JoinHandle<()> {
+        relm4::spawn(async move {
+            tokio::time::sleep(VERIFICATION_TIMEOUT).await;
+            sender.input(Input::VerificationFailed("the watch did not reconnect in time".to_string()));
+        })
+    }
 }
 
 #[relm4::component(pub)]
 impl Component for Model {
     type CommandOutput = ();
-    type Init = ();
+    type Init = gio::Settings;
     type Input = Input;
     type Output = ();
     type Widgets = Widgets;
@@ -172,9 +554,20 @@ impl Component for Model {
                         #[watch]
                         set_label: &model.progress_status,
                         set_halign: gtk::Align::Center,
+                        set_justify: gtk::Justification::Center,
+                        set_wrap: true,
                         set_margin_top: 20,
                     },
 
+                    gtk::Label {
+                        #[watch]
+                        set_label: &format!("Image version: {}", model.image_version.as_deref().unwrap_or_default()),
+                        #[watch]
+                        set_visible: model.image_version.is_some() && model.state != State::Finished,
+                        set_halign: gtk::Align::Center,
+                        add_css_class: "dim-label",
+                    },
+
                     gtk::LevelBar {
                         set_min_value: 0.0,
                         #[watch]
@@ -194,7 +587,8 @@ impl Component for Model {
 
                     gtk::Spinner {
                         #[watch]
-                        set_visible: model.state == State::InProgress && model.progress_current == 0,
+                        set_visible: (model.state == State::InProgress && model.progress_current == 0)
+                            || model.state == State::Verifying,
                         set_spinning: true,
                     },
 
@@ -207,7 +601,7 @@ impl Component for Model {
                             set_label: "Abort",
                             add_css_class:"destructive-action",
                             #[watch]
-                            set_visible: model.state == State::InProgress,
+                            set_visible: model.is_busy(),
                             connect_clicked => Input::Abort,
                         },
 
@@ -232,8 +626,8 @@ impl Component for Model {
         }
     }
 
-    fn init(_: Self::Init, root: Self::Root, sender: ComponentSender<Self>) -> ComponentParts<Self> {
-        let model = Self::default();
+    fn init(settings: Self::Init, root: Self::Root, sender: ComponentSender<Self>) -> ComponentParts<Self> {
+        let model = Self { settings: Some(settings), ..Default::default() };
         let widgets = view_output!();
         ComponentParts { model, widgets }
     }
@@ -241,6 +635,12 @@ impl Component for Model {
     fn update(&mut self, msg: Self::Input, sender: ComponentSender<Self>, _root: &Self::Root) {
         match msg {
             Input::Connected(infinitime) => {
+                if self.state == State::Verifying {
+                    self.progress_status = String::from("Reading firmware version...");
+                    self.task_handle.take().map(|h| h.abort());
+                    let expected = self.expected_version.clone();
+                    self.task_handle = Some(Self::verify_firmware(infinitime.clone(), expected, sender));
+                }
                 self.infinitime = Some(infinitime);
             }
             Input::Disconnected => {
@@ -254,33 +654,228 @@ impl Component for Model {
                 self.state = State::InProgress;
                 self.asset_type = asset_type;
                 self.asset_source = Some(Source::File(filepath.clone()));
+                self.asset_origin = filepath.display().to_string();
+                self.journal_entry = None;
+                self.expected_version = None;
+                self.queued_resources = None;
+                self.resource_plan = None;
+                self.report.clear();
                 self.task_handle = Some(Self::read_asset_file(filepath.clone(), sender));
             }
-            Input::FlashAssetFromUrl(url, asset_type) => {
-                let url = Arc::new(url);
+            Input::FlashAssetFromRelease(asset, asset_type, version) => {
+                let asset = Arc::new(asset);
                 self.progress_status = format!("Downloading {}", asset_type.name().to_lowercase());
                 self.progress_current = 0;
                 self.progress_total = 0;
                 self.state = State::InProgress;
                 self.asset_type = asset_type;
-                self.asset_source = Some(Source::Url(url.clone()));
-                self.task_handle = Some(Self::download_asset(url.clone(), sender));
+                self.asset_source = Some(Source::Release(asset.clone(), version.clone()));
+                self.asset_origin = asset.direct_url.clone();
+                self.journal_entry = None;
+                self.expected_version = version.clone();
+                self.queued_resources = None;
+                self.resource_plan = None;
+                self.report.clear();
+                self.task_handle = Some(self.download_asset(asset.clone(), version, sender));
+            }
+            Input::UpdateAllFromRelease { firmware, resources, version } => {
+                let firmware = Arc::new(firmware);
+                let resources = Arc::new(resources);
+                self.progress_status = String::from("Downloading firmware and resources");
+                self.progress_current = 0;
+                self.progress_total = 0;
+                self.state = State::InProgress;
+                self.asset_type = AssetType::Firmware;
+                self.asset_source = Some(Source::Bundle {
+                    firmware: firmware.clone(),
+                    resources: resources.clone(),
+                    tag: version.clone(),
+                });
+                self.asset_origin = firmware.direct_url.clone();
+                self.journal_entry = None;
+                self.expected_version = Some(version.clone());
+                self.queued_resources = None;
+                self.resource_plan = None;
+                self.report.clear();
+                self.task_handle = Some(self.download_bundle(firmware, resources, version, sender));
+            }
+            Input::BundleReady(firmware, resources) => {
+                let origin = match &self.asset_source {
+                    Some(Source::Bundle { resources, .. }) => resources.direct_url.clone(),
+                    _ => String::new(),
+                };
+                self.queued_resources = Some((Arc::new(resources), origin));
+                sender.input(Input::ContentReady(firmware));
             }
             Input::ContentReady(content) => {
-                if let Some(infinitime) = self.infinitime.clone() {
-                    let content = Arc::new(content);
-                    self.asset_source = None;
-                    self.asset_content = Some(content.clone());
-                    self.task_handle = Some(Self::flash_asset(infinitime, content, self.asset_type, sender));
+                self.image_version = None;
+                self.progress_current = 0;
+                self.progress_total = 0;
+                let content = Arc::new(content);
+                // Both are mcuboot images, resources are an archive
+                if let AssetType::Firmware | AssetType::RecoveryLoader = self.asset_type {
+                    match bt::read_dfu_image_info(&content) {
+                        Ok(info) => {
+                            let version = info.header.version.to_string();
+                            log::info!(
+                                "{} image version: {}, SHA-256: {}", self.asset_type.name(), version,
+                                info.sha256_hex().as_deref().unwrap_or("none")
+                            );
+                            // Embedded version is more reliable than release tag. Build number
+                            // is not reported by the watch, so it's left out of the comparison
+                            if let AssetType::Firmware = self.asset_type {
+                                self.expected_version = Some(info.header.version.release());
+                            }
+                            self.image_version = Some(version);
+                        }
+                        Err(error) => {
+                            log::warn!("Invalid {} image: {}", self.asset_type.name().to_lowercase(), error);
+                            self.progress_status = format!("{} image has no mcuboot header", self.asset_type.name());
+                            self.asset_content = Some(content);
+                            Self::show_image_warning(&error.to_string(), sender);
+                            return;
+                        }
+                    }
                 }
+                self.flash_content(content, sender);
+            }
+            Input::ImageWarningAccepted => {
+                if let Some(content) = self.asset_content.clone() {
+                    self.flash_content(content, sender);
+                }
+            }
+            Input::ImageWarningRejected => {
+                // Re-read the image on retry, so that the warning is shown again
+                self.asset_content = None;
+                self.queued_resources = None;
+                self.progress_status = format!("{} update cancelled", self.asset_type.name());
+                self.state = State::Aborted;
+            }
+            Input::ResourcePlanReady(plan) => {
+                self.task_handle = None;
+                if plan.writes.is_empty() && plan.deletions.is_empty() && plan.dirs.is_empty() {
+                    // Nothing to confirm, only the resources marker is going to be written
+                    sender.input(Input::ResourcePlanAccepted);
+                } else {
+                    self.progress_status = plan.summary();
+                    Self::show_resource_plan(&plan, sender);
+                }
+                self.resource_plan = Some(plan);
+            }
+            Input::ResourcePlanAccepted => {
+                match (self.asset_content.clone(), self.infinitime.clone()) {
+                    (Some(content), Some(infinitime)) => {
+                        self.start_flashing(infinitime, content, sender);
+                    }
+                    _ => sender.input(Input::OtaFailed("Watch is disconnected".to_string())),
+                }
+            }
+            Input::ResourcePlanRejected => {
+                // The content is kept for retry, which shows the plan again
+                self.resource_plan = None;
+                self.progress_status = String::from("Resources update cancelled");
+                self.state = State::Aborted;
             }
             Input::OtaFinished => {
-                self.progress_status = format!("{} update complete :)", self.asset_type.name());
+                match self.asset_type {
+                    AssetType::Firmware => {
+                        self.progress_status = String::from("Firmware sent. Waiting for the watch to restart...");
+                        self.state = State::Verifying;
+                        self.task_handle = Some(Self::verification_timeout(sender));
+                    }
+                    AssetType::Resources if !self.report.is_empty() => {
+                        ui::BROKER.send(ui::Input::ResourcesChanged);
+                        self.journal_entry = None;
+                        self.report.push(String::from("Resources uploaded"));
+                        self.progress_status = format!(
+                            "Update complete :)\n\n{}\n\nDon't forget to validate the firmware on the watch \
+                            (Settings → Firmware), otherwise it will be reverted on the next reboot",
+                            self.report.join("\n")
+                        );
+                        self.state = State::Finished;
+                        self.task_handle = None;
+                        self.asset_content = None;
+                    }
+                    AssetType::ResourcesRemoval => {
+                        ui::BROKER.send(ui::Input::ResourcesChanged);
+                        self.journal_entry = None;
+                        self.progress_status = String::from("Resources removed");
+                        self.state = State::Finished;
+                        self.task_handle = None;
+                        self.asset_content = None;
+                    }
+                    AssetType::Resources => {
+                        ui::BROKER.send(ui::Input::ResourcesChanged);
+                        self.journal_entry = None;
+                        self.progress_status = format!("{} update complete :)", self.asset_type.name());
+                        self.state = State::Finished;
+                        self.task_handle = None;
+                        self.asset_content = None;
+                    }
+                    AssetType::RecoveryLoader => {
+                        self.journal_entry = None;
+                        self.progress_status = format!(
+                            "{} flashed. Wait until the watch finishes installing it, \
+                            then flash InfiniTime firmware again",
+                            self.asset_type.name()
+                        );
+                        self.state = State::Finished;
+                        self.task_handle = None;
+                        self.asset_content = None;
+                    }
+                }
+            }
+            Input::VerificationFinished(verification) => {
+                self.task_handle = None;
+                self.record_verification(&verification);
+                match verification {
+                    FirmwareVerification::Confirmed(version) => {
+                        self.report.push(format!("Firmware updated to {version}"));
+                        if self.flash_queued_resources(sender) {
+                            return;
+                        }
+                        self.progress_status = format!(
+                            "Firmware updated to {version} :)\n\nDon't forget to validate it on the watch \
+                            (Settings → Firmware), otherwise it will be reverted on the next reboot"
+                        );
+                        self.state = State::Finished;
+                        self.asset_content = None;
+                    }
+                    FirmwareVerification::Unverified(version) => {
+                        self.report.push(format!("Firmware flashed, the watch runs {version}, it could not be verified"));
+                        if self.flash_queued_resources(sender) {
+                            return;
+                        }
+                        self.progress_status = format!(
+                            "Firmware flashed, but its version could not be verified: the watch runs {version}.\n\n\
+                            If it's the expected one, don't forget to validate it on the watch \
+                            (Settings → Firmware), otherwise it will be reverted on the next reboot"
+                        );
+                        self.state = State::Finished;
+                        self.asset_content = None;
+                    }
+                    FirmwareVerification::Mismatch { expected, actual } => {
+                        self.progress_status = format!(
+                            "Firmware update failed: the watch still runs {actual} instead of {expected}"
+                        );
+                        self.state = State::Aborted;
+                    }
+                }
+            }
+            Input::VerificationFailed(message) => {
+                self.journal_entry = None;
+                self.progress_status = format!(
+                    "Firmware was sent, but its version could not be verified: {message}"
+                );
+                if self.queued_resources.take().is_some() {
+                    self.progress_status.push_str("\n\nResources were not uploaded");
+                }
                 self.state = State::Finished;
                 self.task_handle = None;
                 self.asset_content = None;
             }
             Input::OtaFailed(message) => {
+                self.journal_entry = None;
                 self.progress_status = format!("{} update failed: {}", self.asset_type.name(), message);
                 self.state = State::Aborted;
                 self.task_handle = None;
@@ -299,26 +894,43 @@ impl Component for Model {
             Input::Retry => {
                 self.progress_current = 0;
                 self.progress_total = 0;
+                self.state = State::InProgress;
                 if let Some(content) = self.asset_content.clone() {
-                    if let Some(infinitime) = self.infinitime.clone() {
-                        self.state = State::InProgress;
-                        self.task_handle = Some(Self::flash_asset(infinitime, content, self.asset_type, sender));
-                    }
+                    // Reports disconnected watch, and plans resources again
+                    self.flash_content(content, sender);
                 } else {
                     match &self.asset_source {
                         Some(Source::File(filepath)) => {
                             self.task_handle = Some(Self::read_asset_file(filepath.clone(), sender));
                         }
-                        Some(Source::Url(url)) => {
-                            self.task_handle = Some(Self::download_asset(url.clone(), sender));
+                        Some(Source::Release(asset, tag)) => {
+                            self.task_handle = Some(self.download_asset(asset.clone(), tag.clone(), sender));
                         }
-                        None => {}
+                        Some(Source::Bundle { firmware, resources, tag }) => {
+                            let (firmware, resources, tag) = (firmware.clone(), resources.clone(), tag.clone());
+                            self.task_handle = Some(self.download_bundle(firmware, resources, tag, sender));
+                        }
+                        None => self.state = State::Aborted,
                     }
                 }
             }
+            Input::Abort if self.state == State::Verifying => {
+                // The firmware is already flashed and recorded as such,
+                // only waiting for the watch to reconnect is cancelled
+                self.task_handle.take().map(|h| h.abort());
+                self.journal_entry = None;
+                self.queued_resources = None;
+                self.progress_status = String::from(
+                    "Firmware flashed, but its version was not verified.\n\nIf the watch runs the expected one, \
+                    don't forget to validate it (Settings → Firmware), otherwise it will be reverted on the next reboot"
+                );
+                self.state = State::Finished;
+                self.asset_content = None;
+            }
             Input::Abort => {
                 if let Some(handle) = self.task_handle.take() {
                     handle.abort();
+                    self.finish_journal_entry(JournalStatus::Aborted);
                     self.progress_status = format!("{} update aborted", self.asset_type.name());
                     self.state = State::Aborted;
                 }
@@ -0,0 +1,59 @@
+//! Secrets stored in the system keyring (Secret Service, or the secret portal
+//! when sandboxed), rather than in plaintext GSettings
+
+use anyhow::Result;
+use std::{collections::HashMap, sync::RwLock};
+
+// Cached for synchronous access, the keyring is only read on startup
+static GITHUB_TOKEN: RwLock<Option<String>> = RwLock::new(None);
+
+const GITHUB_TOKEN_LABEL: &str = "WatchMate GitHub token";
+
+fn github_token_attributes() -> HashMap<&'static str, &'static str> {
+    HashMap::from([("application", super::APP_ID), ("type", "github-token")])
+}
+
+fn cache_github_token(token: Option<String>) {
+    if let Ok(mut cached) = GITHUB_TOKEN.write() {
+        *cached = token;
+    }
+}
+
+/// GitHub personal access token, if one is stored
+pub fn github_token() -> Option<String> {
+    GITHUB_TOKEN.read().ok().and_then(|token| token.clone())
+}
+
+/// Read GitHub token from the keyring. The `legacy` token from
+/// the older versions settings is moved into the keyring first.
+pub async fn load_github_token(legacy: Option<String>) -> Result<Option<String>> {
+    // Still usable for this session if the keyring is unavailable
+    cache_github_token(legacy.clone());
+
+    let keyring = oo7::Keyring::new().await?;
+    keyring.unlock().await?;
+    let attributes = github_token_attributes();
+    if let Some(token) = legacy {
+        keyring.create_item(GITHUB_TOKEN_LABEL, &attributes, token, true).await?;
+    }
+    let token = match keyring.search_items(&attributes).await?.first() {
+        Some(item) => Some(String::from_utf8(item.secret().await?.to_vec())?),
+        None => None,
+    };
+    cache_github_token(token.clone());
+    Ok(token)
+}
+
+/// Store GitHub token in the keyring, or remove it from there if empty
+pub async fn save_github_token(token: String) -> Result<()> {
+    let token = Some(token).filter(|t| !t.trim().is_empty());
+    cache_github_token(token.clone());
+
+    let keyring = oo7::Keyring::new().await?;
+    let attributes = github_token_attributes();
+    match token {
+        Some(token) => keyring.create_item(GITHUB_TOKEN_LABEL, &attributes, token, true).await?,
+        None => keyring.delete(&attributes).await?,
+    }
+    Ok(())
+}
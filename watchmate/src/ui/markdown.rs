@@ -0,0 +1,179 @@
+use pulldown_cmark::{Event, HeadingLevel, Options, Parser, Tag};
+use relm4::gtk::glib;
+
+/// Convert Markdown (e.g. release notes) into Pango markup for `gtk::Label`.
+/// Raw HTML is dropped, images are replaced by their alt text,
+/// bare URLs are turned into links.
+pub fn to_pango(markdown: &str) -> String {
+    let mut output = String::new();
+    // Next item number for every nested list, `None` for bullet lists
+    let mut lists: Vec<Option<u64>> = Vec::new();
+    // Pango doesn't allow nested links, e.g. for the linked badges
+    let mut link_depth = 0;
+
+    for event in Parser::new_ext(markdown, Options::ENABLE_STRIKETHROUGH | Options::ENABLE_TABLES) {
+        match event {
+            Event::Start(tag) => match tag {
+                Tag::Heading(level, ..) => {
+                    let size = match level {
+                        HeadingLevel::H1 => "x-large",
+                        HeadingLevel::H2 => "large",
+                        _ => "medium",
+                    };
+                    output.push_str(&format!("<span weight=\"bold\" size=\"{size}\">"));
+                }
+                Tag::List(start) => {
+                    if !lists.is_empty() && !output.ends_with('\n') {
+                        output.push('\n');
+                    }
+                    lists.push(start);
+                }
+                Tag::Item => {
+                    output.push_str(&"    ".repeat(lists.len().saturating_sub(1)));
+                    match lists.last_mut() {
+                        Some(Some(number)) => {
+                            output.push_str(&format!("{number}. "));
+                            *number += 1;
+                        }
+                        _ => output.push_str("• "),
+                    }
+                }
+                Tag::Emphasis | Tag::BlockQuote => output.push_str("<i>"),
+                Tag::Strong => output.push_str("<b>"),
+                Tag::Strikethrough => output.push_str("<s>"),
+                Tag::CodeBlock(_) => output.push_str("<tt>"),
+                Tag::Link(_, url, _) => {
+                    if link_depth == 0 {
+                        output.push_str(&format!("<a href=\"{}\">", glib::markup_escape_text(&url)));
+                    }
+                    link_depth += 1;
+                }
+                Tag::TableCell => output.push(' '),
+                Tag::Image(..) | Tag::Paragraph | Tag::Table(_) | Tag::TableHead | Tag::TableRow
+                | Tag::FootnoteDefinition(_) => {}
+            },
+            Event::End(tag) => match tag {
+                Tag::Heading(..) => output.push_str("</span>\n\n"),
+                Tag::Paragraph if lists.is_empty() => output.push_str("\n\n"),
+                Tag::Paragraph => {}
+                Tag::List(_) => {
+                    lists.pop();
+                    if lists.is_empty() {
+                        output.push('\n');
+                    }
+                }
+                Tag::Item => {
+                    if !output.ends_with('\n') {
+                        output.push('\n');
+                    }
+                }
+                Tag::Emphasis => output.push_str("</i>"),
+                Tag::BlockQuote => {
+                    output.truncate(output.trim_end().len());
+                    output.push_str("</i>\n\n");
+                }
+                Tag::Strong => output.push_str("</b>"),
+                Tag::Strikethrough => output.push_str("</s>"),
+                Tag::CodeBlock(_) => {
+                    output.truncate(output.trim_end().len());
+                    output.push_str("</tt>\n\n");
+                }
+                Tag::Link(..) => {
+                    link_depth -= 1;
+                    if link_depth == 0 {
+                        output.push_str("</a>");
+                    }
+                }
+                Tag::TableHead | Tag::TableRow => output.push('\n'),
+                Tag::Table(_) => output.push('\n'),
+                Tag::Image(..) | Tag::TableCell | Tag::FootnoteDefinition(_) => {}
+            },
+            Event::Text(text) if link_depth > 0 => output.push_str(&glib::markup_escape_text(&text)),
+            Event::Text(text) => output.push_str(&linkify(&text)),
+            Event::Code(code) => {
+                output.push_str(&format!("<tt>{}</tt>", glib::markup_escape_text(&code)));
+            }
+            Event::SoftBreak => output.push(' '),
+            Event::HardBreak => output.push('\n'),
+            Event::Rule => output.push_str("――――――――\n\n"),
+            Event::TaskListMarker(done) => output.push_str(if done { "☑ " } else { "☐ " }),
+            Event::Html(_) | Event::FootnoteReference(_) => {}
+        }
+    }
+
+    output.trim_end().to_string()
+}
+
+/// Escape the text, wrapping URLs into links
+fn linkify(text: &str) -> String {
+    let mut output = String::new();
+    let mut rest = text;
+    while let Some(start) = [rest.find("https://"), rest.find("http://")].into_iter().flatten().min() {
+        let end = rest[start..].find(char::is_whitespace).map_or(rest.len(), |i| start + i);
+        // Trailing punctuation most likely belongs to the sentence
+        let url = rest[start..end].trim_end_matches(&['.', ',', ';', ':', ')'][..]);
+        let end = start + url.len();
+        output.push_str(&glib::markup_escape_text(&rest[..start]));
+        let url = glib::markup_escape_text(url);
+        output.push_str(&format!("<a href=\"{url}\">{url}</a>"));
+        rest = &rest[end..];
+    }
+    output.push_str(&glib::markup_escape_text(rest));
+    output
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn inline_formatting() {
+        assert_eq!(to_pango("*a* **b** ~~c~~ `d`"), "<i>a</i> <b>b</b> <s>c</s> <tt>d</tt>");
+    }
+
+    #[test]
+    fn headings_and_paragraphs() {
+        assert_eq!(
+            to_pango("# Title\n\nFirst\nline\n\n## Section"),
+            "<span weight=\"bold\" size=\"x-large\">Title</span>\n\nFirst line\n\n<span weight=\"bold\" size=\"large\">Section</span>",
+        );
+    }
+
+    #[test]
+    fn lists() {
+        assert_eq!(to_pango("- a\n- b\n  1. c\n  2. d"), "• a\n• b\n    1. c\n    2. d");
+        assert_eq!(to_pango("3. a\n4. b"), "3. a\n4. b");
+    }
+
+    #[test]
+    fn escapes_text_and_drops_html() {
+        assert_eq!(to_pango("a < b & c <br> d"), "a &lt; b &amp; c  d");
+    }
+
+    #[test]
+    fn links() {
+        assert_eq!(to_pango("[site](https://example.com/?a&b)"), "<a href=\"https://example.com/?a&amp;b\">site</a>");
+    }
+
+    #[test]
+    fn images_as_alt_text() {
+        assert_eq!(to_pango("![logo](https://example.com/logo.png)"), "logo");
+        assert_eq!(
+            to_pango("[![badge](https://example.com/badge.svg)](https://example.com)"),
+            "<a href=\"https://example.com\">badge</a>",
+        );
+    }
+
+    #[test]
+    fn bare_urls() {
+        assert_eq!(
+            to_pango("See https://example.com."),
+            "See <a href=\"https://example.com\">https://example.com</a>.",
+        );
+        assert_eq!(
+            to_pango("http://a.org and https://b.org"),
+            "<a href=\"http://a.org\">http://a.org</a> and <a href=\"https://b.org\">https://b.org</a>",
+        );
+    }
+}
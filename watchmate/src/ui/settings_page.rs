@@ -1,10 +1,13 @@
 use crate::ui;
+use super::devices_page::AdapterInfo;
+use infinitime::gh;
+use anyhow::Result;
 use gtk::{
-    gio, glib::Propagation, prelude::{
-        GtkApplicationExt, OrientableExt, WidgetExt, ButtonExt, SettingsExt
+    gio, glib::{self, Propagation}, prelude::{
+        GtkApplicationExt, OrientableExt, WidgetExt, ButtonExt, SettingsExt, EditableExt, FileExt
     }
 };
-use adw::prelude::{PreferencesPageExt, PreferencesGroupExt, PreferencesRowExt, ActionRowExt};
+use adw::prelude::{PreferencesPageExt, PreferencesGroupExt, PreferencesRowExt, ActionRowExt, ComboRowExt, EntryRowExt, ExpanderRowExt};
 use relm4::{adw, gtk, ComponentParts, ComponentSender, Component};
 use ashpd::{desktop::background::Background, WindowIdentifier, Error};
 
@@ -15,12 +18,32 @@ pub enum Input {
     RunInBackgroundResponse(bool),
     AutoStartRequest(bool),
     AutoStartResponse(bool),
+    RefreshLibrary,
+    RemoveLibraryEntry(gh::LibraryEntry),
+    OpenLibraryFolder,
+    Adapters(Vec<AdapterInfo>),
+    AdapterSelected(u32),
+    GithubToken(Option<String>),
+    GithubTokenApplied(String),
+}
+
+#[derive(Debug)]
+pub enum CommandOutput {
+    LibraryEntries(Result<Vec<gh::LibraryEntry>>),
 }
 
 
 pub struct Model {
     background_switch: gtk::Switch,
     autostart_switch: gtk::Switch,
+    library_row: adw::ExpanderRow,
+    github_token_row: adw::PasswordEntryRow,
+    library_rows: Vec<adw::ActionRow>,
+    library_summary: String,
+    // Adapter names by their position in the selector, empty for the default one
+    adapter_choices: Vec<String>,
+    adapter_labels: gtk::StringList,
+    adapter_index: u32,
     settings: gio::Settings,
 }
 
@@ -43,12 +66,84 @@ impl Model {
             handler(response);
         });
     }
+
+    fn library_entry_row(entry: gh::LibraryEntry, sender: &ComponentSender<Self>) -> adw::ActionRow {
+        let row = adw::ActionRow::builder()
+            .title(format!("{} · {}", entry.tag, entry.name))
+            .subtitle(format!(
+                "{:.1} KB · downloaded {}",
+                entry.size as f32 / 1024.0,
+                entry.added_time().format("%Y-%m-%d %H:%M"),
+            ))
+            .build();
+        let button = gtk::Button::builder()
+            .icon_name("user-trash-symbolic")
+            .tooltip_text("Remove")
+            .valign(gtk::Align::Center)
+            .css_classes(["flat"])
+            .build();
+        button.connect_clicked(glib::clone!(#[strong] sender, move |_| {
+            sender.input(Input::RemoveLibraryEntry(entry.clone()));
+        }));
+        row.add_suffix(&button);
+        row
+    }
+
+    fn set_adapters(&mut self, adapters: Vec<AdapterInfo>) {
+        let preferred = self.settings.string(super::SETTING_ADAPTER).to_string();
+        let mut choices = vec![String::new()];
+        let mut labels = vec![String::from("Default")];
+        for adapter in adapters {
+            let state = if adapter.powered { "on" } else { "off" };
+            labels.push(format!("{} ({}, {}, {})", adapter.alias, adapter.name, adapter.address, state));
+            choices.push(adapter.name);
+        }
+        // Keep the choice while the adapter is unplugged
+        if !choices.contains(&preferred) {
+            labels.push(format!("{preferred} (not available)"));
+            choices.push(preferred.clone());
+        }
+        let labels = labels.iter().map(String::as_str).collect::<Vec<_>>();
+        self.adapter_labels = gtk::StringList::new(&labels);
+        self.adapter_index = choices.iter().position(|c| *c == preferred).unwrap_or(0) as u32;
+        self.adapter_choices = choices;
+    }
+
+    fn refresh_library(&self, sender: &ComponentSender<Self>) {
+        sender.oneshot_command(async {
+            CommandOutput::LibraryEntries(ui::firmware_library().entries().await)
+        });
+    }
+
+    fn show_library(&mut self, entries: Result<Vec<gh::LibraryEntry>>, sender: &ComponentSender<Self>) {
+        for row in self.library_rows.drain(..) {
+            self.library_row.remove(&row);
+        }
+        match entries {
+            Ok(entries) => {
+                let size = entries.iter().map(|e| e.size).sum::<u64>();
+                self.library_summary = match entries.len() {
+                    0 => String::from("Nothing is downloaded yet"),
+                    count => format!("{count} files, {:.1} MB", size as f32 / 1024.0 / 1024.0),
+                };
+                for entry in entries {
+                    let row = Self::library_entry_row(entry, sender);
+                    self.library_row.add_row(&row);
+                    self.library_rows.push(row);
+                }
+            }
+            Err(error) => {
+                log::error!("Failed to read firmware library: {error}");
+                self.library_summary = String::from("Failed to read the library");
+            }
+        }
+    }
 }
 
 
 #[relm4::component(pub)]
 impl Component for Model {
-    type CommandOutput = ();
+    type CommandOutput = CommandOutput;
     type Init = gio::Settings;
     type Input = Input;
     type Output = ();
@@ -94,6 +189,32 @@ impl Component for Model {
 
             adw::PreferencesPage {
                 add = &adw::PreferencesGroup {
+                    add = &adw::ComboRow {
+                        set_title: "Bluetooth adapter",
+                        set_subtitle: "Another powered adapter is used while this one is missing",
+                        #[watch]
+                        #[block_signal(adapter_handler)]
+                        set_model: Some(&model.adapter_labels),
+                        #[watch]
+                        #[block_signal(adapter_handler)]
+                        set_selected: model.adapter_index,
+                        connect_selected_notify[sender] => move |row| {
+                            sender.input(Input::AdapterSelected(row.selected()));
+                        } @adapter_handler,
+                    },
+                    add = &adw::EntryRow {
+                        set_title: "Watch names (comma-separated)",
+                        set_tooltip_text: Some("Devices with these names are listed even if they don't advertise InfiniTime services"),
+                        set_text: &device_names,
+                        connect_changed[settings = model.settings.clone()] => move |row| {
+                            let text = row.text();
+                            let names = text.split(',')
+                                .map(str::trim)
+                                .filter(|name| !name.is_empty())
+                                .collect::<Vec<_>>();
+                            _ = settings.set_strv(super::SETTING_DEVICE_NAMES, names.as_slice());
+                        }
+                    },
                     add = &adw::ActionRow {
                         set_title: "Run in background",
                         set_subtitle: "When closed",
@@ -120,20 +241,120 @@ impl Component for Model {
                             }
                         }
                     },
+                    add = &adw::ActionRow {
+                        set_title: "Forward notifications to new watches",
+                        set_subtitle: "Each watch can be toggled on its dashboard",
+                        add_suffix = &gtk::Switch {
+                            set_active: model.settings.boolean(super::SETTING_NOTIFICATIONS),
+                            set_valign: gtk::Align::Center,
+                            connect_state_set[settings = model.settings.clone()] => move |_, state| {
+                                _ = settings.set_boolean(super::SETTING_NOTIFICATIONS, state);
+                                Propagation::Proceed
+                            }
+                        }
+                    },
+                    add = &adw::ActionRow {
+                        set_title: "Require pairing for notifications",
+                        set_subtitle: "Forward notifications only to a paired watch, the connection is encrypted then",
+                        add_suffix = &gtk::Switch {
+                            set_active: model.settings.boolean(super::SETTING_NOTIFICATIONS_REQUIRE_PAIRING),
+                            set_valign: gtk::Align::Center,
+                            connect_state_set[settings = model.settings.clone()] => move |_, state| {
+                                _ = settings.set_boolean(super::SETTING_NOTIFICATIONS_REQUIRE_PAIRING, state);
+                                Propagation::Proceed
+                            }
+                        }
+                    },
+                },
+                add = &adw::PreferencesGroup {
+                    set_title: "Firmware Update",
+                    add = &adw::ActionRow {
+                        set_title: "Verify resources",
+                        set_subtitle: "Compare content of the files already on the watch, not just size",
+                        add_suffix = &gtk::Switch {
+                            set_active: model.settings.boolean(super::SETTING_VERIFY_RESOURCES),
+                            set_valign: gtk::Align::Center,
+                            connect_state_set[settings = model.settings.clone()] => move |_, state| {
+                                _ = settings.set_boolean(super::SETTING_VERIFY_RESOURCES, state);
+                                Propagation::Proceed
+                            }
+                        }
+                    },
+                    add = &adw::SpinRow::with_range(0.0, 720.0, 1.0) {
+                        set_title: "Check for updates",
+                        set_subtitle: "Every given number of hours while a watch is connected, 0 to disable",
+                        set_value: model.settings.uint(super::SETTING_UPDATE_CHECK_INTERVAL) as f64,
+                        connect_value_notify[settings = model.settings.clone()] => move |row| {
+                            _ = settings.set_uint(super::SETTING_UPDATE_CHECK_INTERVAL, row.value() as u32);
+                        }
+                    },
+                    #[local]
+                    add = &github_token_row -> adw::PasswordEntryRow {
+                        set_title: "GitHub token (optional)",
+                        set_tooltip_text: Some("Stored in the keyring"),
+                        set_show_apply_button: true,
+                        connect_apply[sender] => move |row| {
+                            sender.input(Input::GithubTokenApplied(row.text().to_string()));
+                        }
+                    },
+                },
+                add = &adw::PreferencesGroup {
+                    set_title: "Firmware Library",
+                    set_description: Some("Downloaded releases are kept, so that they can be flashed again without network access"),
+                    add = &adw::SpinRow::with_range(0.0, 100.0, 1.0) {
+                        set_title: "Keep releases",
+                        set_subtitle: "Number of the recently used releases to keep, 0 to keep all",
+                        set_value: model.settings.uint(super::SETTING_LIBRARY_KEEP) as f64,
+                        connect_value_notify[settings = model.settings.clone()] => move |row| {
+                            _ = settings.set_uint(super::SETTING_LIBRARY_KEEP, row.value() as u32);
+                        }
+                    },
+                    #[local]
+                    add = &library_row -> adw::ExpanderRow {
+                        set_title: "Downloaded files",
+                        #[watch]
+                        set_subtitle: &model.library_summary,
+                        add_suffix = &gtk::Button {
+                            set_icon_name: "folder-open-symbolic",
+                            set_tooltip_text: Some("Open folder"),
+                            set_valign: gtk::Align::Center,
+                            add_css_class: "flat",
+                            connect_clicked => Input::OpenLibraryFolder,
+                        },
+                        connect_expanded_notify[sender] => move |row| {
+                            if row.is_expanded() {
+                                sender.input(Input::RefreshLibrary);
+                            }
+                        },
+                    },
                 }
             }
         }
     }
 
     fn init(settings: Self::Init, root: Self::Root, sender: ComponentSender<Self>) -> ComponentParts<Self> {
-        let model = Self {
+        let mut model = Self {
             background_switch: gtk::Switch::new(),
             autostart_switch: gtk::Switch::new(),
+            library_row: adw::ExpanderRow::new(),
+            github_token_row: adw::PasswordEntryRow::new(),
+            library_rows: Vec::new(),
+            library_summary: String::new(),
+            adapter_choices: Vec::new(),
+            adapter_labels: gtk::StringList::new(&[]),
+            adapter_index: 0,
             settings,
         };
 
         let background_switch = model.background_switch.clone();
         let autostart_switch = model.autostart_switch.clone();
+        let library_row = model.library_row.clone();
+        let github_token_row = model.github_token_row.clone();
+        let device_names = model.settings.strv(super::SETTING_DEVICE_NAMES).iter()
+            .map(|name| name.as_str())
+            .collect::<Vec<_>>()
+            .join(", ");
+        model.refresh_library(&sender);
         let widgets = view_output!();
         ComponentParts { model, widgets }
     }
@@ -194,6 +415,67 @@ impl Component for Model {
                 self.autostart_switch.set_active(enabled);
                 _ = self.settings.set_boolean(super::SETTING_AUTO_START, enabled);
             }
+            Input::RefreshLibrary => {
+                self.refresh_library(&sender);
+            }
+            Input::RemoveLibraryEntry(entry) => {
+                sender.oneshot_command(async move {
+                    let library = ui::firmware_library();
+                    if let Err(error) = library.remove(&entry).await {
+                        log::error!("Failed to remove {} {} from the library: {error}", entry.tag, entry.name);
+                        ui::BROKER.send(ui::Input::ToastStatic("Failed to remove the file"));
+                    }
+                    CommandOutput::LibraryEntries(library.entries().await)
+                });
+            }
+            Input::OpenLibraryFolder => {
+                let library = ui::firmware_library();
+                if let Err(error) = std::fs::create_dir_all(library.dir()) {
+                    log::error!("Failed to create firmware library folder: {error}");
+                }
+                let uri = gio::File::for_path(library.dir()).uri();
+                gtk::UriLauncher::new(&uri).launch(
+                    relm4::main_application().active_window().as_ref(),
+                    gio::Cancellable::NONE,
+                    |_| (),
+                );
+            }
+            Input::Adapters(adapters) => {
+                self.set_adapters(adapters);
+            }
+            Input::AdapterSelected(index) => {
+                if let Some(name) = self.adapter_choices.get(index as usize) {
+                    self.adapter_index = index;
+                    if name.as_str() != self.settings.string(super::SETTING_ADAPTER).as_str() {
+                        _ = self.settings.set_string(super::SETTING_ADAPTER, name);
+                    }
+                }
+            }
+            Input::GithubToken(token) => {
+                self.github_token_row.set_text(token.as_deref().unwrap_or_default());
+            }
+            Input::GithubTokenApplied(token) => {
+                relm4::spawn(async move {
+                    match super::keyring::save_github_token(token).await {
+                        Ok(()) => ui::BROKER.send(ui::Input::ToastStatic("GitHub token saved")),
+                        Err(error) => {
+                            log::error!("Failed to save GitHub token to the keyring: {}", error);
+                            ui::BROKER.send(ui::Input::ToastStatic("Failed to save GitHub token to the keyring"));
+                        }
+                    }
+                });
+            }
         };
     }
+
+    fn update_cmd(
+        &mut self,
+        msg: Self::CommandOutput,
+        sender: ComponentSender<Self>,
+        _root: &Self::Root,
+    ) {
+        match msg {
+            CommandOutput::LibraryEntries(entries) => self.show_library(entries, &sender),
+        }
+    }
 }